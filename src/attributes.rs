@@ -0,0 +1,162 @@
+/// File Attributes - the metadata carried from a source file to its target copy
+///
+/// # File Attributes
+///
+/// the modified time and mode bits are always preserved; the owner and extended attributes only when configured,
+/// as changing the owner usually needs root and not every target file system supports extended attributes.  the
+/// attributes are recorded in the file model so a restore can reapply them.
+///
+use anyhow::Result;
+use filetime::FileTime;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// the modified time as seconds and nanoseconds since the epoch
+    pub mtime: i64,
+    pub mtime_nanos: u32,
+    /// the permission bits, e.g. 0o755
+    pub mode: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    /// the extended attribute names and hex encoded values
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl FileAttributes {
+    /// read the file's attributes; the owner and extended attributes are only read if requested
+    pub fn read(path: &Path, owner: bool, xattrs: bool) -> Result<FileAttributes> {
+        let meta = fs::metadata(path)?;
+        let mtime = FileTime::from_last_modification_time(&meta);
+
+        let mut attributes = FileAttributes {
+            mtime: mtime.unix_seconds(),
+            mtime_nanos: mtime.nanoseconds(),
+            mode: meta.permissions().mode() & 0o7777,
+            ..FileAttributes::default()
+        };
+
+        if owner {
+            attributes.uid = Some(meta.uid());
+            attributes.gid = Some(meta.gid());
+        }
+
+        if xattrs && xattr::SUPPORTED_PLATFORM {
+            for name in xattr::list(path)? {
+                if let (Some(key), Some(value)) = (name.to_str(), xattr::get(path, &name)?) {
+                    attributes
+                        .xattrs
+                        .insert(key.to_string(), hex::encode(value));
+                }
+            }
+        }
+
+        Ok(attributes)
+    }
+
+    /// apply the attributes to the file; each failure is logged and the rest are still applied.  extended
+    /// attributes are skipped unless requested.  return false if any failed.
+    pub fn apply(&self, path: &Path, xattrs: bool) -> bool {
+        let mut ok = true;
+        let mut check = |what: &str, resp: Result<()>| {
+            if let Err(e) = resp {
+                warn!("could not set {} on {}: {}", what, path.display(), e);
+                ok = false;
+            }
+        };
+
+        if xattrs {
+            for (name, value) in self.xattrs.iter() {
+                let resp = hex::decode(value)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|value| Ok(xattr::set(path, name, &value)?));
+                check(name, resp);
+            }
+        }
+
+        if self.uid.is_some() || self.gid.is_some() {
+            check(
+                "owner",
+                std::os::unix::fs::chown(path, self.uid, self.gid).map_err(anyhow::Error::from),
+            );
+        }
+
+        check(
+            "mode",
+            fs::set_permissions(path, fs::Permissions::from_mode(self.mode))
+                .map_err(anyhow::Error::from),
+        );
+
+        // last, as setting the other attributes may touch the file
+        let mtime = FileTime::from_unix_time(self.mtime, self.mtime_nanos);
+        check(
+            "modified time",
+            filetime::set_file_mtime(path, mtime).map_err(anyhow::Error::from),
+        );
+
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_apply() {
+        let folder = "tests/tback-tmp/attributes";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+        let src = Path::new("tests/tback-tmp/attributes/script.sh");
+        let dest = Path::new("tests/tback-tmp/attributes/copy.sh");
+        fs::write(src, "#!/bin/sh\n").unwrap();
+        fs::write(dest, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(src, fs::Permissions::from_mode(0o750)).unwrap();
+        filetime::set_file_mtime(src, FileTime::from_unix_time(1_700_000_000, 123_456_789))
+            .unwrap();
+
+        // not every file system supports user attributes
+        let has_xattrs = xattr::set(src, "user.replica.test", b"value").is_ok();
+
+        let attributes = FileAttributes::read(src, true, true).unwrap();
+        assert_eq!(attributes.mtime, 1_700_000_000);
+        assert_eq!(attributes.mtime_nanos, 123_456_789);
+        assert_eq!(attributes.mode, 0o750);
+        assert!(attributes.uid.is_some());
+
+        assert!(attributes.apply(dest, true));
+        let copied = FileAttributes::read(dest, true, true).unwrap();
+        assert_eq!(copied, attributes);
+        if has_xattrs {
+            assert_eq!(
+                attributes.xattrs.get("user.replica.test"),
+                Some(&hex::encode("value"))
+            );
+        }
+
+        let plain = FileAttributes::read(src, false, false).unwrap();
+        assert!(plain.uid.is_none());
+        assert!(plain.xattrs.is_empty());
+
+        let json = serde_json::to_string(&plain).unwrap();
+        assert!(!json.contains("uid"));
+        assert_eq!(
+            serde_json::from_str::<FileAttributes>(&json).unwrap(),
+            plain
+        );
+    }
+
+    #[test]
+    fn apply_missing() {
+        let attributes = FileAttributes::default();
+        assert!(!attributes.apply(Path::new("tests/tback-tmp/no-such-file"), false));
+    }
+}
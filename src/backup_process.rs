@@ -4,18 +4,59 @@
 ///
 /// create with target folder and queue vector; return the list of saved files updated with save date
 ///
-use crate::file_model::FileModel;
+use crate::attributes::FileAttributes;
+use crate::compression;
+use crate::config::{CompareMode, TargetFormat};
+use crate::encryption::{self, EncryptWriter, Key};
+use crate::file_model::{CopyRecord, FileModel, FileVersion};
 use crate::kv_store::KeyValueStore;
+use crate::repository::{self, Repository};
+use crate::run_report::TargetReport;
+use crate::target::{self, LocalTarget, SourceMeta, Target, TargetStat};
 use anyhow::{anyhow, Result};
 use chrono::Utc;
 use log::{debug, error, info, warn};
-use std::fs;
+use openssl::sha;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// the extension of the temp file a copy is written to before it is renamed into place
+pub const TEMP_EXTENSION: &str = ".replica-tmp";
+
+/// source hashes by path, size and modified time
+pub type SourceHashes = Arc<Mutex<HashMap<(PathBuf, u64, u64), String>>>;
 
 pub struct BackupProcess {
     pub target: PathBuf,
     pub files: Vec<FileModel>,
     pub dryrun: bool,
+    pub compare: CompareMode,
+    pub journaled: Vec<String>,
+    pub compress: bool,
+    pub key: Option<Key>,
+    pub stop: Arc<AtomicBool>,
+    pub preserve_owner: bool,
+    pub preserve_xattrs: bool,
+    pub concurrency: usize,
+    /// the store the copies are written to: the target folder, or a target given as a url, e.g.
+    /// sftp://user@host/path
+    pub backend: Arc<dyn Target>,
+    /// mirror copies, or a repository of deduplicated chunks and snapshots
+    pub format: TargetFormat,
+    /// the files are only part of the source, e.g. the changes seen by the watcher, so a repository snapshot keeps
+    /// the previous snapshot's other files
+    pub partial: bool,
+    /// the number of repository snapshots to keep, 0 to keep all
+    pub keep_snapshots: usize,
+    /// the source hashes found by the checks; shared by the processes of a fan out
+    /// so a source is hashed once for all its targets
+    pub source_hashes: Option<SourceHashes>,
 }
 
 impl BackupProcess {
@@ -27,16 +68,35 @@ impl BackupProcess {
 
         info!("dryrun = {}", dryrun);
 
+        // a target that can't be opened is treated as a missing folder, so target_exists reports it
+        let backend = target::open(path).unwrap_or_else(|e| {
+            warn!("could not open target {}: {:#}", path, e);
+            Arc::new(LocalTarget::new(path))
+        });
+
         BackupProcess {
             target: PathBuf::from(tp),
             files,
             dryrun,
+            compare: CompareMode::default(),
+            journaled: vec![],
+            compress: false,
+            key: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            preserve_owner: false,
+            preserve_xattrs: false,
+            concurrency: 1,
+            backend,
+            format: TargetFormat::default(),
+            partial: false,
+            keep_snapshots: 0,
+            source_hashes: None,
         }
     }
 
-    /// return true if the target exists, else false
+    /// return true if the target folder exists, or a remote target can be reached, else false
     pub fn target_exists(&self) -> bool {
-        if self.target.exists() && self.target.is_dir() {
+        if self.backend.exists() {
             true
         } else {
             warn!("Target {:?} does not exist.", self.target);
@@ -44,65 +104,430 @@ impl BackupProcess {
         }
     }
 
-    /// process the file list; return the list of files that were backup
-    pub fn process(&self, mut db: KeyValueStore) -> Result<KeyValueStore> {
-        info!("process the backup queue");
+    /// remove temp files left on the target by an interrupted run; return the number removed
+    pub fn remove_temp_files(&self) -> usize {
+        // listing a remote tree is slow, and remote uploads remove their own temp file on failure
+        if !self.backend.is_local() {
+            return 0;
+        }
+
+        let mut count = 0;
+        let mut folders = vec![String::new()];
+        while let Some(folder) = folders.pop() {
+            let paths = match self.backend.list(&folder) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    warn!(
+                        "could not list {}: {}",
+                        self.target.join(&folder).display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            for path in paths {
+                if path.ends_with('/') {
+                    folders.push(path);
+                    continue;
+                } else if !path.ends_with(TEMP_EXTENSION) {
+                    continue;
+                }
+
+                let temp = self.target.join(&path);
+                if self.dryrun {
+                    info!("dryrun, would remove temp file: {}", temp.display());
+                    continue;
+                }
+
+                match self.backend.delete(&path) {
+                    Ok(_) => {
+                        info!("removed temp file: {}", temp.display());
+                        count += 1;
+                    }
+                    Err(e) => warn!("could not remove {}: {}", temp.display(), e),
+                }
+            }
+        }
+
+        count
+    }
+
+    /// process the file list with up to concurrency workers hashing and copying files; the database is only
+    /// updated from this thread.  return the updated database and the report of the copies
+    pub fn process(&self, mut db: KeyValueStore) -> Result<(KeyValueStore, TargetReport)> {
+        info!(
+            "process the backup queue, concurrency: {}",
+            self.concurrency
+        );
+        let start_time = Instant::now();
+
+        // a path listed twice, e.g. by overlapping sources, is copied once; reuse the key and save history of an
+        // existing record for this path
+        let mut seen: HashSet<&Path> = HashSet::new();
+        let queue: Vec<(FileModel, Option<FileModel>)> = self
+            .files
+            .iter()
+            .filter(|file_model| seen.insert(file_model.path.as_path()))
+            .map(|file_model| {
+                let stored = db.find(file_model.path.to_str().unwrap()).cloned();
+                (db.identify(file_model.clone()), stored)
+            })
+            .collect();
+
+        let repository = match self.format {
+            TargetFormat::Repository => Some(self.open_repository()?),
+            TargetFormat::Mirror => None,
+        };
+
+        let next = AtomicUsize::new(0);
+        let workers = self.concurrency.clamp(1, queue.len().max(1));
+        let (tx, rx) = mpsc::channel();
+        let mut report = TargetReport::new(self.target.to_str().unwrap(), queue.len());
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let tx = tx.clone();
+                let (queue, next, repository) = (&queue, &next, &repository);
+                scope.spawn(move || {
+                    while !self.stop.load(Ordering::Relaxed) {
+                        let Some((file_model, stored)) =
+                            queue.get(next.fetch_add(1, Ordering::Relaxed))
+                        else {
+                            break;
+                        };
 
-        let files = self.files.clone();
-        for file_model in files {
-            let fpath = file_model.path.as_os_str();
-            match self.check_and_copy_file(&file_model) {
-                Some(saved_model) => {
-                    info!("file backup: {:?} -> {}", fpath, saved_model.path.display());
+                        let saved = match repository {
+                            Some(repository) => self.backup_to_repository(repository, file_model),
+                            None => self.backup_file(file_model, stored.as_ref()),
+                        };
+                        if tx.send((file_model.path.clone(), saved)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+
+            for (fpath, saved) in rx {
+                match saved {
+                    Ok(Some(saved_model)) if self.dryrun => {
+                        info!("dryrun, would backup: {:?}", fpath);
+                        report.copied(saved_model.len);
+                    }
+                    Ok(Some(saved_model)) => {
+                        info!("file backup: {:?} -> {}", fpath, saved_model.path.display());
+                        report.copied(saved_model.len);
 
-                    // save to db
-                    let resp = db.set(saved_model.clone());
-                    if resp.is_err() {
-                        error!("could not save to database: {:?}", resp);
-                    } else {
-                        info!("saved to db: {:?}", saved_model);
+                        // save to db
+                        let resp = db.set(saved_model.clone());
+                        if resp.is_err() {
+                            error!("could not save to database: {:?}", resp);
+                        } else {
+                            info!("saved to db: {:?}", saved_model);
+                        }
+                    }
+                    Ok(None) => {
+                        debug!("skip {:?}", fpath);
+                        report.skipped();
                     }
+                    Err(e) => report.failed(fpath.to_str().unwrap(), &e),
                 }
-                None => debug!("skip {:?}", fpath),
             }
+        });
+
+        let stopped =
+            self.stop.load(Ordering::Relaxed) && next.load(Ordering::Relaxed) < queue.len();
+        if stopped {
+            warn!("stop requested, skip the remaining files");
+        }
+
+        // the snapshot records the files saved so far, so a stopped run keeps the previous snapshot's other files
+        if let (Some(repository), false) = (&repository, self.dryrun) {
+            let saved = repository.save(!self.partial && !stopped)?;
+            if saved.is_some() {
+                if let Err(e) = repository.prune(self.keep_snapshots) {
+                    warn!("could not prune the repository {:?}: {:#}", self.target, e);
+                }
+            }
+        }
+
+        report.seconds = start_time.elapsed().as_secs_f64();
+        info!(
+            "copied {} files, {} bytes in {:.3} seconds, {:.2} MB/s, {} failed",
+            report.copied,
+            report.bytes,
+            report.seconds,
+            report.throughput(),
+            report.failed
+        );
+
+        Ok((db, report))
+    }
+
+    /// check the file against its copy on the target and copy it if a backup is required; return the saved model,
+    /// None if the copy is current, or the error that stopped the copy
+    pub fn backup_file(
+        &self,
+        model: &FileModel,
+        stored: Option<&FileModel>,
+    ) -> Result<Option<FileModel>> {
+        if let Some(link) = &model.link_target {
+            let target_path = self.target_path(model);
+            return self.check_and_copy_link(model, link, target_path.as_path());
+        }
+
+        match self.check_file(model, stored)? {
+            Some(target_model) => Ok(Some(self.copy_model(model, target_model)?)),
+            None => Ok(None),
         }
+    }
+
+    /// open the target's repository with the process's compression and key
+    fn open_repository(&self) -> Result<Repository> {
+        let mut repository = match Repository::open(Arc::clone(&self.backend)) {
+            Ok(repository) => repository,
+            Err(e) => {
+                let msg = format!("could not open the repository {:?}: {:#}", self.target, e);
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+        };
+        repository.compress = self.compress;
+        repository.key = self.key.clone();
 
-        Ok(db)
+        Ok(repository)
     }
 
-    /// create the target path; check stat to see backup is required
-    pub fn check_and_copy_file(&self, model: &FileModel) -> Option<FileModel> {
+    /// add the file to the repository's snapshot, carrying the previous snapshot's entry if it has the same content
+    /// or the write fails; return the saved model, None if the file is unchanged, or the error that stopped the write
+    pub fn backup_to_repository(
+        &self,
+        repository: &Repository,
+        model: &FileModel,
+    ) -> Result<Option<FileModel>> {
+        if model.link_target.is_some() {
+            let msg = format!("can't store the symlink {:?} in a repository", model.path);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+
+        if let Some(previous) = repository.previous.files.get(&model.relative_path()) {
+            if self.unchanged_stored(model, previous.len, previous.modified, &previous.hash) {
+                repository.carry(&model.relative_path());
+                return Ok(None);
+            }
+        }
+
+        if self.dryrun {
+            return Ok(Some(model.clone()));
+        }
+
+        let attributes = self.read_attributes(model.path.as_path());
+        let file = match repository.write_file(model, attributes.clone()) {
+            Ok(file) => file,
+            Err(e) => {
+                repository.carry(&model.relative_path());
+                let msg = format!("error saving {:?} to the repository: {:#}", model.path, e);
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+        };
+
+        // the model points at the latest snapshot holding the file
+        let snapshots = format!("{}{}/", self.target.display(), repository::SNAPSHOTS);
+        let mut model = model.clone();
+        model.hash = file.hash;
+        model.last_saved = Some(Utc::now().naive_utc());
+        model.compressed = repository.compress;
+        model.encrypted = repository.key.is_some();
+        model.attributes = attributes;
+        model
+            .written_to
+            .retain(|written| !written.starts_with(&snapshots));
+        model
+            .written_to
+            .insert(format!("{}{}", snapshots, repository.name));
+
+        Ok(Some(model))
+    }
+
+    /// as backup_file, with a failed copy logged and returned as None
+    pub fn check_and_copy_file(
+        &self,
+        model: &FileModel,
+        stored: Option<&FileModel>,
+    ) -> Option<FileModel> {
+        self.backup_file(model, stored).unwrap_or_else(|e| {
+            error!("backup failed for {:?}: {:#}", model.path, e);
+            None
+        })
+    }
+
+    /// create the target path; check stat, or the stored record for compressed or encrypted copies, to see backup
+    /// is required; return the model of the copy to write, None if the copy is current, or the error if the copy
+    /// can't be checked.  links are not checked.
+    pub fn check_file(
+        &self,
+        model: &FileModel,
+        stored: Option<&FileModel>,
+    ) -> Result<Option<FileModel>> {
+        let target_path = self.target_path(model);
+
+        if self.is_journaled(model) {
+            return self.check_version(model, target_path.as_path());
+        }
+
+        // if the file exists, check the size and modfied dates; if different then
+        let copy_path = self.copy_path(model, target_path.clone());
+        if copy_path != target_path || !self.backend.is_local() {
+            Ok(self.match_stored(model, stored, copy_path.as_path()))
+        } else {
+            self.match_files(model, target_path.as_path())
+        }
+    }
+
+    /// return the path of the model's plain copy on the target
+    fn target_path(&self, model: &FileModel) -> PathBuf {
         let relative_path = model.relative_path();
         let target_path = Path::join(self.target.as_path(), PathBuf::from(relative_path));
 
         debug!("target path: {}", target_path.to_string_lossy());
 
-        // if the file exists, check the size and modfied dates; if different then
-        let target_model = self.match_files(model, target_path.as_path());
-        target_model.as_ref()?;
+        target_path
+    }
 
-        let target_model = target_model.unwrap();
+    /// recreate the symlink on the target unless a link to the same path is already there
+    fn check_and_copy_link(
+        &self,
+        model: &FileModel,
+        link: &Path,
+        target_path: &Path,
+    ) -> Result<Option<FileModel>> {
+        let relative = self.relative_path(target_path);
+        if self.backend.read_link(&relative).ok().flatten().as_deref() == Some(link) {
+            return Ok(None);
+        }
 
-        match self.copy_model(model, target_model) {
-            Ok(model) => Some(model),
-            Err(_e) => None,
+        if self.dryrun {
+            return Ok(Some(model.clone()));
         }
+
+        if let Err(e) = self.backend.link(&relative, link) {
+            let msg = format!("error linking {}: {:#}", target_path.display(), e);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+
+        let mut model = model.clone();
+        model.last_saved = Some(Utc::now().naive_utc());
+        model
+            .written_to
+            .insert(target_path.to_str().unwrap().to_string());
+
+        Ok(Some(model))
     }
 
-    /// return a new file model if the two don't match or the target does not exist
-    pub fn match_files(&self, ref_model: &FileModel, target_path: &Path) -> Option<FileModel> {
+    /// compare with the latest version on this target; if changed, return the model of a new time-stamped version
+    fn check_version(&self, model: &FileModel, target_path: &Path) -> Result<Option<FileModel>> {
+        let prefix = self.target.to_str().unwrap();
+        if let Some(latest) = model.latest_version(prefix) {
+            let latest_path = Path::new(&latest.path);
+            if self.is_stored_copy(model, latest_path) || !self.backend.is_local() {
+                let saved = latest.saved.and_utc().timestamp_micros() as u64;
+                if self.copy_exists(latest_path)
+                    && self.unchanged_stored(model, latest.len, saved, &latest.hash)
+                {
+                    return Ok(None);
+                }
+            } else if self.match_files(model, latest_path)?.is_none() {
+                return Ok(None);
+            }
+        }
+
+        let version_path =
+            self.copy_path(model, FileVersion::version_path(target_path, Utc::now()));
+        debug!("version path: {}", version_path.display());
+
+        let mut target_model = FileModel::new(version_path.to_str().unwrap());
+        target_model.key = model.key.clone();
+
+        Ok(Some(target_model))
+    }
+
+    /// return true if the file or one of its parent folders is in the journaled list
+    pub fn is_journaled(&self, model: &FileModel) -> bool {
+        let relative_path = model.relative_path();
+        let path = relative_path.trim_start_matches("./");
+
+        self.journaled.iter().any(|journaled| {
+            let journaled = journaled.trim_start_matches("./").trim_end_matches('/');
+            path == journaled || path.starts_with(format!("{}/", journaled).as_str())
+        })
+    }
+
+    /// return true if compression is on and the file is not already in a compressed format
+    pub fn should_compress(&self, model: &FileModel) -> bool {
+        self.compress && !compression::is_compressed_format(model.path.as_path())
+    }
+
+    /// append the compressed and encrypted extensions as needed for the model's copy
+    fn copy_path(&self, model: &FileModel, path: PathBuf) -> PathBuf {
+        let mut path = path;
+        if self.should_compress(model) {
+            path = compression::compressed_path(path.as_path());
+        }
+        if self.key.is_some() {
+            path = encryption::encrypted_path(path.as_path());
+        }
+
+        path
+    }
+
+    /// return true if the copy is compressed or encrypted and so must be compared with the stored record
+    fn is_stored_copy(&self, model: &FileModel, copy: &Path) -> bool {
+        compression::is_compressed_copy(model.path.as_path(), copy)
+            || encryption::is_encrypted_copy(model.path.as_path(), copy)
+    }
+
+    /// return a new file model if the source doesn't match the stored record of the target copy; used
+    /// where the target's size and content differ from the source, e.g. compressed or encrypted copies
+    pub fn match_stored(
+        &self,
+        ref_model: &FileModel,
+        stored: Option<&FileModel>,
+        target_path: &Path,
+    ) -> Option<FileModel> {
         let filename = target_path.to_str().unwrap();
         let mut target_model = FileModel::new(filename);
-        // this ensures that the last_updated gets set to the correct record
         target_model.key = ref_model.key.clone();
 
-        if target_path.exists() {
-            target_model = target_model.read_metadata().unwrap();
-            // assume they are the same...
+        // each copy has its own record, as an earlier target in this run may have saved newer content
+        if let Some(stored) = stored.filter(|stored| stored.written_to.contains(filename)) {
+            if let Some(record) = stored.copy_record(filename) {
+                if self.copy_exists(target_path)
+                    && self.unchanged_stored(ref_model, record.len, record.modified, &record.hash)
+                {
+                    return None;
+                }
+            }
+
+            return Some(target_model);
+        }
 
-            // TODO: need to compare hashes
-            if target_model.len == ref_model.len {
-                // && target_model.modified < ref_model.modified {
+        // without a record, e.g. a lost database, a remote copy may record the source it was written from, or a plain
+        // copy have the size and modified time of its source
+        let Some(stat) = self.target_stat(target_path) else {
+            return Some(target_model);
+        };
+        if let Some(source) = stat.source {
+            if self.unchanged_stored(ref_model, source.len, source.modified, &source.hash) {
+                return None;
+            }
+        } else if let Some(modified) = stat.modified {
+            if !self.is_stored_copy(ref_model, target_path)
+                && self.unchanged_copy(ref_model, stat.len, modified)
+            {
                 return None;
             }
         }
@@ -110,49 +535,314 @@ impl BackupProcess {
         Some(target_model)
     }
 
+    /// return true if a plain copy that can't be hashed has the source's size and is as new; remote times are only
+    /// kept to the second
+    fn unchanged_copy(&self, ref_model: &FileModel, len: u64, modified: u64) -> bool {
+        ref_model.len == len
+            && self.compare != CompareMode::AlwaysHash
+            && modified / 1_000_000 >= ref_model.modified / 1_000_000
+    }
+
+    /// return true if the copy is on the target; a target that can't be checked counts as missing
+    fn copy_exists(&self, path: &Path) -> bool {
+        self.target_stat(path).is_some()
+    }
+
+    /// return the copy's stat, or None if there is no copy or it can't be checked
+    fn target_stat(&self, path: &Path) -> Option<TargetStat> {
+        match self.backend.stat(&self.relative_path(path)) {
+            Ok(stat) => stat,
+            Err(e) => {
+                warn!("could not check {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// apply the compare mode to the source and the stored size, modified time and hash of its copy
+    fn unchanged_stored(&self, ref_model: &FileModel, len: u64, modified: u64, hash: &str) -> bool {
+        if ref_model.len != len {
+            return false;
+        }
+
+        let newer = ref_model.modified > modified;
+        let same_hash =
+            || !hash.is_empty() && self.source_hash(ref_model).ok().as_deref() == Some(hash);
+
+        match self.compare {
+            CompareMode::SizeMtime => !newer,
+            CompareMode::HashOnChange => !newer || same_hash(),
+            CompareMode::AlwaysHash => same_hash(),
+        }
+    }
+
+    /// return a new file model if the two don't match or the target does not exist, or the error if the target
+    /// can't be checked
+    pub fn match_files(
+        &self,
+        ref_model: &FileModel,
+        target_path: &Path,
+    ) -> Result<Option<FileModel>> {
+        let filename = target_path.to_str().unwrap();
+        let mut target_model = FileModel::new(filename);
+        // this ensures that the last_updated gets set to the correct record
+        target_model.key = ref_model.key.clone();
+
+        let stat = match self.backend.stat(&self.relative_path(target_path)) {
+            Ok(stat) => stat,
+            Err(e) => {
+                let msg = format!("could not check {}: {}", target_path.display(), e);
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+        };
+
+        if let Some(stat) = stat {
+            target_model.len = stat.len;
+            target_model.modified = stat.modified.unwrap_or_default();
+
+            if target_model.len == ref_model.len && self.unchanged(ref_model, &target_model) {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(target_model))
+    }
+
+    /// apply the compare mode to two files of equal length; return true if they match
+    fn unchanged(&self, ref_model: &FileModel, target_model: &FileModel) -> bool {
+        let newer = ref_model.modified > target_model.modified;
+
+        match self.compare {
+            CompareMode::SizeMtime => !newer,
+            CompareMode::HashOnChange => !newer || self.same_hash(ref_model, target_model),
+            CompareMode::AlwaysHash => self.same_hash(ref_model, target_model),
+        }
+    }
+
+    /// hash the source (unless already known) and target; a read error counts as a mismatch
+    fn same_hash(&self, ref_model: &FileModel, target_model: &FileModel) -> bool {
+        let dest = self
+            .backend
+            .hash(&self.relative_path(target_model.path.as_path()));
+        match (self.source_hash(ref_model), dest) {
+            (Ok(src), Ok(Some(dest))) => src == dest,
+            (src, dest) => {
+                warn!("hash failed for {:?}: {:?} {:?}", ref_model.path, src, dest);
+                false
+            }
+        }
+    }
+
+    /// return the source's hash, calculating it unless already known
+    fn source_hash(&self, ref_model: &FileModel) -> Result<String> {
+        if !ref_model.hash.is_empty() {
+            return Ok(ref_model.hash.clone());
+        }
+
+        let Some(hashes) = &self.source_hashes else {
+            return ref_model.hash_file();
+        };
+        let key = (ref_model.path.clone(), ref_model.len, ref_model.modified);
+        if let Some(hash) = hashes.lock().unwrap().get(&key) {
+            return Ok(hash.clone());
+        }
+
+        let hash = ref_model.hash_file()?;
+        hashes.lock().unwrap().insert(key, hash.clone());
+
+        Ok(hash)
+    }
+
     /// Copy the source to destination; update the source last_saved date and written to hash;
     /// Return the updated src model
     pub fn copy_model(&self, src: &FileModel, dest: FileModel) -> Result<FileModel> {
-        let save_model = FileModel::copy_from(dest);
-
         if self.dryrun {
-            return Ok(save_model);
+            return Ok(src.clone());
         }
 
+        let save_model = FileModel::copy_from(dest);
+
         let src_path = src.path.as_path();
         let dest_path = save_model.path.as_path();
-        if self.copy(src_path, dest_path).is_err() {
-            let msg = format!("error saving to: {}", dest_path.display());
+        let compressed = compression::is_compressed_copy(src_path, dest_path);
+        let encrypted = encryption::is_encrypted_copy(src_path, dest_path);
+
+        // read before the copy so the attributes describe the content that was copied
+        let attributes = self.read_attributes(src_path);
+
+        // compressed and encrypted copies are matched against the stored hash so always need one; hashed before the
+        // copy so a remote copy can record it
+        let mut model = src.clone();
+        if compressed || encrypted || self.compare != CompareMode::SizeMtime {
+            match model.hash_file() {
+                Ok(hash) => model.hash = hash,
+                Err(e) => warn!("could not hash {}: {}", src_path.display(), e),
+            }
+        }
+
+        let reader: Option<&mut File> = None;
+        if let Err(e) = self.put_copy(reader, &model, dest_path, compressed, encrypted) {
+            let msg = format!("error saving to: {}: {:#}", dest_path.display(), e);
             error!("{}", msg);
             Err(anyhow!("{}", msg))
         } else {
-            let now = Utc::now().naive_utc();
-            let write_path = dest_path.to_str().unwrap();
+            Ok(self.saved_model(model, dest_path, compressed, encrypted, attributes))
+        }
+    }
 
-            let mut model = src.clone();
+    /// write the source's content from the reader, e.g. a source read once for several targets, to the copy;
+    /// return the updated src model.  the hash is left to the caller, which sees the whole content.
+    pub fn write_model<R: Read>(
+        &self,
+        src: &FileModel,
+        dest: FileModel,
+        reader: &mut R,
+    ) -> Result<FileModel> {
+        let src_path = src.path.as_path();
+        let dest_path = dest.path.as_path();
+        let compressed = compression::is_compressed_copy(src_path, dest_path);
+        let encrypted = encryption::is_encrypted_copy(src_path, dest_path);
+        let attributes = self.read_attributes(src_path);
 
-            model.last_saved = Some(now);
-            model.written_to.insert(write_path.to_string());
+        self.put_copy(Some(reader), src, dest_path, compressed, encrypted)?;
+
+        Ok(self.saved_model(src.clone(), dest_path, compressed, encrypted, attributes))
+    }
+
+    /// read the source's attributes to carry to the copy; a failure is logged
+    fn read_attributes(&self, src: &Path) -> Option<FileAttributes> {
+        match FileAttributes::read(src, self.preserve_owner, self.preserve_xattrs) {
+            Ok(attributes) => Some(attributes),
+            Err(e) => {
+                warn!("could not read attributes of {}: {}", src.display(), e);
+                None
+            }
+        }
+    }
+
+    /// apply the attributes to the new copy and return the model updated with the save
+    fn saved_model(
+        &self,
+        mut model: FileModel,
+        dest_path: &Path,
+        compressed: bool,
+        encrypted: bool,
+        attributes: Option<FileAttributes>,
+    ) -> FileModel {
+        let now = Utc::now().naive_utc();
+        let write_path = dest_path.to_str().unwrap();
 
-            Ok(model)
+        // extended attributes are left off encrypted copies as they are not encrypted; remote copies only record
+        // the attributes for a restore
+        if let (Some(attributes), true) = (&attributes, self.backend.is_local()) {
+            attributes.apply(dest_path, !encrypted);
         }
+
+        model.last_saved = Some(now);
+        model.compressed = compressed;
+        model.encrypted = encrypted;
+        model.attributes = attributes;
+        if self.is_journaled(&model) {
+            model.versions.push(FileVersion {
+                path: write_path.to_string(),
+                saved: now,
+                len: model.len,
+                hash: model.hash.clone(),
+            });
+        } else {
+            model.written_to.insert(write_path.to_string());
+            model.copies.insert(
+                write_path.to_string(),
+                CopyRecord {
+                    len: model.len,
+                    modified: model.modified,
+                    hash: model.hash.clone(),
+                    saved: now,
+                },
+            );
+        }
+
+        model
     }
 
-    /// copy from src to dest
+    /// copy from src to dest through a temp file so dest is never left partly written
     pub fn copy(&self, src: &Path, dest: &Path) -> Result<()> {
-        let parent = dest.parent().expect("the destination shoul have a parent");
+        let resp = self
+            .backend
+            .put(src, &self.relative_path(dest), &SourceMeta::default());
+        if let Err(e) = resp {
+            let msg = format!(
+                "error copying {} to {}: {:#}",
+                src.display(),
+                dest.display(),
+                e
+            );
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+
+        Ok(())
+    }
 
-        if !parent.exists() {
-            info!("create the parent folder: {:?}", &parent);
-            if fs::create_dir_all(parent).is_err() {
-                let msg = format!("error creating parent folder: {}", parent.display());
+    /// write a compressed and/or encrypted copy of src to dest; encryption uses the process key
+    pub fn store_copy(
+        &self,
+        src: &FileModel,
+        dest: &Path,
+        compressed: bool,
+        encrypted: bool,
+    ) -> Result<()> {
+        let mut reader = match File::open(&src.path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) => {
+                let msg = format!("error reading {}: {}", src.path.display(), e);
                 error!("{}", msg);
                 return Err(anyhow!("{}", msg));
             }
-        }
+        };
+
+        self.store_from(&mut reader, src, dest, compressed, encrypted)
+    }
 
-        if fs::copy(src, dest).is_err() {
-            let msg = format!("error copying {} to {}", src.display(), dest.display());
+    /// write the content read for src to dest through the target, compressed and/or encrypted as requested; the
+    /// copy records the hash of the content that was read
+    fn store_from<R: Read>(
+        &self,
+        reader: &mut R,
+        src: &FileModel,
+        dest: &Path,
+        compressed: bool,
+        encrypted: bool,
+    ) -> Result<()> {
+        let key = match (encrypted, &self.key) {
+            (true, None) => {
+                let msg = format!("no encryption key to write {}", dest.display());
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+            (true, Some(key)) => Some(key),
+            (false, _) => None,
+        };
+
+        let mut reader = HashReader::new(reader);
+        let resp = self
+            .backend
+            .write(&self.relative_path(dest), &mut |writer| {
+                write_copy(&mut reader, writer, compressed, key)?;
+                Ok(SourceMeta {
+                    len: src.len,
+                    modified: src.modified,
+                    hash: reader.finish(),
+                })
+            });
+        if let Err(e) = resp {
+            let msg = format!(
+                "error writing {} to {}: {}",
+                src.path.display(),
+                dest.display(),
+                e
+            );
             error!("{}", msg);
             return Err(anyhow!("{}", msg));
         }
@@ -160,15 +850,107 @@ impl BackupProcess {
         Ok(())
     }
 
+    /// return the path of a copy relative to the target
+    fn relative_path(&self, path: &Path) -> String {
+        target::relative_to(self.target.to_str().unwrap(), path.to_str().unwrap())
+    }
+
+    /// put the copy of src to dest on the target with a record of the source; plain copies of the source file are
+    /// put as they are, others are written through the target from the reader, or the source file if there is none
+    fn put_copy<R: Read>(
+        &self,
+        reader: Option<&mut R>,
+        src: &FileModel,
+        dest: &Path,
+        compressed: bool,
+        encrypted: bool,
+    ) -> Result<()> {
+        match reader {
+            Some(reader) => self.store_from(reader, src, dest, compressed, encrypted),
+            None if compressed || encrypted => self.store_copy(src, dest, compressed, encrypted),
+            None => {
+                let source = SourceMeta {
+                    len: src.len,
+                    modified: src.modified,
+                    hash: src.hash.clone(),
+                };
+                self.backend
+                    .put(src.path.as_path(), &self.relative_path(dest), &source)
+            }
+        }
+    }
+
     /// retrun the UTC time in seconds (unix timestamp.) decode with date -r <seconds>
     pub fn timestamp(&self) -> u64 {
         Utc::now().timestamp() as u64
     }
 }
 
+/// a reader that hashes the content as it is read
+struct HashReader<R: Read> {
+    inner: R,
+    hasher: sha::Sha256,
+}
+
+impl<R: Read> HashReader<R> {
+    fn new(inner: R) -> HashReader<R> {
+        HashReader {
+            inner,
+            hasher: sha::Sha256::new(),
+        }
+    }
+
+    /// return the hash of the content read so far, in hex
+    fn finish(&self) -> String {
+        hex::encode(self.hasher.clone().finish())
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+
+        Ok(count)
+    }
+}
+
+/// write the reader's content to the writer, compressed and/or encrypted with the key
+fn write_copy<R: Read>(
+    reader: &mut R,
+    writer: &mut dyn Write,
+    compressed: bool,
+    key: Option<&Key>,
+) -> Result<()> {
+    match key {
+        Some(key) => {
+            let writer = EncryptWriter::new(writer, key)?;
+            write_body(reader, writer, compressed)?.finish()?;
+        }
+        None => {
+            write_body(reader, writer, compressed)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// copy the reader to the writer, compressing if requested; return the writer
+fn write_body<R: Read, W: Write>(reader: &mut R, writer: W, compressed: bool) -> Result<W> {
+    if compressed {
+        compression::compress(reader, writer)
+    } else {
+        let mut writer = writer;
+        io::copy(reader, &mut writer)?;
+        Ok(writer)
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::*;
+    use std::fs;
 
     fn create_filelist() -> Vec<FileModel> {
         let files: Vec<FileModel> = Vec::new();
@@ -198,6 +980,450 @@ mod tests {
         assert!(true);
     }
 
+    #[test]
+    fn process_reuses_key() {
+        let _ = fs::remove_dir_all("tests/tback-tmp/process");
+        fs::create_dir_all("tests/tback-tmp/process").unwrap();
+        let db = KeyValueStore::init(PathBuf::from("tests/data/saved.json")).unwrap();
+        let count = db.dbsize();
+
+        let model = FileModel::new("./tests/file1.txt").read_metadata().unwrap();
+        let backup = BackupProcess::new("tests/tback-tmp/process", vec![model.clone()], false);
+
+        let (db, report) = backup.process(db).unwrap();
+        assert_eq!(db.dbsize(), count);
+        assert_eq!(report.files, 1);
+        assert_eq!(report.copied, 1);
+        assert_eq!(report.bytes, model.len);
+        assert_eq!(report.failed, 0);
+
+        let saved = db.find("./tests/file1.txt").unwrap();
+        assert_eq!(saved.key, "4LWn7mr28UxySwNG");
+        assert_eq!(saved.path, model.path);
+        assert_eq!(saved.written_to.len(), 1);
+    }
+
+    #[test]
+    fn process_dryrun() {
+        let db = KeyValueStore::init(PathBuf::from("tests/data/saved.json")).unwrap();
+
+        let model = FileModel::new("./tests/file3.txt").read_metadata().unwrap();
+        let backup = BackupProcess::new("tests/tback-tmp/dryrun", vec![model], true);
+
+        let (db, report) = backup.process(db).unwrap();
+        assert!(!db.is_dirty());
+        assert!(db.find("./tests/file3.txt").is_none());
+        assert_eq!(report.copied, 1);
+    }
+
+    #[test]
+    fn process_failures() {
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let mut missing = FileModel::new("./tests/file1.txt").read_metadata().unwrap();
+        missing.path = PathBuf::from("./tests/no-such-file.txt");
+        let backup = BackupProcess::new("tests/tback-tmp/failures", vec![missing], false);
+
+        let (db, report) = backup.process(db).unwrap();
+        assert_eq!(db.dbsize(), 0);
+        assert_eq!(report.failed, 1);
+        assert_eq!(report.failures[0].path, "./tests/no-such-file.txt");
+        assert!(report.failures[0].error.contains("No such file"));
+    }
+
+    #[test]
+    fn is_journaled() {
+        let mut backup = BackupProcess::new("./", vec![], true);
+        backup.journaled = vec!["tests/.config/".to_string(), "tests/file1.txt".to_string()];
+
+        assert!(backup.is_journaled(&FileModel::new("./tests/.config/file1.txt")));
+        assert!(backup.is_journaled(&FileModel::new("./tests/file1.txt")));
+        assert!(!backup.is_journaled(&FileModel::new("./tests/file1.txt.bak")));
+        assert!(!backup.is_journaled(&FileModel::new("./tests/.configs/file1.txt")));
+    }
+
+    #[test]
+    fn process_journaled() {
+        use std::{thread, time::Duration};
+
+        let target = "tests/tback-tmp/journal";
+        let _ = fs::remove_dir_all(target);
+        fs::create_dir_all("tests/tback-tmp").unwrap();
+        let src_path = "./tests/tback-tmp/journaled.txt";
+        fs::write(src_path, "first version").unwrap();
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let model = FileModel::new(src_path).read_metadata().unwrap();
+        let mut backup = BackupProcess::new(target, vec![model], false);
+        backup.journaled = vec!["tests/tback-tmp/journaled.txt".to_string()];
+
+        let (db, _) = backup.process(db).unwrap();
+        let saved = db.find(src_path).unwrap();
+        assert!(saved.written_to.is_empty());
+        assert_eq!(saved.versions.len(), 1);
+        let first = saved.versions[0].clone();
+        assert!(Path::new(&first.path).exists());
+        assert!(first.path.contains("journaled.txt@"));
+
+        // unchanged, so no new version
+        let (db, _) = backup.process(db).unwrap();
+        assert_eq!(db.find(src_path).unwrap().versions.len(), 1);
+
+        // changed; the version stamp has a one second resolution
+        thread::sleep(Duration::from_millis(1100));
+        fs::write(src_path, "second version, longer").unwrap();
+        let model = FileModel::new(src_path).read_metadata().unwrap();
+        backup.files = vec![model];
+
+        let (db, _) = backup.process(db).unwrap();
+        let saved = db.find(src_path).unwrap();
+        assert_eq!(saved.versions.len(), 2);
+        assert_eq!(fs::read_to_string(&first.path).unwrap(), "first version");
+        let latest = saved.latest_version(target).unwrap();
+        assert_eq!(
+            fs::read_to_string(&latest.path).unwrap(),
+            "second version, longer"
+        );
+    }
+
+    #[test]
+    fn process_compressed() {
+        let target = "tests/tback-tmp/compressed";
+        let _ = fs::remove_dir_all(target);
+
+        let files: Vec<FileModel> = ["./tests/file1.txt", "./tests/big-file.pdf"]
+            .iter()
+            .map(|path| FileModel::new(path).read_metadata().unwrap())
+            .collect();
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let mut backup = BackupProcess::new(target, files, false);
+        backup.compress = true;
+
+        let (mut db, _) = backup.process(db).unwrap();
+        assert_eq!(db.dbsize(), 2);
+
+        let saved = db.find("./tests/file1.txt").unwrap();
+        assert!(saved.compressed);
+        assert!(!saved.hash.is_empty());
+        let copy = format!("{}/./tests/file1.txt.gz", target);
+        assert!(saved.written_to.contains(&copy));
+        assert!(Path::new(&copy).exists());
+
+        // already compressed formats are copied as-is
+        let saved = db.find("./tests/big-file.pdf").unwrap();
+        assert!(!saved.compressed);
+        assert!(Path::new(&format!("{}/./tests/big-file.pdf", target)).exists());
+
+        // nothing has changed, so nothing is written
+        db.savedb("tests/tback-tmp/compressed-db.json").unwrap();
+        let (db, _) = backup.process(db).unwrap();
+        assert!(!db.is_dirty());
+
+        for compare in [CompareMode::SizeMtime, CompareMode::AlwaysHash] {
+            backup.compare = compare;
+            let (db, _) = backup.process(db.clone()).unwrap();
+            assert!(!db.is_dirty());
+        }
+    }
+
+    #[test]
+    fn process_encrypted() {
+        let target = "tests/tback-tmp/encrypted";
+        let _ = fs::remove_dir_all(target);
+
+        let files: Vec<FileModel> = ["./tests/file1.txt", "./tests/big-file.pdf"]
+            .iter()
+            .map(|path| FileModel::new(path).read_metadata().unwrap())
+            .collect();
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let mut backup = BackupProcess::new(target, files, false);
+        backup.compress = true;
+        backup.key = Some(Key::from_bytes([3u8; 32]));
+
+        let (db, _) = backup.process(db).unwrap();
+
+        let saved = db.find("./tests/file1.txt").unwrap();
+        assert!(saved.compressed && saved.encrypted);
+        let copy = format!("{}/./tests/file1.txt.gz.enc", target);
+        assert!(saved.written_to.contains(&copy));
+        assert_ne!(
+            fs::read(&copy).unwrap(),
+            fs::read("tests/file1.txt").unwrap()
+        );
+
+        let saved = db.find("./tests/big-file.pdf").unwrap();
+        assert!(!saved.compressed && saved.encrypted);
+        assert!(saved
+            .written_to
+            .contains(&format!("{}/./tests/big-file.pdf.enc", target)));
+
+        // nothing has changed, so nothing is written
+        let mut db = db;
+        db.savedb("tests/tback-tmp/encrypted-db.json").unwrap();
+        let (db, _) = backup.process(db).unwrap();
+        assert!(!db.is_dirty());
+    }
+
+    #[test]
+    fn store_copy_no_key() {
+        let backup = BackupProcess::new("./", vec![], false);
+        let src = FileModel::new("tests/file2.txt");
+        let dest = Path::new("tests/tback-tmp/no-key/file2.txt.enc");
+        assert!(backup.store_copy(&src, dest, false, true).is_err());
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn process_preserves_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let folder = "tests/tback-tmp/preserve";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/target", folder)).unwrap();
+        let src = format!("./{}/run.sh", folder);
+        fs::write(&src, "#!/bin/sh\necho ok\n").unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o755)).unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_mtime(&src, mtime).unwrap();
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let model = FileModel::new(&src).read_metadata().unwrap();
+        let target = format!("{}/target", folder);
+        let backup = BackupProcess::new(&target, vec![model.clone()], false);
+        let (db, _) = backup.process(db).unwrap();
+
+        let copy = format!("{}/{}", target, model.relative_path());
+        let meta = fs::metadata(&copy).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o755);
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta),
+            mtime
+        );
+
+        let saved = db.find(&src).unwrap();
+        let attributes = saved.attributes.as_ref().unwrap();
+        assert_eq!(attributes.mode, 0o755);
+        assert_eq!(attributes.mtime, 1_600_000_000);
+        assert!(attributes.uid.is_none());
+
+        // the copy now matches by size and modified time so the next pass skips it
+        let model = db.identify(model);
+        assert!(backup.check_and_copy_file(&model, Some(saved)).is_none());
+    }
+
+    #[test]
+    fn process_links() {
+        let folder = "tests/tback-tmp/links";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/target", folder)).unwrap();
+        let src = format!("./{}/link.txt", folder);
+        std::os::unix::fs::symlink("real.txt", &src).unwrap();
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let model = FileModel::from_link(Path::new(&src)).unwrap();
+        let target = format!("{}/target", folder);
+        let copy = PathBuf::from(format!("{}/{}", target, model.relative_path()));
+
+        let backup = BackupProcess::new(&target, vec![model.clone()], true);
+        let (db, _) = backup.process(db).unwrap();
+        assert!(copy.symlink_metadata().is_err());
+
+        // a dangling link is stored all the same
+        let backup = BackupProcess::new(&target, vec![model.clone()], false);
+        let (db, _) = backup.process(db).unwrap();
+        assert_eq!(fs::read_link(&copy).unwrap(), PathBuf::from("real.txt"));
+        let saved = db.find(&src).unwrap().clone();
+        assert!(saved.written_to.contains(copy.to_str().unwrap()));
+        assert!(backup.check_and_copy_file(&saved, Some(&saved)).is_none());
+
+        // a changed link replaces the old one
+        let mut changed = saved.clone();
+        changed.link_target = Some(PathBuf::from("other.txt"));
+        assert!(backup.check_and_copy_file(&changed, Some(&saved)).is_some());
+        assert_eq!(fs::read_link(&copy).unwrap(), PathBuf::from("other.txt"));
+    }
+
+    #[test]
+    fn process_concurrent() {
+        let folder = "tests/tback-tmp/concurrent";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/src/sub", folder)).unwrap();
+        fs::create_dir_all(format!("{}/target", folder)).unwrap();
+
+        let files: Vec<FileModel> = (0..20)
+            .map(|idx| {
+                let path = format!("./{}/src/sub/file-{}.txt", folder, idx);
+                fs::write(&path, "x".repeat(idx * 100)).unwrap();
+                FileModel::new(&path).read_metadata().unwrap()
+            })
+            .collect();
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let target = format!("{}/target", folder);
+        let mut backup = BackupProcess::new(&target, files.clone(), false);
+        backup.concurrency = 4;
+        let (db, _) = backup.process(db).unwrap();
+
+        assert_eq!(db.dbsize(), 20);
+        for model in files.iter() {
+            let saved = db.find(model.path.to_str().unwrap()).unwrap();
+            assert_eq!(saved.key, model.key);
+            assert!(saved.last_saved.is_some());
+            let copy = format!("{}/{}", target, model.relative_path());
+            assert_eq!(fs::metadata(copy).unwrap().len(), model.len);
+        }
+
+        // nothing changed so a second pass copies nothing
+        let mut db = db;
+        db.savedb("tests/tback-tmp/concurrent-db.json").unwrap();
+        let (db, _) = backup.process(db).unwrap();
+        assert!(!db.is_dirty());
+    }
+
+    #[test]
+    fn process_repository() {
+        let folder = "tests/tback-tmp/process-repository";
+        let target = format!("{}/target", folder);
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/src", folder)).unwrap();
+
+        let model = |name: &str| {
+            FileModel::new(&format!("./{}/src/{}", folder, name))
+                .read_metadata()
+                .unwrap()
+        };
+        let count = |dir: &str| fs::read_dir(format!("{}/{}", target, dir)).unwrap().count();
+
+        // a.pdf and b.pdf have the same content, stored once
+        fs::copy("tests/big-file.pdf", format!("{}/src/a.pdf", folder)).unwrap();
+        fs::copy("tests/big-file.pdf", format!("{}/src/b.pdf", folder)).unwrap();
+        fs::copy("tests/file1.txt", format!("{}/src/c.txt", folder)).unwrap();
+        let files = vec![model("a.pdf"), model("b.pdf"), model("c.txt")];
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let mut backup = BackupProcess::new(&target, files, false);
+        backup.format = TargetFormat::Repository;
+        backup.compress = true;
+        let (db, report) = backup.process(db).unwrap();
+        assert_eq!(report.copied, 3);
+        assert_eq!(count(repository::SNAPSHOTS), 1);
+        let chunks = count(repository::CHUNKS);
+        assert_eq!(chunks, 2);
+
+        let saved = db.find(&format!("./{}/src/a.pdf", folder)).unwrap();
+        assert_eq!(saved.hash, model("a.pdf").hash_file().unwrap());
+        assert_eq!(saved.written_to.len(), 1);
+        assert!(saved
+            .written_to
+            .iter()
+            .all(|path| path.starts_with(&format!("{}/snapshots/", target))));
+
+        // nothing changed, so nothing is written
+        let (db, report) = backup.process(db).unwrap();
+        assert_eq!(report.skipped, 3);
+        assert_eq!(count(repository::SNAPSHOTS), 1);
+
+        // a renamed file adds a snapshot but no chunks
+        fs::rename(
+            format!("{}/src/c.txt", folder),
+            format!("{}/src/d.txt", folder),
+        )
+        .unwrap();
+        backup.files = vec![model("a.pdf"), model("b.pdf"), model("d.txt")];
+        let (_, report) = backup.process(db).unwrap();
+        assert_eq!(report.copied, 1);
+        assert_eq!(count(repository::SNAPSHOTS), 2);
+        assert_eq!(count(repository::CHUNKS), chunks);
+
+        // the renamed file's old path is dropped from the new snapshot
+        let repository = Repository::open(Arc::clone(&backup.backend)).unwrap();
+        let mut paths: Vec<String> = repository.previous.files.keys().cloned().collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                model("a.pdf").relative_path(),
+                model("b.pdf").relative_path(),
+                model("d.txt").relative_path()
+            ]
+        );
+    }
+
+    #[test]
+    fn process_changed_second_target() {
+        let folder = "tests/tback-tmp/second-target";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/src", folder)).unwrap();
+        let source = format!("./{}/src/notes.txt", folder);
+        let plain = format!("{}/plain", folder);
+        let stored = format!("{}/stored", folder);
+
+        let run = |db: KeyValueStore, content: &str| {
+            fs::write(&source, content).unwrap();
+            let model = FileModel::new(&source).read_metadata().unwrap();
+
+            let mut db = db;
+            let mut copied = vec![];
+            for target in [&plain, &stored] {
+                let mut backup = BackupProcess::new(target, vec![model.clone()], false);
+                if target == &stored {
+                    backup.compress = true;
+                    backup.key = Some(Key::from_bytes([4u8; 32]));
+                }
+                let (next, report) = backup.process(db).unwrap();
+                db = next;
+                copied.push(report.copied);
+            }
+            (db, copied)
+        };
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let (db, copied) = run(db, "the first save");
+        assert_eq!(copied, vec![1, 1]);
+
+        // the first target's save of the new content must not hide the second target's old copy
+        let (db, copied) = run(db, "the second, longer save");
+        assert_eq!(copied, vec![1, 1]);
+
+        let saved = db.find(&source).unwrap();
+        let stored_copy = format!("{}/{}.gz.enc", stored, saved.relative_path());
+        assert_eq!(saved.copies[&stored_copy].len, 23);
+        assert_eq!(saved.copies.len(), 2);
+
+        let (_, copied) = run(db, "the second, longer save");
+        assert_eq!(copied, vec![0, 0]);
+    }
+
+    #[test]
+    fn process_stop() {
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let model = FileModel::new("./tests/file1.txt").read_metadata().unwrap();
+        let backup = BackupProcess::new("tests/tback-tmp/stopped", vec![model], false);
+        backup.stop.store(true, Ordering::Relaxed);
+
+        let (db, _) = backup.process(db).unwrap();
+        assert_eq!(db.dbsize(), 0);
+    }
+
+    #[test]
+    fn process_duplicate_paths() {
+        let target = "tests/tback-tmp/duplicate-paths";
+        let _ = fs::remove_dir_all(target);
+        let model = FileModel::new("./tests/file2.txt").read_metadata().unwrap();
+        let files = vec![model.clone(), model.clone(), model];
+
+        // two workers would otherwise write the same temp file at once
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let mut backup = BackupProcess::new(target, files, false);
+        backup.concurrency = 2;
+        let (db, report) = backup.process(db).unwrap();
+        assert_eq!(report.files, 1);
+        assert_eq!(report.copied, 1);
+        assert_eq!(report.failed, 0);
+        assert_eq!(db.dbsize(), 1);
+    }
+
     #[test]
     fn copy_model() {
         let src = FileModel::new("tests/file3.txt");
@@ -238,6 +1464,38 @@ mod tests {
         assert!(response.is_ok());
     }
 
+    #[test]
+    fn copy_atomic() {
+        let folder = "tests/tback-tmp/atomic";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/sub", folder)).unwrap();
+        let dest = PathBuf::from(format!("{}/file1.txt", folder));
+        let temp = PathBuf::from(format!("{}/file1.txt{}", folder, TEMP_EXTENSION));
+        fs::write(&dest, "previous copy").unwrap();
+
+        // a failed copy leaves the previous copy in place and no temp file
+        let backup = BackupProcess::new(folder, vec![], false);
+        assert!(backup
+            .copy(Path::new("tests/no-such-file.txt"), &dest)
+            .is_err());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "previous copy");
+        assert!(!temp.exists());
+
+        backup.copy(Path::new("tests/file1.txt"), &dest).unwrap();
+        assert_eq!(fs::metadata(&dest).unwrap().len(), 186);
+        assert!(!temp.exists());
+
+        // leftovers of an interrupted run are removed
+        fs::write(&temp, "partial").unwrap();
+        fs::write(format!("{}/sub/x.gz{}", folder, TEMP_EXTENSION), "partial").unwrap();
+        let dryrun = BackupProcess::new(folder, vec![], true);
+        assert_eq!(dryrun.remove_temp_files(), 0);
+        assert!(temp.exists());
+        assert_eq!(backup.remove_temp_files(), 2);
+        assert!(!temp.exists());
+        assert!(dest.exists());
+    }
+
     #[test]
     fn match_files() {
         let src = FileModel::new("tests/file2.txt");
@@ -246,7 +1504,7 @@ mod tests {
         println!("src: {:?}, dest: {}", src, dest.display());
 
         let backup = BackupProcess::new("./", vec![], true);
-        let response = backup.match_files(&src, dest);
+        let response = backup.match_files(&src, dest).unwrap();
 
         println!("{:?}", response);
         assert!(response.is_none());
@@ -260,12 +1518,73 @@ mod tests {
         println!("src: {:?}, dest: {}", src, dest.display());
 
         let backup = BackupProcess::new("./", vec![], true);
-        let response = backup.match_files(&src, dest);
+        let response = backup.match_files(&src, dest).unwrap();
 
         println!("{:?}", response);
         assert!(response.is_some());
     }
 
+    #[test]
+    fn match_same_size_files() {
+        use std::{thread, time::Duration};
+
+        fs::create_dir_all("tests/tback-tmp").unwrap();
+        let src_path = "tests/tback-tmp/same-size-src.txt";
+        let dest = Path::new("tests/tback-tmp/same-size-dest.txt");
+        fs::write(src_path, "first version").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        fs::write(dest, "other version").unwrap();
+
+        let src = FileModel::new(src_path).read_metadata().unwrap();
+        let mut backup = BackupProcess::new("./", vec![], true);
+
+        // the target is newer and the same size, so only a hash will catch it
+        backup.compare = CompareMode::SizeMtime;
+        assert!(backup.match_files(&src, dest).unwrap().is_none());
+        backup.compare = CompareMode::HashOnChange;
+        assert!(backup.match_files(&src, dest).unwrap().is_none());
+        backup.compare = CompareMode::AlwaysHash;
+        assert!(backup.match_files(&src, dest).unwrap().is_some());
+
+        // now the source is newer
+        thread::sleep(Duration::from_millis(10));
+        fs::write(src_path, "third version").unwrap();
+        let src = FileModel::new(src_path).read_metadata().unwrap();
+
+        backup.compare = CompareMode::SizeMtime;
+        assert!(backup.match_files(&src, dest).unwrap().is_some());
+        backup.compare = CompareMode::HashOnChange;
+        assert!(backup.match_files(&src, dest).unwrap().is_some());
+
+        // same content, source newer
+        fs::write(dest, "third version").unwrap();
+        thread::sleep(Duration::from_millis(10));
+        fs::write(src_path, "third version").unwrap();
+        let src = FileModel::new(src_path).read_metadata().unwrap();
+
+        backup.compare = CompareMode::SizeMtime;
+        assert!(backup.match_files(&src, dest).unwrap().is_some());
+        backup.compare = CompareMode::HashOnChange;
+        assert!(backup.match_files(&src, dest).unwrap().is_none());
+    }
+
+    #[test]
+    fn copy_model_hash() {
+        fs::create_dir_all("tests/tback-tmp").unwrap();
+        let src = FileModel::new("tests/file2.txt");
+        let dest = FileModel::new("tests/tback-tmp/hashed-file2.txt");
+
+        let backup = BackupProcess::new("./", vec![], false);
+        let model = backup.copy_model(&src, dest).unwrap();
+        assert_eq!(model.hash, src.hash_file().unwrap());
+
+        let mut backup = BackupProcess::new("./", vec![], false);
+        backup.compare = CompareMode::SizeMtime;
+        let dest = FileModel::new("tests/tback-tmp/hashed-file2.txt");
+        let model = backup.copy_model(&src, dest).unwrap();
+        assert!(model.hash.is_empty());
+    }
+
     #[test]
     fn timestamp() {
         let path = "tests/";
@@ -1,23 +1,37 @@
 //!
 //!
+#![allow(clippy::empty_docs)]
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
 use log::{error, info, warn};
 use replica::backup_process::BackupProcess;
-use replica::config::Config;
+use replica::config::{self, Config, TargetFormat};
+use replica::encryption::Key;
+use replica::fan_out::FanOut;
+use replica::file_model::FileModel;
 use replica::file_walker::FileWalker;
+use replica::file_watcher::FileWatcher;
 use replica::kv_store::KeyValueStore;
+use replica::restore_process::RestoreProcess;
+use replica::run_report::{RunReport, RunStatus, TargetReport};
+use replica::target;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use std::env;
-use std::path::PathBuf;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug, Default, Parser)]
 #[clap(name = "replica", author, version, about, long_about = None)]
 pub struct Cli {
-    /// set and alternate configuration file
-    #[clap(short, long, value_parser, default_value_t = String::from(".replica/config/config.toml"))]
-    pub config: String,
+    /// set an alternate configuration file; defaults to $REPLICA_CONFIG, then the first of
+    /// $XDG_CONFIG_HOME/replica/config.toml and ~/.replica/config/config.toml that exists
+    #[clap(short, long, value_parser)]
+    pub config: Option<String>,
 
     /// set verbose to log to console
     #[clap(short, long, value_parser)]
@@ -26,20 +40,96 @@ pub struct Cli {
     /// run the full db read, file walker, queue but skip process queue
     #[clap(short, long, value_parser, default_value_t = false)]
     pub dryrun: bool,
+
+    /// keep running, backing up each config interval until stopped with SIGTERM or SIGINT
+    #[clap(long, value_parser, default_value_t = false)]
+    pub daemon: bool,
+
+    /// write the run report as json to this file, or - for stdout
+    #[clap(long, value_parser)]
+    pub report: Option<String>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// restore files from a target back to their original location
+    Restore(RestoreArgs),
+    /// list the saved versions of journaled files
+    Versions(VersionsArgs),
+    /// work with the config file
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// write a commented starter config and logging config under ~/.replica
+    Init(InitArgs),
+}
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct InitArgs {
+    /// replace existing config files
+    #[clap(long, value_parser, default_value_t = false)]
+    pub force: bool,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// parse and validate the config file, listing each problem found
+    Check,
+}
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct RestoreArgs {
+    /// the target to restore from; defaults to the first configured target that exists
+    #[clap(short, long, value_parser)]
+    pub target: Option<String>,
+
+    /// restore under this folder rather than the original location
+    #[clap(long, value_parser)]
+    pub to: Option<String>,
+
+    /// replace files that already exist
+    #[clap(long, value_parser, default_value_t = false)]
+    pub overwrite: bool,
+
+    /// restore this version stamp of journaled files, e.g. 2026-10-18T10:00:00Z; defaults to the latest
+    #[clap(long, value_parser)]
+    pub version: Option<String>,
+
+    /// glob patterns to select the files to restore; all files if none
+    #[clap(value_parser)]
+    pub patterns: Vec<String>,
 }
 
-/// cd to home folder; panic on fail
-fn cd_app_home(app_home: &str) {
-    let msg = format!("Change to app home: {}", app_home);
-    info!("{}", msg.as_str());
-    env::set_current_dir(app_home).unwrap_or_else(|_| panic!("{}", msg));
+#[derive(Clone, Debug, Default, Args)]
+pub struct VersionsArgs {
+    /// the target to list; defaults to the first configured target that exists
+    #[clap(short, long, value_parser)]
+    pub target: Option<String>,
+
+    /// glob patterns to select the files to list; all files if none
+    #[clap(value_parser)]
+    pub patterns: Vec<String>,
+}
+
+/// cd to home folder; return an error if it can't be entered
+fn cd_app_home(app_home: &str) -> Result<()> {
+    info!("Change to app home: {}", app_home);
+    if let Err(e) = env::set_current_dir(app_home) {
+        let msg = format!("could not change to app home {}: {}", app_home, e);
+        error!("{}", msg);
+        return Err(anyhow!("{}", msg));
+    }
+
+    Ok(())
 }
 
 /// read the cli to override config dryrun and verbose if false
-fn startup(cli: Cli) -> Config {
+fn startup(cli: Cli) -> Result<Config> {
     // println!("cli: {:?}", cli);
-    let mut config = Config::read_config(cli.config.as_str()).expect("config should initialize");
-    config.start_logger().expect("logger should start.");
+    let mut config = Config::read_config(Config::discover(cli.config.as_deref()).as_str())?;
+    config.start_logger()?;
 
     if !config.dryrun {
         config.dryrun = cli.dryrun;
@@ -49,63 +139,515 @@ fn startup(cli: Cli) -> Config {
         config.verbose = cli.verbose;
     }
 
+    if cli.report.is_some() {
+        config.report_file = cli.report;
+    }
+
     info!("replica config: {:?}", config);
+    for problem in config.validate() {
+        warn!("config: {}", problem);
+    }
 
-    config.to_owned()
+    Ok(config.to_owned())
 }
 
-/// the primary process
-fn run(config: Config) -> Result<()> {
+/// the primary process; a single backup pass.  return the status for the exit code
+fn run(config: Config) -> RunStatus {
     let start_time = Instant::now();
 
-    cd_app_home(config.home.as_str());
+    if cd_app_home(config.home.as_str()).is_err() {
+        return RunStatus::ConfigError;
+    }
 
     if config.dryrun {
         warn!("THIS IS A DRY RUN!");
     }
 
     // read the current database DbOps
+    let mut db = match KeyValueStore::init(PathBuf::from(config.dbfile.clone())) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("could not read the database {}: {:?}", config.dbfile, e);
+            return RunStatus::DbError;
+        }
+    };
+    db.snapshots = config.db_snapshots;
+    let key = match load_key(&config) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("could not load the encryption key: {:?}", e);
+            return RunStatus::ConfigError;
+        }
+    };
+
+    // the temp files are only left over if no other run or daemon is writing them
+    if let Some(pid) = Config::running_pid() {
+        error!("replica is already running as process {}", pid);
+        return RunStatus::Error;
+    }
+    Config::write_pid_file();
+
+    remove_temp_files(&config);
+    let (_, report) = backup_pass(&config, db, &key, &Arc::new(AtomicBool::new(false)));
+    write_report(&config, &report);
+    Config::remove_pid_file();
+
+    let elapsed = (start_time.elapsed().as_nanos() as f64) / 1_000_000_000.0;
+    info!("process time: {} seconds", elapsed);
+
+    let status = report.status(config.min_targets);
+    if status == RunStatus::Ok {
+        info!("PROCESS COMPLETE {}", "-".repeat(80));
+    } else {
+        error!("PROCESS FAILED: {:?} {}", status, "-".repeat(80));
+    }
+
+    status
+}
+
+/// run a backup pass each config interval until a SIGTERM or SIGINT; the database is kept between passes.  when
+/// watching, a pass only backs up the files changed since the last pass, with a full walk each full walk interval
+fn daemon(config: Config) -> Result<()> {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        // a second signal while stopping exits immediately
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop))?;
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+
+    daemon_loop(config, stop)
+}
+
+/// the daemon's passes, until the stop flag is set
+fn daemon_loop(config: Config, stop: Arc<AtomicBool>) -> Result<()> {
+    cd_app_home(config.home.as_str())?;
+
+    if let Some(pid) = Config::running_pid() {
+        return Err(anyhow!("replica is already running as process {}", pid));
+    }
+
     let mut db = KeyValueStore::init(PathBuf::from(config.dbfile.clone()))?;
+    db.snapshots = config.db_snapshots;
+    let key = load_key(&config)?;
+
+    let watcher = if config.watch {
+        Some(FileWatcher::new(config.clone())?)
+    } else {
+        None
+    };
+
+    Config::write_pid_file();
+    remove_temp_files(&config);
+    info!("daemon started, interval: {} seconds", config.interval);
+
+    let interval = Duration::from_secs(config.interval);
+    let full_walk_interval = Duration::from_secs(config.full_walk_interval);
+    let mut last_full_walk: Option<Instant> = None;
+    while !stop.load(Ordering::Relaxed) {
+        let start_time = Instant::now();
+        db.start_pass();
+        match &watcher {
+            Some(watcher) if last_full_walk.is_some_and(|t| t.elapsed() < full_walk_interval) => {
+                let files = watcher.changed_files();
+                if !files.is_empty() {
+                    let report;
+                    (db, report) = backup_files(&config, files, true, db, &key, &stop);
+                    write_report(&config, &report);
+                    log_status(&config, &report);
+                }
+            }
+            _ => {
+                // the walk sees everything, so earlier events are no longer needed
+                if let Some(watcher) = &watcher {
+                    watcher.clear();
+                }
+                let report;
+                (db, report) = backup_pass(&config, db, &key, &stop);
+                write_report(&config, &report);
+                log_status(&config, &report);
+                last_full_walk = Some(start_time);
+            }
+        }
+        info!("pass time: {:?}", start_time.elapsed());
 
-    let walker = FileWalker::new(config.clone());
-    if let Ok(files) = walker.walk_files_and_folders() {
-        info!("file count: {}", files.len());
-
-        // loop over the target dirs; if the target exists, then try to backup to it.  if not, then warn
-        for target_dir in config.targets {
-            let backup = BackupProcess::new(target_dir.as_str(), files.clone(), config.dryrun);
-            if !backup.target_exists() {
-                continue;
+        // sleep in short steps so a stop request is seen promptly
+        while start_time.elapsed() < interval && !stop.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    Config::remove_pid_file();
+    info!("DAEMON STOPPED {}", "-".repeat(80));
+
+    Ok(())
+}
+
+/// fail before anything is written if encryption is on but the key can't be read
+fn load_key(config: &Config) -> Result<Option<Key>> {
+    if config.encrypt {
+        Ok(Some(Key::load(config.key_file.as_deref())?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// remove the temp files left on each available target, and the files staged for remote targets, by an
+/// interrupted run
+fn remove_temp_files(config: &Config) {
+    let count = target::remove_staged(config.dryrun);
+    if count > 0 {
+        warn!(
+            "removed {} staged files from {}",
+            count,
+            env::temp_dir().display()
+        );
+    }
+
+    for target_dir in config.targets.iter() {
+        let backup = BackupProcess::new(target_dir.as_str(), vec![], config.dryrun);
+        if backup.target_exists() {
+            let count = backup.remove_temp_files();
+            if count > 0 {
+                warn!("removed {} temp files from {}", count, target_dir);
             }
+        }
+    }
+}
 
-            let results = backup.process(db.clone());
-            if results.is_err() {
-                error!("backup failed: {:?}", results);
-            } else {
-                db = results.unwrap();
-                if db.is_dirty() {
-                    let resp = db.savedb(config.dbfile.as_str());
-                    if resp.is_err() {
-                        error!("database save failed: {:?}", resp);
-                    }
-                }
+/// walk the files and back up to each available target; return the updated database and the run report
+fn backup_pass(
+    config: &Config,
+    db: KeyValueStore,
+    key: &Option<Key>,
+    stop: &Arc<AtomicBool>,
+) -> (KeyValueStore, RunReport) {
+    let walker = FileWalker::new(config.clone());
+    let walk = walker.walk_files_and_folders();
+    if walk.errors.is_empty() {
+        return backup_files(config, walk.files, false, db, key, stop);
+    }
+
+    // back up what was found, but as a partial pass so the paths that couldn't be read aren't dropped
+    let (db, mut report) = backup_files(config, walk.files, true, db, key, stop);
+    let msg = format!(
+        "the walk could not read {} paths, the first: {}",
+        walk.errors.len(),
+        walk.errors[0]
+    );
+    error!("{}", msg);
+    report.error = Some(msg);
+    (db, report)
+}
+
+/// back up the files to each available target, partial if they are only the changed files; return the updated
+/// database and the run report
+fn backup_files(
+    config: &Config,
+    files: Vec<FileModel>,
+    partial: bool,
+    mut db: KeyValueStore,
+    key: &Option<Key>,
+    stop: &Arc<AtomicBool>,
+) -> (KeyValueStore, RunReport) {
+    info!("file count: {}", files.len());
+    let mut report = RunReport::new(files.len(), config.dryrun);
+
+    let (processes, unavailable) = backup_processes(config, &files, partial, key, stop);
+    report.targets = unavailable;
+
+    // read each file once and write to all the targets at once; repositories are written one at a time
+    if config.fan_out && config.format == TargetFormat::Mirror {
+        let mut fan_out = FanOut::new(processes, config.dryrun);
+        fan_out.stop = Arc::clone(stop);
+        let (results, targets) = fan_out.process(db);
+        report.targets.extend(targets);
+        return (save_db(config, results, &mut report), report.finish());
+    }
+
+    for backup in processes {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+
+        match backup.process(db.clone()) {
+            Ok((results, target)) => {
+                db = save_db(config, results, &mut report);
+                report.targets.push(target);
+            }
+            Err(e) => {
+                error!("backup failed: {:?}", e);
+                let target = backup.target.to_str().unwrap();
+                let target = TargetReport::unavailable(target, backup.files.len(), &e);
+                report.targets.push(target);
             }
         }
     }
 
-    let elapsed = (start_time.elapsed().as_nanos() as f64) / 1_000_000_000.0;
-    info!("process time: {} seconds", elapsed);
-    info!("PROCESS COMPLETE {}", "-".repeat(80));
+    (db, report.finish())
+}
+
+/// log a daemon pass that failed the policy; the daemon carries on
+fn log_status(config: &Config, report: &RunReport) {
+    let status = report.status(config.min_targets);
+    if status != RunStatus::Ok {
+        error!("pass failed: {:?}", status);
+    }
+}
+
+/// log the report's summary and write it as json to the report file, if set
+fn write_report(config: &Config, report: &RunReport) {
+    info!("run report: {}", report.summary());
+    for target in report.targets.iter() {
+        if let Some(error) = &target.error {
+            warn!("target failed: {}: {}", target.target, error);
+        }
+        for failure in target.failures.iter() {
+            warn!(
+                "failed: {} to {}: {}",
+                failure.path, target.target, failure.error
+            );
+        }
+    }
+
+    let resp = match config.report_file.as_deref() {
+        None => Ok(()),
+        Some("-") => report.to_json().map(|json| println!("{}", json)),
+        Some(filename) => report.write(filename),
+    };
+    if let Err(e) = resp {
+        error!("could not write the run report: {:?}", e);
+    }
+}
+
+/// return a backup process for each target that exists, with the files routed to it, and a failed report for each
+/// target that doesn't
+fn backup_processes(
+    config: &Config,
+    files: &[FileModel],
+    partial: bool,
+    key: &Option<Key>,
+    stop: &Arc<AtomicBool>,
+) -> (Vec<BackupProcess>, Vec<TargetReport>) {
+    let mut processes = vec![];
+    let mut unavailable = vec![];
+
+    // loop over the target dirs; if the target exists, then try to backup to it.  if not, then warn
+    for target_dir in config.targets.iter() {
+        // files in a source folder with its own targets only go to those targets
+        let routed: Vec<FileModel> = files
+            .iter()
+            .filter(|model| config.routes_to(&model.path, target_dir))
+            .cloned()
+            .collect();
+
+        let mut backup = BackupProcess::new(target_dir.as_str(), routed, config.dryrun);
+        backup.compare = config.compare;
+        backup.journaled = config.journaled.clone();
+        backup.compress = config.compress;
+        backup.key = key.clone();
+        backup.stop = Arc::clone(stop);
+        backup.preserve_owner = config.preserve_owner;
+        backup.preserve_xattrs = config.preserve_xattrs;
+        backup.concurrency = config.concurrency;
+        backup.format = config.format;
+        backup.partial = partial;
+        backup.keep_snapshots = config.repository_snapshots;
+        if backup.target_exists() {
+            processes.push(backup);
+        } else {
+            let error = anyhow!("the target does not exist or could not be reached");
+            let target = TargetReport::unavailable(target_dir, backup.files.len(), &error);
+            unavailable.push(target);
+        }
+    }
+
+    (processes, unavailable)
+}
+
+/// save the database if it changed, recording a failed save in the report; return it
+fn save_db(config: &Config, mut db: KeyValueStore, report: &mut RunReport) -> KeyValueStore {
+    if db.is_dirty() {
+        if let Err(e) = db.savedb(config.dbfile.as_str()) {
+            error!("database save failed: {:?}", e);
+            report.db_error = Some(format!("{:#}", e));
+        }
+    }
+
+    db
+}
+
+/// return the requested target or the first configured target that can be reached
+fn select_target(config: &Config, target: Option<String>) -> Result<String> {
+    match target {
+        Some(target) => Ok(target),
+        None => match config
+            .targets
+            .iter()
+            .find(|target| replica::target::open(target).is_ok_and(|backend| backend.exists()))
+        {
+            Some(target) => Ok(target.to_string()),
+            None => Err(anyhow!("no configured target is available")),
+        },
+    }
+}
+
+/// create the restore process for the requested or first available target
+fn restore_process(config: &Config, target: Option<String>) -> Result<RestoreProcess> {
+    let target = select_target(config, target)?;
+    let process = RestoreProcess::new(target.as_str(), config.dryrun);
+    if !process.target_exists() {
+        return Err(anyhow!("target {} does not exist", target));
+    }
+
+    Ok(process)
+}
+
+/// return the key needed to decrypt copies; required if encryption is on, else used if one is available
+fn restore_key(config: &Config) -> Result<Option<Key>> {
+    if config.encrypt {
+        return Ok(Some(Key::load(config.key_file.as_deref())?));
+    }
+
+    match Key::load(config.key_file.as_deref()) {
+        Ok(key) => Ok(Some(key)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// restore files from the requested or first available target
+fn restore(config: Config, args: RestoreArgs) -> Result<()> {
+    cd_app_home(config.home.as_str())?;
+
+    if config.dryrun {
+        warn!("THIS IS A DRY RUN!");
+    }
+
+    let db = KeyValueStore::init(PathBuf::from(config.dbfile.clone()))?;
+
+    let mut process = restore_process(&config, args.target)?;
+    process.dest_root = args.to.map(PathBuf::from);
+    process.patterns = args.patterns;
+    process.overwrite = args.overwrite;
+    process.version = args.version;
+    process.key = restore_key(&config)?;
+
+    let restored = process.process(&db)?;
+    info!(
+        "restored {} files from {:?}",
+        restored.len(),
+        process.target
+    );
+    info!("RESTORE COMPLETE {}", "-".repeat(80));
 
     Ok(())
 }
 
-fn main() -> Result<()> {
+/// print the versions of the journaled files on the requested or first available target
+fn versions(config: Config, args: VersionsArgs) -> Result<()> {
+    cd_app_home(config.home.as_str())?;
+
+    let db = KeyValueStore::init(PathBuf::from(config.dbfile.clone()))?;
+
+    let mut process = restore_process(&config, args.target)?;
+    process.patterns = args.patterns;
+
+    for (model, version) in process.list_versions(&db)? {
+        println!(
+            "{}  {}  {:>10}  {}",
+            version.stamp(),
+            model.path.display(),
+            version.len,
+            version.path
+        );
+    }
+
+    Ok(())
+}
+
+/// parse and validate the config file, printing the problems found; return the status for the exit code
+fn config_check(filename: &str) -> RunStatus {
+    let config = match Config::read_config(filename) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return RunStatus::ConfigError;
+        }
+    };
+
+    let problems = config.validate();
+    for problem in problems.iter() {
+        eprintln!("{}: {}", filename, problem);
+    }
+
+    if problems.is_empty() {
+        println!("{}: ok", filename);
+        RunStatus::Ok
+    } else {
+        RunStatus::ConfigError
+    }
+}
+
+/// write the starter config files; return the status for the exit code
+fn init(args: InitArgs) -> RunStatus {
+    match Config::init(Path::new(config::DEFAULT_FOLDER), args.force) {
+        Ok(written) => {
+            for path in written.iter() {
+                println!("wrote {}", path.display());
+            }
+            println!(
+                "edit {} then run: replica config check",
+                config::DEFAULT_CONFIG
+            );
+            RunStatus::Ok
+        }
+        Err(e) => {
+            eprintln!("could not write the config: {:#}", e);
+            RunStatus::Error
+        }
+    }
+}
+
+fn main() -> ExitCode {
     let home = env::var("HOME").expect("The user should have a home folder.");
-    cd_app_home(home.as_str());
+    if let Err(e) = cd_app_home(home.as_str()) {
+        eprintln!("{:#}", e);
+        return RunStatus::Error.into();
+    }
+
+    let cli = Cli::parse();
+    let config_file = Config::discover(cli.config.as_deref());
+
+    // run before startup, which needs a readable config
+    match cli.command {
+        Some(Command::Config(ConfigCommand::Check)) => return config_check(&config_file).into(),
+        Some(Command::Init(args)) => return init(args).into(),
+        _ => (),
+    }
 
-    let config = startup(Cli::parse());
-    run(config)
+    let config = match startup(cli.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("could not read the config {}: {:#}", config_file, e);
+            return RunStatus::ConfigError.into();
+        }
+    };
+
+    let resp = match cli.command {
+        Some(Command::Restore(args)) => restore(config, args),
+        Some(Command::Versions(args)) => versions(config, args),
+        Some(Command::Config(_)) | Some(Command::Init(_)) => unreachable!("run before startup"),
+        None if cli.daemon => daemon(config),
+        None => return run(config).into(),
+    };
+
+    match resp {
+        Ok(_) => RunStatus::Ok.into(),
+        Err(e) => {
+            error!("{:?}", e);
+            eprintln!("{:#}", e);
+            RunStatus::Error.into()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -124,9 +666,12 @@ mod tests {
 
     fn dflt_cli() -> Cli {
         Cli {
-            config: get_conf_path(),
+            config: Some(get_conf_path()),
             verbose: false,
             dryrun: false,
+            daemon: false,
+            report: None,
+            command: None,
         }
     }
 
@@ -141,10 +686,11 @@ mod tests {
     fn startup_test() {
         let cli = dflt_cli();
 
-        let config = startup(cli);
+        let config = startup(cli).unwrap();
         println!("ctx: {:?}", config);
 
-        assert!(true);
+        assert_eq!(config.name, "test-run-replica");
+        assert_eq!(config.targets, vec![String::from("tback")]);
     }
 
     #[test]
@@ -159,8 +705,30 @@ mod tests {
         let cli = dflt_cli();
         println!("{:?}", cli);
 
-        let results = run(config);
-        assert!(results.is_ok());
+        // the run config's target doesn't exist
+        assert_eq!(run(config), RunStatus::NoTarget);
+    }
+
+    #[test]
+    fn run_test_target() {
+        let mut config = Config::read_config(get_conf_path().as_str()).unwrap();
+        config.dryrun = true;
+        config.targets = vec![String::from("tests/tback")];
+        assert_eq!(run(config.clone()), RunStatus::Ok);
+
+        // a target that can't be reached fails the policy
+        config.targets.push(String::from("tests/no-such-target"));
+        config.min_targets = 2;
+        assert_eq!(run(config), RunStatus::PartialFailure);
+    }
+
+    #[test]
+    fn startup_bad_config() {
+        let cli = Cli {
+            config: Some(String::from("tests/no-such-config.toml")),
+            ..dflt_cli()
+        };
+        assert!(startup(cli).is_err());
     }
 
     #[test]
@@ -172,14 +740,188 @@ mod tests {
         println!("conf path : {:?}", conf_path);
         let results = run(config);
         println!("{:?}", results);
+        assert_eq!(results, RunStatus::NoTarget);
+    }
+
+    #[test]
+    fn run_test_report() {
+        let mut config = Config::read_config(get_conf_path().as_str()).unwrap();
+        config.dryrun = true;
+        let report_file = env::current_dir()
+            .unwrap()
+            .join("tests/tback-tmp/run-test-report.json");
+        let _ = std::fs::remove_file(&report_file);
+        config.report_file = Some(report_file.to_str().unwrap().to_string());
+
+        run(config);
+
+        let text = std::fs::read_to_string(&report_file).unwrap();
+        let report: RunReport = serde_json::from_str(&text).unwrap();
+        assert!(report.dryrun);
+        assert!(report.files > 0);
+        assert_eq!(report.failed(), 0);
+
+        // the run config's target doesn't exist, and is reported with why
+        assert!(!report.targets.is_empty());
+        assert!(report.targets.iter().all(|target| target.error.is_some()));
+    }
+
+    #[test]
+    fn restore_test() {
+        let config = Config::read_config(get_conf_path().as_str()).unwrap();
+
+        let args = RestoreArgs {
+            target: Some(String::from("tests/tback")),
+            to: Some(String::from("tests/tback-tmp/restore-test")),
+            ..RestoreArgs::default()
+        };
+        assert!(restore(config.clone(), args).is_ok());
+
+        let args = RestoreArgs {
+            target: Some(String::from("tests/no-such-target")),
+            ..RestoreArgs::default()
+        };
+        assert!(restore(config, args).is_err());
+    }
+
+    #[test]
+    fn versions_test() {
+        let config = Config::read_config(get_conf_path().as_str()).unwrap();
+
+        let args = VersionsArgs {
+            target: Some(String::from("tests/tback")),
+            ..VersionsArgs::default()
+        };
+        assert!(versions(config.clone(), args).is_ok());
+
+        let args = VersionsArgs {
+            target: Some(String::from("tests/no-such-target")),
+            ..VersionsArgs::default()
+        };
+        assert!(versions(config, args).is_err());
+    }
+
+    #[test]
+    fn config_check_test() {
+        assert_eq!(config_check(get_conf_path().as_str()), RunStatus::Ok);
+        assert_eq!(
+            config_check("tests/no-such-config.toml"),
+            RunStatus::ConfigError
+        );
+
+        let cli = Cli::parse_from(["replica", "config", "check"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Config(ConfigCommand::Check))
+        ));
+        assert!(cli.config.is_none());
+    }
+
+    #[test]
+    fn parse_init() {
+        let cli = Cli::parse_from(["replica", "init", "--force"]);
+        match cli.command {
+            Some(Command::Init(args)) => assert!(args.force),
+            _ => panic!("expected the init command"),
+        }
+    }
+
+    #[test]
+    fn run_test_missing_key() {
+        let mut config = Config::read_config(get_conf_path().as_str()).unwrap();
+        config.encrypt = true;
+        config.key_file = Some(String::from("tests/no-such.key"));
+
+        assert_eq!(run(config), RunStatus::ConfigError);
+    }
+
+    #[test]
+    fn run_test_bad_home() {
+        let mut config = Config::read_config(get_conf_path().as_str()).unwrap();
+        config.home = String::from("/no-such-replica-home");
+
+        assert_eq!(run(config), RunStatus::ConfigError);
+    }
+
+    #[test]
+    fn run_test_walk_error() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let folder = "tests/tback-tmp/run-unreadable";
+        let locked = format!("{}/locked", folder);
+        let unlock = || {
+            let _ = std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755));
+        };
+        unlock();
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(&locked).unwrap();
+        std::fs::write(format!("{}/open.txt", folder), "open").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let mut config = Config::read_config(get_conf_path().as_str()).unwrap();
+        config.dryrun = true;
+        config.targets = vec![String::from("tests/tback")];
+        config.files = vec![];
+        config.source_folders = vec![folder.to_string()];
+        let status = run(config);
+        let readable = std::fs::read_dir(&locked).is_ok();
+        unlock();
+
+        // root reads the folder anyway
+        if readable {
+            assert_eq!(status, RunStatus::Ok);
+        } else {
+            assert_eq!(status, RunStatus::Error);
+        }
+    }
+
+    #[test]
+    fn daemon_test() {
+        let mut config = Config::read_config(get_conf_path().as_str()).unwrap();
+        config.dryrun = true;
+        config.interval = 1;
+        config.watch = true;
+
+        // stop once the daemon has had time for a couple of passes, as a SIGTERM would
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(1500));
+            flag.store(true, Ordering::Relaxed);
+        });
+
+        let results = daemon_loop(config, stop);
+        handle.join().unwrap();
+
         assert!(results.is_ok());
+        assert!(File::open(replica::PID_FILE).is_err());
+    }
+
+    #[test]
+    fn parse_restore() {
+        let cli = Cli::parse_from([
+            "replica",
+            "--dryrun",
+            "restore",
+            "--overwrite",
+            ".config/**",
+        ]);
+        assert!(cli.dryrun);
+        match cli.command {
+            Some(Command::Restore(args)) => {
+                assert!(args.overwrite);
+                assert_eq!(args.patterns, vec![String::from(".config/**")]);
+            }
+            _ => panic!("expected the restore command"),
+        }
     }
 
     #[test]
     fn test_app_home() {
         let test_home = env::current_dir().expect("should get the current working directory");
         println!("{}", test_home.display());
-        cd_app_home(test_home.to_str().unwrap());
+        cd_app_home(test_home.to_str().unwrap()).unwrap();
+        assert!(cd_app_home("tests/no-such-home").is_err());
     }
 
     #[test]
@@ -0,0 +1,124 @@
+/// Compression - gzip the copies written to a target
+///
+/// # Compression
+///
+/// compressed copies carry a .gz extension on the target; files that are already compressed are copied as-is
+///
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// the extension appended to a compressed copy
+pub const EXTENSION: &str = ".gz";
+
+/// file extensions of formats that do not benefit from another compression pass
+const COMPRESSED_FORMATS: [&str; 30] = [
+    "gz", "tgz", "bz2", "xz", "zst", "lz4", "lzma", "zip", "7z", "rar", "jar", "dmg", "jpg",
+    "jpeg", "png", "gif", "webp", "heic", "avif", "mp3", "m4a", "aac", "ogg", "flac", "mp4", "m4v",
+    "mov", "mkv", "webm", "pdf",
+];
+
+/// return true if the file's extension is a known compressed format
+pub fn is_compressed_format(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => COMPRESSED_FORMATS.contains(&ext.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+/// return the path with the compressed extension appended
+pub fn compressed_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}{}", path.display(), EXTENSION))
+}
+
+/// return true if the copy is a compressed copy of the original, i.e. its name is the original's plus .gz
+pub fn is_compressed_copy(original: &Path, copy: &Path) -> bool {
+    match crate::file_model::copy_suffix(original, copy) {
+        Some(suffix) => suffix
+            .trim_end_matches(crate::encryption::EXTENSION)
+            .ends_with(EXTENSION),
+        None => false,
+    }
+}
+
+/// stream the reader through gzip into the writer; return the writer
+pub fn compress<R: Read, W: Write>(reader: &mut R, writer: W) -> Result<W> {
+    let mut encoder = GzEncoder::new(writer, Compression::default());
+    io::copy(reader, &mut encoder)?;
+
+    Ok(encoder.finish()?)
+}
+
+/// return a reader that decompresses the gzipped reader
+pub fn decompressor<R: Read>(reader: R) -> GzDecoder<R> {
+    GzDecoder::new(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn compressed_formats() {
+        assert!(is_compressed_format(Path::new("tests/big-file.pdf")));
+        assert!(is_compressed_format(Path::new("photos/IMG_0001.JPG")));
+        assert!(!is_compressed_format(Path::new("tests/file1.txt")));
+        assert!(!is_compressed_format(Path::new(".zshrc")));
+    }
+
+    #[test]
+    fn compressed_copy() {
+        let original = Path::new("./tests/file1.txt");
+        let copy = compressed_path(Path::new("tback/./tests/file1.txt"));
+        assert_eq!(copy, PathBuf::from("tback/./tests/file1.txt.gz"));
+        assert!(is_compressed_copy(original, &copy));
+        assert!(is_compressed_copy(
+            original,
+            Path::new("tback/tests/file1.txt@2026-10-18T10:00:00Z.gz")
+        ));
+        assert!(!is_compressed_copy(
+            original,
+            Path::new("tback/tests/file1.txt")
+        ));
+        assert!(!is_compressed_copy(
+            Path::new("logs.tar.gz"),
+            Path::new("tback/logs.tar.gz")
+        ));
+    }
+
+    #[test]
+    fn compress_decompress() {
+        let content = fs::read("tests/file1.txt").unwrap();
+
+        let packed = compress(&mut content.as_slice(), vec![]).unwrap();
+        assert_ne!(packed, content);
+
+        let mut unpacked = vec![];
+        decompressor(packed.as_slice())
+            .read_to_end(&mut unpacked)
+            .unwrap();
+        assert_eq!(unpacked, content);
+
+        let mut unpacked = vec![];
+        assert!(decompressor(content.as_slice())
+            .read_to_end(&mut unpacked)
+            .is_err());
+    }
+
+    #[test]
+    fn compressed_copy_encrypted() {
+        let original = Path::new("./tests/file1.txt");
+        assert!(is_compressed_copy(
+            original,
+            Path::new("tback/tests/file1.txt.gz.enc")
+        ));
+        assert!(!is_compressed_copy(
+            original,
+            Path::new("tback/tests/file1.txt.enc")
+        ));
+    }
+}
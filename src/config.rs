@@ -1,7 +1,9 @@
-use anyhow::Result;
-use log::{info, warn};
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use std::{
     fs::File,
     io::{BufReader, Read},
@@ -9,39 +11,419 @@ use std::{
 
 use crate::VERSION;
 
+/// the strategy used to decide if a source file differs from its target copy
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompareMode {
+    /// compare the size and modified time only; never reads file content
+    SizeMtime,
+    /// compare the size; hash both files only when the source is newer than the target
+    #[default]
+    HashOnChange,
+    /// compare the size then always hash both files
+    AlwaysHash,
+}
+
+/// how symlinks found in source folders are backed up
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkMode {
+    /// leave symlinks out of the backup
+    #[default]
+    Skip,
+    /// back up the link itself, recreated as a link on the target
+    Store,
+    /// back up what the link points to; links that loop back to a parent folder are skipped
+    Follow,
+}
+
+/// how copies are laid out on a target
+#[derive(Debug, Default, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TargetFormat {
+    /// a copy of each file at its relative path
+    #[default]
+    Mirror,
+    /// deduplicated chunks named by their hash, with a snapshot of the files written by each run
+    Repository,
+}
+
+/// a source folder with its own rules, from a `[[sources]]` table
+#[derive(Debug, Default, Clone, Deserialize, PartialEq)]
+pub struct SourceConfig {
+    pub path: String,
+    /// patterns added to the global excludes for this folder
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    /// the targets this folder is backed up to; empty for all targets
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// the deepest level walked below the folder; unlimited if not set
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// the same as symlinks = "follow"
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// overrides the config's symlink mode for this folder
+    #[serde(default)]
+    pub symlinks: Option<SymlinkMode>,
+    /// keys in the table that aren't used, reported by validate
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+impl SourceConfig {
+    /// create a source with the default rules, as used for the flat source_folders list
+    pub fn new(path: &str) -> SourceConfig {
+        SourceConfig {
+            path: path.to_string(),
+            ..SourceConfig::default()
+        }
+    }
+
+    /// return the symlink mode for this folder, defaulting to the config's mode
+    pub fn symlink_mode(&self, default: SymlinkMode) -> SymlinkMode {
+        if self.follow_symlinks {
+            SymlinkMode::Follow
+        } else {
+            self.symlinks.unwrap_or(default)
+        }
+    }
+
+    /// return true if the folder is backed up to the target
+    pub fn has_target(&self, target: &str) -> bool {
+        self.targets.is_empty() || self.targets.iter().any(|t| t == target)
+    }
+}
+
 #[derive(Debug, Default, Clone, Deserialize)]
 pub struct Config {
+    #[serde(default = "default_name")]
     pub name: String,
+    #[serde(default = "default_version")]
     pub version: String,
+    #[serde(default = "default_home")]
     pub home: String,
+    #[serde(default = "default_logging_config")]
     pub logging_config: String,
+    #[serde(default)]
     pub source_folders: Vec<String>,
+    #[serde(default)]
+    pub sources: Vec<SourceConfig>,
     pub targets: Vec<String>,
+    #[serde(default)]
     pub files: Vec<String>,
+    #[serde(default)]
     pub excludes: Vec<String>,
+    #[serde(default)]
+    pub includes: Vec<String>,
+    #[serde(default)]
     pub journaled: Vec<String>,
+    #[serde(default = "default_dbfile")]
     pub dbfile: String,
+    #[serde(default = "default_db_snapshots")]
+    pub db_snapshots: usize,
+    #[serde(default)]
     pub compress: bool,
+    #[serde(default)]
     pub encrypt: bool,
+    #[serde(default)]
     pub dryrun: bool,
+    #[serde(default)]
     pub verbose: bool,
+    #[serde(default)]
+    pub compare: CompareMode,
+    #[serde(default)]
+    pub key_file: Option<String>,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default)]
+    pub watch: bool,
+    #[serde(default)]
+    pub symlinks: SymlinkMode,
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    #[serde(default)]
+    pub fan_out: bool,
+    #[serde(default)]
+    pub format: TargetFormat,
+    #[serde(default)]
+    pub repository_snapshots: usize,
+    #[serde(default)]
+    pub preserve_owner: bool,
+    #[serde(default)]
+    pub preserve_xattrs: bool,
+    #[serde(default = "default_full_walk_interval")]
+    pub full_walk_interval: u64,
+    #[serde(default)]
+    pub report_file: Option<String>,
+    #[serde(default = "default_min_targets")]
+    pub min_targets: usize,
+    /// keys in the file that aren't used, e.g. misspelled, reported by validate
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+/// the config file used when neither --config nor REPLICA_CONFIG is set and there is no XDG config; relative to $HOME
+pub const DEFAULT_CONFIG: &str = ".replica/config/config.toml";
+
+/// the folder `replica init` writes the starter config, logging config, database and logs to; relative to $HOME
+pub const DEFAULT_FOLDER: &str = ".replica";
+
+/// the commented starter config and logging config written by `replica init`
+const STARTER_CONFIG: &str = include_str!("../templates/config.toml");
+const STARTER_LOGGING: &str = include_str!("../templates/logging.yaml");
+
+fn default_name() -> String {
+    "replica".to_string()
+}
+
+fn default_version() -> String {
+    VERSION.to_string()
+}
+
+/// the default home is $HOME, the folder replica starts in
+fn default_home() -> String {
+    "./".to_string()
+}
+
+fn default_logging_config() -> String {
+    format!("{}/config/logging.yaml", DEFAULT_FOLDER)
+}
+
+fn default_dbfile() -> String {
+    format!("{}/data/files.json", DEFAULT_FOLDER)
+}
+
+/// the default number of time-stamped snapshots of the previous dbfile kept on save
+fn default_db_snapshots() -> usize {
+    crate::kv_store::DEFAULT_SNAPSHOTS
+}
+
+/// the default number of files copied at once
+fn default_concurrency() -> usize {
+    1
+}
+
+/// the default number of targets that must be backed up without failures for a run to succeed
+fn default_min_targets() -> usize {
+    1
+}
+
+/// the default number of seconds between daemon passes
+fn default_interval() -> u64 {
+    300
+}
+
+/// the default number of seconds between full walks when the daemon is watching for changes
+fn default_full_walk_interval() -> u64 {
+    3600
+}
+
+/// return $REPLICA_CONFIG if set, else the first config file that exists, else the default
+fn config_file(env_config: Option<String>, xdg_config_home: Option<String>) -> String {
+    if let Some(filename) = env_config.filter(|filename| !filename.is_empty()) {
+        return filename;
+    }
+
+    // relative to $HOME, where replica starts
+    let xdg_home = xdg_config_home
+        .filter(|folder| !folder.is_empty())
+        .unwrap_or_else(|| ".config".to_string());
+    let xdg = format!("{}/replica/config.toml", xdg_home);
+
+    if Path::new(&xdg).is_file() {
+        xdg
+    } else {
+        DEFAULT_CONFIG.to_string()
+    }
+}
+
+/// describe a toml parse error by line, column and the key on that line
+fn parse_error(text: &str, e: &toml::de::Error) -> String {
+    let Some(span) = e.span() else {
+        return e.message().to_string();
+    };
+
+    let before = &text[..span.start];
+    let line = before.matches('\n').count() + 1;
+    let column = span.start - before.rfind('\n').map_or(0, |pos| pos + 1) + 1;
+    let key = text
+        .lines()
+        .nth(line - 1)
+        .and_then(|line| line.split_once('='))
+        .map(|(key, _)| key.trim())
+        .unwrap_or_default();
+
+    if key.is_empty() {
+        format!("line {}, column {}: {}", line, column, e.message())
+    } else {
+        format!(
+            "line {}, column {}, key {}: {}",
+            line,
+            column,
+            key,
+            e.message()
+        )
+    }
+}
+
+/// return the canonical path of the deepest folder that exists, joined with the rest of the path
+fn resolve(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut rest: Vec<&std::ffi::OsStr> = vec![];
+    while !existing.as_os_str().is_empty() && !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+
+    let base = if existing.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        existing
+    };
+    match base.canonicalize() {
+        Ok(resolved) => rest
+            .iter()
+            .rev()
+            .fold(resolved, |path, name| path.join(name)),
+        Err(_) => path.to_path_buf(),
+    }
 }
 
 impl Config {
-    // read and parse the config file
+    /// return the config file to read: the --config file if given, then $REPLICA_CONFIG, then the first of
+    /// $XDG_CONFIG_HOME/replica/config.toml (~/.config when not set) and the default that exists
+    pub fn discover(explicit: Option<&str>) -> String {
+        if let Some(filename) = explicit {
+            return filename.to_string();
+        }
+
+        config_file(
+            std::env::var("REPLICA_CONFIG").ok(),
+            std::env::var("XDG_CONFIG_HOME").ok(),
+        )
+    }
+
+    /// write the starter config and logging config to the folder's config folder and create its data and logs
+    /// folders; existing files are left alone unless force is set.  return the files written
+    pub fn init(folder: &Path, force: bool) -> Result<Vec<PathBuf>> {
+        for name in ["config", "data", "logs"] {
+            std::fs::create_dir_all(folder.join(name))?;
+        }
+
+        let mut written = vec![];
+        for (name, text) in [
+            ("config/config.toml", STARTER_CONFIG),
+            ("config/logging.yaml", STARTER_LOGGING),
+        ] {
+            let path = folder.join(name);
+            if path.exists() && !force {
+                warn!("{} exists, not replaced", path.display());
+                continue;
+            }
+
+            std::fs::write(&path, text)?;
+            written.push(path);
+        }
+
+        Ok(written)
+    }
+
+    // read and parse the config file; a parse error reports the file, line and key
     pub fn read_config(filename: &str) -> Result<Config> {
         let file = File::open(filename)?;
         let mut reader = BufReader::new(file);
 
         let mut text = String::new();
         reader.read_to_string(&mut text)?;
-        let config: Config = toml::from_str(&text).unwrap();
+        let config: Config = match toml::from_str(&text) {
+            Ok(config) => config,
+            Err(e) => {
+                let msg = format!("{}: {}", filename, parse_error(&text, &e));
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+        };
 
         info!("config: {}, version: {}", config.name, config.version);
 
         Ok(config)
     }
 
+    /// check the parsed config for settings that would fail or surprise at run time; paths are relative to home.
+    /// return the problems found, empty if none
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems: Vec<String> = vec![];
+        let home = Path::new(&self.home);
+
+        if !home.is_dir() {
+            problems.push(format!("home folder does not exist: {}", self.home));
+        }
+        if self.targets.is_empty() {
+            problems.push("no targets to back up to".to_string());
+        }
+        if self.source_folders.is_empty() && self.sources.is_empty() && self.files.is_empty() {
+            problems.push("no source_folders, sources or files to back up".to_string());
+        }
+
+        for key in self.unknown.keys() {
+            problems.push(format!("unknown key: {}", key));
+        }
+        for source in self.sources.iter() {
+            for key in source.unknown.keys() {
+                problems.push(format!("unknown key in sources {}: {}", source.path, key));
+            }
+        }
+
+        if File::open(&self.logging_config).is_err() {
+            problems.push(format!(
+                "logging_config can't be read: {}",
+                self.logging_config
+            ));
+        }
+
+        let sources = self.source_list();
+        for source in sources.iter() {
+            if !home.join(&source.path).is_dir() {
+                problems.push(format!("source folder does not exist: {}", source.path));
+            }
+        }
+
+        for file in self.files.iter() {
+            if !home.join(file).is_file() {
+                problems.push(format!("file does not exist: {}", file));
+            }
+        }
+
+        // a target inside a source would back itself up on every pass
+        for target in self.targets.iter() {
+            if crate::target::is_remote(target) {
+                if let Err(e) = crate::target::open(target) {
+                    problems.push(format!("{:#}", e));
+                }
+                continue;
+            }
+
+            let target_path = resolve(&home.join(target));
+            for source in sources.iter() {
+                if target_path.starts_with(resolve(&home.join(&source.path))) {
+                    problems.push(format!(
+                        "target {} is inside source folder {}",
+                        target, source.path
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
     /// create and return a copy
     pub fn copy(&self) -> Config {
         Config {
@@ -50,18 +432,60 @@ impl Config {
             home: self.home.to_string(),
             logging_config: self.logging_config.to_string(),
             source_folders: self.source_folders.clone(),
+            sources: self.sources.clone(),
             targets: self.targets.clone(),
             files: self.files.clone(),
             excludes: self.excludes.clone(),
+            includes: self.includes.clone(),
             journaled: self.journaled.clone(),
             dbfile: self.dbfile.clone(),
+            db_snapshots: self.db_snapshots,
             compress: self.compress,
             encrypt: self.encrypt,
             dryrun: false,
             verbose: false,
+            compare: self.compare,
+            key_file: self.key_file.clone(),
+            interval: self.interval,
+            watch: self.watch,
+            symlinks: self.symlinks,
+            concurrency: self.concurrency,
+            fan_out: self.fan_out,
+            format: self.format,
+            repository_snapshots: self.repository_snapshots,
+            preserve_owner: self.preserve_owner,
+            preserve_xattrs: self.preserve_xattrs,
+            full_walk_interval: self.full_walk_interval,
+            report_file: self.report_file.clone(),
+            min_targets: self.min_targets,
+            unknown: self.unknown.clone(),
         }
     }
 
+    /// return the flat source folders followed by the `[[sources]]` tables
+    pub fn source_list(&self) -> Vec<SourceConfig> {
+        self.source_folders
+            .iter()
+            .map(|folder| SourceConfig::new(folder))
+            .chain(self.sources.iter().cloned())
+            .collect()
+    }
+
+    /// return true if the file at this home relative path is backed up to the target; configured files and files
+    /// outside any source go to every target, files in a source go to its targets
+    pub fn routes_to(&self, path: &Path, target: &str) -> bool {
+        let sources: Vec<SourceConfig> = self
+            .source_list()
+            .into_iter()
+            .filter(|source| {
+                let folder: PathBuf = [&self.home, &source.path].iter().collect();
+                path.starts_with(folder)
+            })
+            .collect();
+
+        sources.is_empty() || sources.iter().any(|source| source.has_target(target))
+    }
+
     /// start the logger
     pub fn start_logger(&self) -> Result<()> {
         log4rs::init_file(&self.logging_config, Default::default())?;
@@ -80,9 +504,34 @@ impl Config {
             .expect("should write to the pid file")
     }
 
+    /// return the pid from the pid file if it belongs to another process that is still running
+    pub fn running_pid() -> Option<u32> {
+        let text = std::fs::read_to_string(crate::PID_FILE).ok()?;
+        let pid: u32 = text.trim().parse().ok()?;
+        if pid == std::process::id() {
+            return None;
+        }
+
+        if Config::is_running(pid) {
+            Some(pid)
+        } else {
+            warn!("stale pid file for process {}", pid);
+            None
+        }
+    }
+
+    /// return true if a process with the pid is running
+    pub fn is_running(pid: u32) -> bool {
+        subprocess::Exec::cmd("kill")
+            .args(&["-0", pid.to_string().as_str()])
+            .stdout(subprocess::NullFile)
+            .stderr(subprocess::NullFile)
+            .join()
+            .is_ok_and(|status| status.success())
+    }
+
     /// remove the pid file on exit
     pub fn remove_pid_file() {
-        use std::path::Path;
         info!("remove pid dfile: {}", crate::PID_FILE);
         let fp = Path::new(crate::PID_FILE);
         if fp.exists() {
@@ -95,6 +544,7 @@ impl Config {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -106,6 +556,68 @@ mod tests {
         assert!(!config.source_folders.is_empty());
     }
 
+    #[test]
+    fn minimal() {
+        let config: Config = toml::from_str("targets = [ \"/Volumes/a\" ]").unwrap();
+        assert_eq!(config.name, "replica");
+        assert_eq!(config.version, VERSION);
+        assert_eq!(config.home, "./");
+        assert_eq!(config.logging_config, ".replica/config/logging.yaml");
+        assert_eq!(config.dbfile, ".replica/data/files.json");
+        assert!(config.journaled.is_empty());
+        assert!(!config.compress);
+        assert!(!config.verbose);
+        assert!(config
+            .validate()
+            .contains(&"no source_folders, sources or files to back up".to_string()));
+
+        assert!(toml::from_str::<Config>("name = \"no-targets\"").is_err());
+    }
+
+    #[test]
+    fn discover() {
+        assert_eq!(Config::discover(Some("my.toml")), "my.toml");
+        assert_eq!(config_file(Some("env.toml".to_string()), None), "env.toml");
+        assert_eq!(
+            config_file(Some(String::new()), Some("tests/no-such-xdg".to_string())),
+            DEFAULT_CONFIG
+        );
+
+        let folder = "tests/tback-tmp/xdg";
+        std::fs::create_dir_all(format!("{}/replica", folder)).unwrap();
+        std::fs::write(format!("{}/replica/config.toml", folder), "").unwrap();
+        assert_eq!(
+            config_file(None, Some(folder.to_string())),
+            "tests/tback-tmp/xdg/replica/config.toml"
+        );
+    }
+
+    #[test]
+    fn init() {
+        let folder = Path::new("tests/tback-tmp/init");
+        let _ = std::fs::remove_dir_all(folder);
+
+        let written = Config::init(folder, false).unwrap();
+        assert_eq!(written.len(), 2);
+        assert!(folder.join("data").is_dir());
+        assert!(folder.join("logs").is_dir());
+
+        let config = Config::read_config("tests/tback-tmp/init/config/config.toml").unwrap();
+        assert!(config.unknown.is_empty());
+        assert_eq!(config.targets, vec!["/Volumes/backup/replica"]);
+        assert_eq!(config.logging_config, default_logging_config());
+        assert_eq!(config.interval, 300);
+
+        // an edited config is kept
+        std::fs::write(folder.join("config/config.toml"), "targets = []").unwrap();
+        assert!(Config::init(folder, false).unwrap().is_empty());
+        assert_eq!(
+            std::fs::read_to_string(folder.join("config/config.toml")).unwrap(),
+            "targets = []"
+        );
+        assert_eq!(Config::init(folder, true).unwrap().len(), 2);
+    }
+
     #[test]
     fn copy() {
         let refc = Config::read_config(".test-replica/config/config.toml").unwrap();
@@ -114,6 +626,169 @@ mod tests {
 
         assert_eq!(refc.name, config.name);
         assert_eq!(refc.version, config.version);
+        assert_eq!(refc.compare, config.compare);
+    }
+
+    #[test]
+    fn compare_mode() {
+        let config = Config::read_config(".test-replica/config/config.toml").unwrap();
+        assert_eq!(config.compare, CompareMode::HashOnChange);
+
+        let text = std::fs::read_to_string(".test-replica/config/config.toml").unwrap();
+        let text = format!("{}\ncompare = \"always-hash\"\n", text);
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(config.compare, CompareMode::AlwaysHash);
+    }
+
+    #[test]
+    fn target_format() {
+        let config = Config::read_config(".test-replica/config/config.toml").unwrap();
+        assert_eq!(config.format, TargetFormat::Mirror);
+
+        let text = std::fs::read_to_string(".test-replica/config/config.toml").unwrap();
+        let text = format!("{}\nformat = \"repository\"\n", text);
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(config.format, TargetFormat::Repository);
+        assert_eq!(config.copy().format, TargetFormat::Repository);
+        assert_eq!(config.repository_snapshots, 0);
+    }
+
+    #[test]
+    fn interval() {
+        let config = Config::read_config(".test-replica/config/config.toml").unwrap();
+        assert_eq!(config.interval, 300);
+        assert_eq!(config.copy().interval, 300);
+        assert!(!config.watch);
+        assert_eq!(config.full_walk_interval, 3600);
+        assert_eq!(config.db_snapshots, 5);
+        assert_eq!(config.concurrency, 1);
+        assert!(!config.fan_out);
+        assert!(config.report_file.is_none());
+        assert_eq!(config.min_targets, 1);
+        assert_eq!(config.copy().db_snapshots, 5);
+    }
+
+    #[test]
+    fn read_config_errors() {
+        assert!(Config::read_config("tests/no-such-config.toml").is_err());
+
+        let filename = "tests/tback-tmp/bad-config.toml";
+        let _ = std::fs::create_dir_all("tests/tback-tmp");
+        let text = std::fs::read_to_string(".test-replica/config/run-config.toml").unwrap();
+        let text = text.replace("compress = false", "compress = \"yes\"");
+        std::fs::write(filename, &text).unwrap();
+
+        let msg = Config::read_config(filename).unwrap_err().to_string();
+        let line = text
+            .lines()
+            .position(|l| l.starts_with("compress"))
+            .unwrap()
+            + 1;
+        assert!(msg.starts_with(filename));
+        assert!(msg.contains(&format!("line {}, column 12, key compress", line)));
+        assert!(msg.contains("expected a boolean"));
+    }
+
+    #[test]
+    fn validate() {
+        let mut config = Config::read_config(".test-replica/config/run-config.toml").unwrap();
+        config.files = vec!["tests/file1.txt".to_string()];
+        config.targets = vec!["tests/tback".to_string()];
+        assert_eq!(config.validate(), Vec::<String>::new());
+
+        config.files.push("tests/no-such-file.txt".to_string());
+        config
+            .source_folders
+            .push("tests/no-such-folder".to_string());
+        config.logging_config = "no-such-logging.yaml".to_string();
+        config.targets.push("tests/.config/backup".to_string());
+        config.targets.push("sftp://nas/replica".to_string());
+        config.targets.push("ftp://nas/replica".to_string());
+        let problems = config.validate();
+        assert_eq!(
+            problems,
+            vec![
+                "logging_config can't be read: no-such-logging.yaml",
+                "source folder does not exist: tests/no-such-folder",
+                "file does not exist: tests/no-such-file.txt",
+                "target tests/.config/backup is inside source folder tests/.config",
+                "unknown target scheme ftp: ftp://nas/replica",
+            ]
+        );
+
+        config.home = "tests/no-such-home".to_string();
+        assert_eq!(
+            config.validate()[0],
+            "home folder does not exist: tests/no-such-home"
+        );
+    }
+
+    #[test]
+    fn validate_unknown_keys() {
+        let text = std::fs::read_to_string(".test-replica/config/run-config.toml").unwrap();
+        let text = format!(
+            "intreval = 60\n{}\n[[sources]]\npath = \"tests\"\nmax_dept = 2\n",
+            text
+        );
+        let config: Config = toml::from_str(&text).unwrap();
+        let problems = config.validate();
+        assert!(problems.contains(&"unknown key: intreval".to_string()));
+        assert!(problems.contains(&"unknown key in sources tests: max_dept".to_string()));
+        assert_eq!(config.interval, 300);
+    }
+
+    #[test]
+    fn sources() {
+        let config = Config::read_config(".test-replica/config/config.toml").unwrap();
+        assert!(config.sources.is_empty());
+        assert_eq!(config.source_list().len(), config.source_folders.len());
+
+        let text = r#"
+            name = "sources"
+            version = "0.1.0"
+            home = "/home/dpw"
+            logging_config = "console.yaml"
+            source_folders = [ ".ssh" ]
+            targets = [ "/Volumes/a", "/Volumes/b" ]
+            files = []
+            excludes = []
+            journaled = []
+            dbfile = "files.json"
+            compress = false
+            encrypt = false
+            dryrun = false
+            verbose = false
+
+            [[sources]]
+            path = "photos"
+            excludes = [ "*.tmp" ]
+            targets = [ "/Volumes/b" ]
+            max_depth = 2
+            follow_symlinks = true
+        "#;
+        let config: Config = toml::from_str(text).unwrap();
+        let sources = config.copy().source_list();
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0], SourceConfig::new(".ssh"));
+        assert_eq!(sources[1].path, "photos");
+        assert_eq!(sources[1].excludes, vec!["*.tmp"]);
+        assert_eq!(sources[1].max_depth, Some(2));
+        assert!(sources[1].follow_symlinks);
+        assert_eq!(config.symlinks, SymlinkMode::Skip);
+        assert_eq!(
+            sources[0].symlink_mode(SymlinkMode::Store),
+            SymlinkMode::Store
+        );
+        assert_eq!(
+            sources[1].symlink_mode(SymlinkMode::Store),
+            SymlinkMode::Follow
+        );
+
+        let photo = Path::new("/home/dpw/photos/2026/cat.jpg");
+        assert!(!config.routes_to(photo, "/Volumes/a"));
+        assert!(config.routes_to(photo, "/Volumes/b"));
+        assert!(config.routes_to(Path::new("/home/dpw/.ssh/config"), "/Volumes/a"));
+        assert!(config.routes_to(Path::new("/home/dpw/.zshrc"), "/Volumes/a"));
     }
 
     #[test]
@@ -125,9 +800,12 @@ mod tests {
 
         let mut buf = String::new();
         let resp = file.read_to_string(&mut buf);
-        assert_eq!(resp.is_ok(), true);
+        assert!(resp.is_ok());
         assert_eq!(buf, pid);
 
+        // our own pid is never reported as another running process
+        assert!(Config::running_pid().is_none());
+
         Config::remove_pid_file();
         let result = File::open(crate::PID_FILE);
         assert_eq!(result.is_err(), true);
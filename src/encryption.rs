@@ -0,0 +1,495 @@
+/// Encryption - AES-256-GCM authenticated encryption of the copies written to a target
+///
+/// # Encryption
+///
+/// encrypted copies carry a .enc extension on the target.  each file is written as a header (magic, format version,
+/// kdf salt, key id and a random nonce), the cipher text, then the 16 byte authentication tag.  the header is
+/// authenticated along with the content so a wrong key, a truncated file or any tampering is reported on restore.
+///
+/// the key is read from the config's key_file or derived from the REPLICA_PASSPHRASE environment variable.  a key file
+/// holding 64 hex characters is used as the raw key; any other content is treated as a passphrase, derived with a
+/// random salt that is written to each header, so a file is decrypted with the passphrase alone.
+///
+use anyhow::{anyhow, Result};
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sha;
+use openssl::sign::Signer;
+use openssl::symm::{Cipher, Crypter, Mode};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// the extension appended to an encrypted copy
+pub const EXTENSION: &str = ".enc";
+
+/// the environment variable read for a passphrase when no key file is configured
+pub const PASSPHRASE_ENV: &str = "REPLICA_PASSPHRASE";
+
+const MAGIC: &[u8; 4] = b"RPLC";
+const FORMAT_VERSION: u8 = 1;
+pub const SALT_LEN: usize = 16;
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + KEY_ID_LEN + NONCE_LEN;
+const BUFFER_SIZE: usize = 64 * 1024;
+
+const KDF_ITERATIONS: usize = 200_000;
+
+/// a 256 bit key with a short id that is written to each file's header
+#[derive(Clone)]
+pub struct Key {
+    bytes: [u8; 32],
+    id: [u8; KEY_ID_LEN],
+    /// the salt the key was derived with; zeros for a raw key
+    salt: [u8; SALT_LEN],
+    /// kept to derive the key for the salt in another file's header; None for a raw key
+    passphrase: Option<Arc<Passphrase>>,
+}
+
+/// a passphrase with the keys derived from it, by salt
+struct Passphrase {
+    text: String,
+    derived: Mutex<HashMap<[u8; SALT_LEN], [u8; 32]>>,
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key {{ id: {} }}", hex::encode(self.id))
+    }
+}
+
+impl Key {
+    /// create the key from raw bytes
+    pub fn from_bytes(bytes: [u8; 32]) -> Key {
+        let mut hasher = sha::Sha256::new();
+        hasher.update(b"replica key id");
+        hasher.update(&bytes);
+        let hash = hasher.finish();
+
+        let mut id = [0u8; KEY_ID_LEN];
+        id.copy_from_slice(&hash[..KEY_ID_LEN]);
+
+        Key {
+            bytes,
+            id,
+            salt: [0u8; SALT_LEN],
+            passphrase: None,
+        }
+    }
+
+    /// derive the key from a passphrase and a new random salt with pbkdf2-hmac-sha256
+    pub fn from_passphrase(passphrase: &str) -> Result<Key> {
+        if passphrase.is_empty() {
+            return Err(anyhow!("the encryption passphrase is empty"));
+        }
+
+        let passphrase = Arc::new(Passphrase {
+            text: passphrase.to_string(),
+            derived: Mutex::new(HashMap::new()),
+        });
+        let salt = new_salt()?;
+        let mut key = Key::from_bytes(derive(&passphrase.text, &salt)?);
+        key.salt = salt;
+        key.passphrase = Some(passphrase);
+
+        Ok(key)
+    }
+
+    /// return the key for the salt, deriving it from the passphrase once per salt; a raw key is the same for any
+    /// salt
+    pub fn with_salt(&self, salt: &[u8; SALT_LEN]) -> Result<Key> {
+        let Some(passphrase) = &self.passphrase else {
+            return Ok(self.clone());
+        };
+        if *salt == self.salt {
+            return Ok(self.clone());
+        }
+
+        let mut derived = passphrase.derived.lock().unwrap();
+        let bytes = match derived.get(salt) {
+            Some(bytes) => *bytes,
+            None => {
+                let bytes = derive(&passphrase.text, salt)?;
+                derived.insert(*salt, bytes);
+                bytes
+            }
+        };
+
+        let mut key = Key::from_bytes(bytes);
+        key.salt = *salt;
+        key.passphrase = Some(Arc::clone(passphrase));
+
+        Ok(key)
+    }
+
+    /// read the key file, or the passphrase environment variable if there is no key file
+    pub fn load(key_file: Option<&str>) -> Result<Key> {
+        let text = match key_file {
+            Some(filename) => fs::read_to_string(filename)
+                .map_err(|e| anyhow!("could not read the key file {}: {}", filename, e))?,
+            None => std::env::var(PASSPHRASE_ENV).map_err(|_| {
+                anyhow!(
+                    "encryption needs a key_file in the config or the {} environment variable",
+                    PASSPHRASE_ENV
+                )
+            })?,
+        };
+
+        let text = text.trim();
+        if text.len() == 64 {
+            if let Ok(raw) = hex::decode(text) {
+                let mut bytes = [0u8; 32];
+                bytes.copy_from_slice(&raw);
+                return Ok(Key::from_bytes(bytes));
+            }
+        }
+
+        Key::from_passphrase(text)
+    }
+
+    /// return the hmac-sha256 of the data in hex, under a secret derived from the key; names content without
+    /// revealing its plain hash
+    pub fn keyed_hash(&self, data: &[u8]) -> Result<String> {
+        let secret = hmac(&self.bytes, b"replica keyed hash")?;
+
+        Ok(hex::encode(hmac(&secret, data)?))
+    }
+
+    /// return the key id in hex
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+}
+
+/// derive a key from the passphrase and salt with pbkdf2-hmac-sha256
+fn derive(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        KDF_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut bytes,
+    )?;
+
+    Ok(bytes)
+}
+
+/// return a new random salt for a key derived from a passphrase
+pub fn new_salt() -> Result<[u8; SALT_LEN]> {
+    let mut salt = [0u8; SALT_LEN];
+    rand_bytes(&mut salt)?;
+
+    Ok(salt)
+}
+
+/// return the hmac-sha256 of the data under the key
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+/// return the path with the encrypted extension appended
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}{}", path.display(), EXTENSION))
+}
+
+/// return true if the copy is an encrypted copy of the original
+pub fn is_encrypted_copy(original: &Path, copy: &Path) -> bool {
+    match crate::file_model::copy_suffix(original, copy) {
+        Some(suffix) => suffix.ends_with(EXTENSION),
+        None => false,
+    }
+}
+
+/// return true if the content starts with an encryption header
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+/// encrypts everything written to it; call finish to write the authentication tag
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    crypter: Crypter,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// write the header with a new random nonce to the inner writer
+    pub fn new(mut inner: W, key: &Key) -> Result<EncryptWriter<W>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(FORMAT_VERSION);
+        header.extend_from_slice(&key.salt);
+        header.extend_from_slice(&key.id);
+        header.extend_from_slice(&nonce);
+
+        let mut crypter = Crypter::new(
+            Cipher::aes_256_gcm(),
+            Mode::Encrypt,
+            &key.bytes,
+            Some(&nonce),
+        )?;
+        crypter.aad_update(&header)?;
+        inner.write_all(&header)?;
+
+        Ok(EncryptWriter {
+            inner,
+            crypter,
+            buf: vec![],
+        })
+    }
+
+    /// finalize the cipher, write the tag and return the inner writer
+    pub fn finish(mut self) -> Result<W> {
+        let mut out = [0u8; 32];
+        let count = self.crypter.finalize(&mut out)?;
+        self.inner.write_all(&out[..count])?;
+
+        let mut tag = [0u8; TAG_LEN];
+        self.crypter.get_tag(&mut tag)?;
+        self.inner.write_all(&tag)?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = min(buf.len(), BUFFER_SIZE);
+        self.buf.resize(len + TAG_LEN, 0);
+
+        let count = self.crypter.update(&buf[..len], &mut self.buf)?;
+        self.inner.write_all(&self.buf[..count])?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// decrypts the inner reader; the tag is verified when the end of the cipher text is reached and a mismatch is
+/// returned as an InvalidData error.  plain text is returned before it is verified, so callers must discard the
+/// output on error.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    crypter: Crypter,
+    remaining: u64,
+    buf: Vec<u8>,
+    verified: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    /// read and check the header; len is the total size of the encrypted file
+    pub fn new(mut inner: R, len: u64, key: &Key) -> Result<DecryptReader<R>> {
+        if len < (HEADER_LEN + TAG_LEN) as u64 {
+            return Err(anyhow!("not an encrypted file: too short"));
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        inner.read_exact(&mut header)?;
+
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(anyhow!("not an encrypted file: bad header"));
+        }
+
+        let version = header[MAGIC.len()];
+        if version != FORMAT_VERSION {
+            return Err(anyhow!("unknown encryption format version: {}", version));
+        }
+
+        let salt_start = MAGIC.len() + 1;
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&header[salt_start..salt_start + SALT_LEN]);
+        let key = key.with_salt(&salt)?;
+
+        let id_start = salt_start + SALT_LEN;
+
+        let key_id = &header[id_start..id_start + KEY_ID_LEN];
+        if key_id != key.id {
+            return Err(anyhow!(
+                "wrong key: the file was encrypted with key {}, the configured key is {}",
+                hex::encode(key_id),
+                key.id()
+            ));
+        }
+
+        let nonce = &header[id_start + KEY_ID_LEN..];
+        let mut crypter = Crypter::new(
+            Cipher::aes_256_gcm(),
+            Mode::Decrypt,
+            &key.bytes,
+            Some(nonce),
+        )?;
+        crypter.aad_update(&header)?;
+
+        Ok(DecryptReader {
+            inner,
+            crypter,
+            remaining: len - (HEADER_LEN + TAG_LEN) as u64,
+            buf: vec![0u8; BUFFER_SIZE],
+            verified: false,
+        })
+    }
+
+    /// read the tag and verify the content
+    fn verify(&mut self) -> io::Result<()> {
+        let mut tag = [0u8; TAG_LEN];
+        self.inner.read_exact(&mut tag)?;
+        self.crypter.set_tag(&tag)?;
+
+        let mut out = [0u8; 32];
+        self.crypter.finalize(&mut out).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decryption failed: the file is corrupt or was modified",
+            )
+        })?;
+
+        self.verified = true;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            if !self.verified {
+                self.verify()?;
+            }
+            return Ok(0);
+        }
+
+        let want = min(min(buf.len(), BUFFER_SIZE) as u64, self.remaining) as usize;
+        let count = self.inner.read(&mut self.buf[..want])?;
+        if count == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the encrypted file is truncated",
+            ));
+        }
+        self.remaining -= count as u64;
+
+        // gcm is a stream mode so the plain text is the same length as the cipher text
+        let mut out = vec![0u8; count + TAG_LEN];
+        let plain = self.crypter.update(&self.buf[..count], &mut out)?;
+        buf[..plain].copy_from_slice(&out[..plain]);
+
+        Ok(plain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(content: &[u8], key: &Key) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(vec![], key).unwrap();
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(encrypted: &[u8], key: &Key) -> Result<Vec<u8>> {
+        let mut reader = DecryptReader::new(encrypted, encrypted.len() as u64, key)?;
+        let mut plain = vec![];
+        reader.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = Key::from_bytes([7u8; 32]);
+        let content = fs::read("tests/big-file.pdf").unwrap();
+
+        let encrypted = encrypt(&content, &key);
+        assert_eq!(encrypted.len(), content.len() + HEADER_LEN + TAG_LEN);
+        assert_ne!(&encrypted[HEADER_LEN..HEADER_LEN + 64], &content[..64]);
+
+        let plain = decrypt(&encrypted, &key).unwrap();
+        assert_eq!(plain, content);
+
+        // each file gets its own nonce
+        assert_ne!(encrypt(&content, &key), encrypted);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let key = Key::from_bytes([7u8; 32]);
+        let encrypted = encrypt(b"my secret", &key);
+
+        let other = Key::from_bytes([8u8; 32]);
+        let err = decrypt(&encrypted, &other).unwrap_err();
+        assert!(err.to_string().starts_with("wrong key"));
+    }
+
+    #[test]
+    fn tampered() {
+        let key = Key::from_bytes([7u8; 32]);
+        let mut encrypted = encrypt(b"my secret", &key);
+        encrypted[HEADER_LEN + 2] ^= 1;
+        assert!(decrypt(&encrypted, &key).is_err());
+
+        let encrypted = encrypt(b"my secret", &key);
+        assert!(decrypt(&encrypted[..encrypted.len() - 1], &key).is_err());
+        assert!(decrypt(b"not encrypted", &key).is_err());
+    }
+
+    #[test]
+    fn load() {
+        let key = Key::load(Some(".test-replica/config/test.key")).unwrap();
+        assert_eq!(key.bytes[0], 0x4a);
+
+        let err = Key::load(Some("tests/no-such.key")).unwrap_err();
+        assert!(err.to_string().contains("tests/no-such.key"));
+
+        // each load of a passphrase gets its own salt, and either key reads the other's files
+        let key = Key::load(Some("tests/file2.txt")).unwrap();
+        let passphrase = Key::from_passphrase("my test profile...").unwrap();
+        assert_ne!(key.id(), passphrase.id());
+        assert_eq!(
+            key.with_salt(&passphrase.salt).unwrap().id(),
+            passphrase.id()
+        );
+        let encrypted = encrypt(b"my secret", &key);
+        assert_eq!(decrypt(&encrypted, &passphrase).unwrap(), b"my secret");
+        let other = Key::from_passphrase("another passphrase").unwrap();
+        assert!(decrypt(&encrypted, &other).is_err());
+        assert!(Key::from_passphrase("").is_err());
+    }
+
+    #[test]
+    fn encrypted_copy() {
+        let original = Path::new("./tests/file1.txt");
+        assert!(is_encrypted_copy(
+            original,
+            Path::new("tback/tests/file1.txt.gz.enc")
+        ));
+        assert!(is_encrypted_copy(
+            original,
+            Path::new("tback/tests/file1.txt@2026-10-18T10:00:00Z.enc")
+        ));
+        assert!(!is_encrypted_copy(
+            original,
+            Path::new("tback/tests/file1.txt")
+        ));
+        assert!(!is_encrypted_copy(
+            Path::new("secret.enc"),
+            Path::new("tback/secret.enc")
+        ));
+    }
+}
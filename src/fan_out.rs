@@ -0,0 +1,614 @@
+/// Fan Out - read each changed source file once and write it to all targets at once
+///
+/// # Fan Out
+///
+/// each target has its own writer thread.  a file that one or more targets need is read once, in chunks that are
+/// handed to the writers of those targets and hashed on the way, so the source disk is read once per file.  a slow
+/// target holds up the others while its buffer of chunks is full; if it stays full past the lag limit, the target
+/// drops out of the file and copies it on its own after the others are done.  the database is only updated from the
+/// calling thread.
+///
+use crate::backup_process::BackupProcess;
+use crate::file_model::FileModel;
+use crate::kv_store::KeyValueStore;
+use crate::run_report::TargetReport;
+use anyhow::Result;
+use hashbrown::{HashMap, HashSet};
+use log::{debug, error, info, warn};
+use openssl::sha;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the size of each chunk read from the source
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// the number of chunks buffered for each target, i.e. 16MB
+const CHUNK_BUFFER: usize = 64;
+
+/// how long a target's full buffer may hold up the source read before the target drops out of the file
+const LAG_LIMIT: Duration = Duration::from_secs(10);
+
+enum Chunk {
+    Data(Arc<Vec<u8>>),
+    /// the whole file was read; carries its hash
+    End(String),
+}
+
+/// read the chunks of one file as they arrive; fails if the source read stops before the end
+struct ChunkReader {
+    chunks: Receiver<Chunk>,
+    current: Arc<Vec<u8>>,
+    pos: usize,
+    hash: Option<String>,
+}
+
+impl ChunkReader {
+    fn new(chunks: Receiver<Chunk>) -> ChunkReader {
+        ChunkReader {
+            chunks,
+            current: Arc::new(vec![]),
+            pos: 0,
+            hash: None,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.current.len() {
+            if self.hash.is_some() {
+                return Ok(0);
+            }
+
+            match self.chunks.recv() {
+                Ok(Chunk::Data(data)) => {
+                    self.current = data;
+                    self.pos = 0;
+                }
+                Ok(Chunk::End(hash)) => self.hash = Some(hash),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the source read stopped before the end",
+                    ))
+                }
+            }
+        }
+
+        let count = buf.len().min(self.current.len() - self.pos);
+        buf[..count].copy_from_slice(&self.current[self.pos..self.pos + count]);
+        self.pos += count;
+
+        Ok(count)
+    }
+}
+
+/// a copy for a target's writer to make
+struct Job {
+    src: FileModel,
+    dest: FileModel,
+    chunks: Receiver<Chunk>,
+}
+
+pub struct FanOut {
+    /// a backup process per target with the files routed to that target
+    pub processes: Vec<BackupProcess>,
+    pub dryrun: bool,
+    pub stop: Arc<AtomicBool>,
+    /// the number of chunks buffered for each target
+    pub buffer: usize,
+    /// how long a full buffer may hold up the others before its target drops out of the file
+    pub lag_limit: Duration,
+}
+
+impl FanOut {
+    pub fn new(mut processes: Vec<BackupProcess>, dryrun: bool) -> FanOut {
+        // a source checked against several targets is hashed once
+        let hashes = Arc::new(Mutex::new(std::collections::HashMap::new()));
+        for process in processes.iter_mut() {
+            process.source_hashes = Some(Arc::clone(&hashes));
+        }
+
+        FanOut {
+            processes,
+            dryrun,
+            stop: Arc::new(AtomicBool::new(false)),
+            buffer: CHUNK_BUFFER,
+            lag_limit: LAG_LIMIT,
+        }
+    }
+
+    /// back up the files of all the processes; return the updated database and a report for each target
+    pub fn process(&self, mut db: KeyValueStore) -> (KeyValueStore, Vec<TargetReport>) {
+        info!("fan out to {} targets", self.processes.len());
+        let start_time = Instant::now();
+
+        let files = self.target_files();
+        let mut reports: Vec<TargetReport> = self
+            .processes
+            .iter()
+            .map(|process| TargetReport::new(process.target.to_str().unwrap(), process.files.len()))
+            .collect();
+        let (results_tx, results) = mpsc::channel::<(usize, PathBuf, Result<FileModel>)>();
+
+        // the files a slow target dropped out of, copied by the target after the others
+        let mut lagged: Vec<(usize, FileModel)> = vec![];
+        let mut dropped: HashSet<(usize, PathBuf)> = HashSet::new();
+
+        thread::scope(|scope| {
+            let writers: Vec<Sender<Job>> = self
+                .processes
+                .iter()
+                .enumerate()
+                .map(|(idx, process)| {
+                    let (tx, jobs) = mpsc::channel::<Job>();
+                    let results_tx = results_tx.clone();
+                    scope.spawn(move || {
+                        for job in jobs {
+                            let src = job.src.path.clone();
+                            let resp = write_job(process, job);
+                            if results_tx.send((idx, src, resp)).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                    tx
+                })
+                .collect();
+            drop(results_tx);
+
+            for (model, targets) in files {
+                if self.stop.load(Ordering::Relaxed) {
+                    warn!("stop requested, skip the remaining files");
+                    break;
+                }
+
+                for result in results.try_iter() {
+                    self.save_unless_dropped(&mut db, &mut reports, &dropped, result);
+                }
+
+                // reuse the key and save history of an existing record for this path
+                let stored = db.find(model.path.to_str().unwrap()).cloned();
+                let model = db.identify(model);
+
+                let mut needed: Vec<(usize, FileModel)> = vec![];
+                for idx in targets {
+                    let process = &self.processes[idx];
+                    if model.link_target.is_some() {
+                        // nothing to read for a link
+                        match process.backup_file(&model, stored.as_ref()) {
+                            Ok(None) => reports[idx].skipped(),
+                            Ok(Some(_)) if self.dryrun => reports[idx].copied(0),
+                            Ok(Some(saved)) => {
+                                let result = (idx, model.path.clone(), Ok(saved));
+                                self.save(&mut db, &mut reports, result);
+                            }
+                            Err(e) => {
+                                let result = (idx, model.path.clone(), Err(e));
+                                self.save(&mut db, &mut reports, result);
+                            }
+                        }
+                    } else {
+                        match process.check_file(&model, stored.as_ref()) {
+                            Ok(Some(dest)) => needed.push((idx, dest)),
+                            Ok(None) => reports[idx].skipped(),
+                            Err(e) => {
+                                let result = (idx, model.path.clone(), Err(e));
+                                self.save(&mut db, &mut reports, result);
+                            }
+                        }
+                    }
+                }
+
+                if needed.is_empty() {
+                    debug!("skip {:?}", model.path);
+                    continue;
+                }
+
+                if self.dryrun {
+                    for (idx, _) in needed {
+                        let target = self.processes[idx].target.display();
+                        info!("dryrun, would backup: {:?} to {}", model.path, target);
+                        reports[idx].copied(model.len);
+                    }
+                    continue;
+                }
+
+                let mut senders: Vec<(usize, SyncSender<Chunk>)> = needed
+                    .into_iter()
+                    .filter_map(|(idx, dest)| {
+                        let (tx, chunks) = mpsc::sync_channel(self.buffer);
+                        let job = Job {
+                            src: model.clone(),
+                            dest,
+                            chunks,
+                        };
+                        writers[idx].send(job).ok().map(|_| (idx, tx))
+                    })
+                    .collect();
+
+                // dropping the senders without the end marker fails the writes
+                match self.read_chunks(&model, &mut senders) {
+                    Ok((hash, slow)) => {
+                        for (_, tx) in senders.iter() {
+                            let _ = tx.send(Chunk::End(hash.clone()));
+                        }
+                        for idx in slow {
+                            warn!(
+                                "{} is slow, copy {:?} after the other targets",
+                                self.processes[idx].target.display(),
+                                model.path
+                            );
+                            dropped.insert((idx, model.path.clone()));
+                            lagged.push((idx, model.clone()));
+                        }
+                    }
+                    Err(e) => error!("error reading {}: {}", model.path.display(), e),
+                }
+            }
+
+            drop(writers);
+            for result in results.iter() {
+                self.save_unless_dropped(&mut db, &mut reports, &dropped, result);
+            }
+        });
+
+        // the slow targets catch up, reading the files again
+        for (idx, model) in lagged {
+            if self.stop.load(Ordering::Relaxed) {
+                warn!("stop requested, skip the remaining files");
+                break;
+            }
+
+            let stored = db.find(model.path.to_str().unwrap()).cloned();
+            match self.processes[idx].backup_file(&model, stored.as_ref()) {
+                Ok(None) => reports[idx].skipped(),
+                Ok(Some(saved)) => {
+                    self.save(&mut db, &mut reports, (idx, model.path.clone(), Ok(saved)))
+                }
+                Err(e) => self.save(&mut db, &mut reports, (idx, model.path.clone(), Err(e))),
+            }
+        }
+
+        let elapsed = start_time.elapsed().as_secs_f64();
+        let bytes: u64 = reports.iter().map(|report| report.bytes).sum();
+        info!(
+            "wrote {} bytes in {:.3} seconds, {:.2} MB/s",
+            bytes,
+            elapsed,
+            bytes as f64 / 1_000_000.0 / elapsed.max(0.001)
+        );
+
+        for report in reports.iter_mut() {
+            report.seconds = elapsed;
+        }
+
+        (db, reports)
+    }
+
+    /// return each file with the indexes of the targets it goes to, in the order first seen
+    fn target_files(&self) -> Vec<(FileModel, Vec<usize>)> {
+        let mut files: Vec<(FileModel, Vec<usize>)> = vec![];
+        let mut index: HashMap<PathBuf, usize> = HashMap::new();
+
+        for (idx, process) in self.processes.iter().enumerate() {
+            for file in process.files.iter() {
+                match index.get(&file.path) {
+                    Some(&pos) if files[pos].1.contains(&idx) => (),
+                    Some(&pos) => files[pos].1.push(idx),
+                    None => {
+                        index.insert(file.path.clone(), files.len());
+                        files.push((file.clone(), vec![idx]));
+                    }
+                }
+            }
+        }
+
+        files
+    }
+
+    /// as save, with the failed writes of the targets that dropped out of a file left for the catch up
+    fn save_unless_dropped(
+        &self,
+        db: &mut KeyValueStore,
+        reports: &mut [TargetReport],
+        dropped: &HashSet<(usize, PathBuf)>,
+        result: (usize, PathBuf, Result<FileModel>),
+    ) {
+        let (idx, src, resp) = result;
+        if resp.is_err() && dropped.contains(&(idx, src.clone())) {
+            debug!(
+                "dropped {:?} for {}",
+                src,
+                self.processes[idx].target.display()
+            );
+            return;
+        }
+
+        self.save(db, reports, (idx, src, resp));
+    }
+
+    /// read the file in chunks, sending each to every writer; a writer whose buffer stays full past the lag limit
+    /// is dropped.  return the file's hash and the indexes of the dropped targets
+    fn read_chunks(
+        &self,
+        model: &FileModel,
+        senders: &mut Vec<(usize, SyncSender<Chunk>)>,
+    ) -> Result<(String, Vec<usize>)> {
+        let mut file = File::open(&model.path)?;
+        let mut hasher = sha::Sha256::new();
+        let mut slow = vec![];
+
+        loop {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            let count = file.read(&mut buf)?;
+            if count == 0 {
+                break;
+            }
+
+            buf.truncate(count);
+            hasher.update(&buf);
+
+            let chunk = Arc::new(buf);
+            senders.retain(|(idx, tx)| {
+                let sent = send_within(tx, Arc::clone(&chunk), self.lag_limit);
+                if !sent {
+                    slow.push(*idx);
+                }
+                sent
+            });
+        }
+
+        Ok((hex::encode(hasher.finish()), slow))
+    }
+
+    /// save a target's result to the database, merged with the other targets' saves of the same file, and count
+    /// it in the target's report
+    fn save(
+        &self,
+        db: &mut KeyValueStore,
+        reports: &mut [TargetReport],
+        result: (usize, PathBuf, Result<FileModel>),
+    ) {
+        let (idx, src, resp) = result;
+        let target = self.processes[idx].target.display();
+        match resp {
+            Ok(saved_model) => {
+                info!("file backup: {:?} -> {}", saved_model.path, target);
+                reports[idx].copied(saved_model.len);
+                if let Err(e) = db.update(saved_model) {
+                    error!("could not save to database: {:?}", e);
+                }
+            }
+            Err(e) => {
+                error!("backup of {:?} to {} failed: {:#}", src, target, e);
+                reports[idx].failed(src.to_str().unwrap(), &e);
+            }
+        }
+    }
+}
+
+/// write the job's copy from its chunks; the hash arrives with the last chunk
+fn write_job(process: &BackupProcess, job: Job) -> Result<FileModel> {
+    let dest_path = job.dest.path.to_str().unwrap().to_string();
+    let mut reader = ChunkReader::new(job.chunks);
+    let mut model = process.write_model(&job.src, job.dest, &mut reader)?;
+
+    if let Some(hash) = reader.hash {
+        if let Some(version) = model.versions.iter_mut().find(|v| v.path == dest_path) {
+            version.hash = hash.clone();
+        }
+        if let Some(record) = model.copies.get_mut(&dest_path) {
+            record.hash = hash.clone();
+        }
+        model.hash = hash;
+    }
+
+    Ok(model)
+}
+
+/// send the chunk to a writer, waiting while its buffer is full; return false if it stays full past the limit
+fn send_within(tx: &SyncSender<Chunk>, chunk: Arc<Vec<u8>>, limit: Duration) -> bool {
+    let start = Instant::now();
+    let mut chunk = Chunk::Data(chunk);
+
+    loop {
+        match tx.try_send(chunk) {
+            // a writer that failed has dropped its receiver; the others carry on
+            Ok(_) | Err(TrySendError::Disconnected(_)) => return true,
+            Err(TrySendError::Full(_)) if start.elapsed() >= limit => return false,
+            Err(TrySendError::Full(full)) => {
+                chunk = full;
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CompareMode;
+    use crate::encryption::Key;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn chunk_reader() {
+        let (tx, rx) = mpsc::sync_channel(4);
+        tx.send(Chunk::Data(Arc::new(b"hello ".to_vec()))).unwrap();
+        tx.send(Chunk::Data(Arc::new(b"world".to_vec()))).unwrap();
+        tx.send(Chunk::End("hash".to_string())).unwrap();
+
+        let mut reader = ChunkReader::new(rx);
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello world");
+        assert_eq!(reader.hash.as_deref(), Some("hash"));
+
+        // an unfinished read is an error rather than a short file
+        let (tx, rx) = mpsc::sync_channel(4);
+        tx.send(Chunk::Data(Arc::new(b"partial".to_vec()))).unwrap();
+        drop(tx);
+        let mut reader = ChunkReader::new(rx);
+        assert!(reader.read_to_end(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn process() {
+        let folder = "tests/tback-tmp/fan-out";
+        let _ = fs::remove_dir_all(folder);
+        for name in ["src", "usb", "nas"] {
+            fs::create_dir_all(format!("{}/{}", folder, name)).unwrap();
+        }
+
+        let files: Vec<FileModel> = (0..5)
+            .map(|idx| {
+                let path = format!("./{}/src/file-{}.txt", folder, idx);
+                fs::write(&path, "fan out ".repeat(idx * 50_000)).unwrap();
+                FileModel::new(&path).read_metadata().unwrap()
+            })
+            .collect();
+
+        let usb = format!("{}/usb", folder);
+        let nas = format!("{}/nas", folder);
+        let mut encrypted = BackupProcess::new(&nas, files.clone(), false);
+        encrypted.compress = true;
+        encrypted.key = Some(Key::load(Some(".test-replica/config/test.key")).unwrap());
+        let processes = vec![BackupProcess::new(&usb, files.clone(), false), encrypted];
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let fan_out = FanOut::new(processes, false);
+        let (db, reports) = fan_out.process(db);
+        assert_eq!(db.dbsize(), 5);
+        assert_eq!(reports.len(), 2);
+        for report in reports.iter() {
+            assert_eq!(report.copied, 5);
+            assert_eq!(report.failed, 0);
+            assert_eq!(
+                report.bytes,
+                files.iter().map(|model| model.len).sum::<u64>()
+            );
+        }
+
+        for model in files.iter() {
+            let saved = db.find(model.path.to_str().unwrap()).unwrap();
+            assert_eq!(saved.written_to.len(), 2);
+            assert_eq!(saved.hash, model.hash_file().unwrap());
+
+            let copy = format!("{}/{}", usb, model.relative_path());
+            assert_eq!(fs::read(copy).unwrap(), fs::read(&model.path).unwrap());
+            let stored = format!("{}/{}.gz.enc", nas, model.relative_path());
+            assert!(Path::new(&stored).exists());
+        }
+
+        // nothing changed so a second pass writes nothing
+        let mut db = db;
+        db.savedb("tests/tback-tmp/fan-out-db.json").unwrap();
+        let (db, reports) = fan_out.process(db);
+        assert!(!db.is_dirty());
+        assert_eq!(reports[0].skipped, 5);
+
+        // hashing every file, each source is hashed once for both targets
+        let mut fan_out = fan_out;
+        for process in fan_out.processes.iter_mut() {
+            process.compare = CompareMode::AlwaysHash;
+        }
+        let (_, reports) = fan_out.process(db);
+        assert!(reports.iter().all(|report| report.skipped == 5));
+        let hashes = fan_out.processes[0].source_hashes.clone().unwrap();
+        let shared = fan_out.processes[1].source_hashes.as_ref().unwrap();
+        assert!(Arc::ptr_eq(&hashes, shared));
+        assert_eq!(hashes.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn process_slow_target() {
+        let folder = "tests/tback-tmp/fan-out-slow";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/src", folder)).unwrap();
+
+        let files: Vec<FileModel> = (1..4)
+            .map(|idx| {
+                let path = format!("./{}/src/file-{}.txt", folder, idx);
+                fs::write(&path, "slow target ".repeat(idx * 200_000)).unwrap();
+                FileModel::new(&path).read_metadata().unwrap()
+            })
+            .collect();
+
+        // with no lag allowed, a target that falls behind drops out and copies the file afterwards
+        let fast = format!("{}/fast", folder);
+        let slow = format!("{}/slow", folder);
+        let mut stored = BackupProcess::new(&slow, files.clone(), false);
+        stored.compress = true;
+        stored.key = Some(Key::from_bytes([3u8; 32]));
+        let processes = vec![BackupProcess::new(&fast, files.clone(), false), stored];
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let mut fan_out = FanOut::new(processes, false);
+        fan_out.buffer = 1;
+        fan_out.lag_limit = Duration::ZERO;
+        let (db, reports) = fan_out.process(db);
+        for report in reports.iter() {
+            assert_eq!(report.copied, 3);
+            assert_eq!(report.failed, 0);
+        }
+
+        for model in files.iter() {
+            let saved = db.find(model.path.to_str().unwrap()).unwrap();
+            assert_eq!(saved.written_to.len(), 2);
+            let copy = format!("{}/{}", fast, model.relative_path());
+            assert_eq!(fs::read(copy).unwrap(), fs::read(&model.path).unwrap());
+            let copy = format!("{}/{}.gz.enc", slow, model.relative_path());
+            assert_eq!(
+                saved.copy_record(&copy).unwrap().hash,
+                model.hash_file().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn process_routed() {
+        let folder = "tests/tback-tmp/fan-out-routed";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/a", folder)).unwrap();
+        fs::create_dir_all(format!("{}/b", folder)).unwrap();
+
+        let one = FileModel::new("./tests/file1.txt").read_metadata().unwrap();
+        let two = FileModel::new("./tests/file2.txt").read_metadata().unwrap();
+        let processes = vec![
+            BackupProcess::new(&format!("{}/a", folder), vec![one.clone()], false),
+            BackupProcess::new(
+                &format!("{}/b", folder),
+                vec![one.clone(), two.clone()],
+                false,
+            ),
+        ];
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let fan_out = FanOut::new(processes, true);
+        let files = fan_out.target_files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].1, vec![0, 1]);
+        assert_eq!(files[1].1, vec![1]);
+
+        // a file listed twice for a target is written to it once
+        let mut fan_out = fan_out;
+        fan_out.processes[0].files.push(one.clone());
+        assert_eq!(fan_out.target_files()[0].1, vec![0, 1]);
+        fan_out.processes[0].files.pop();
+
+        // dryrun writes nothing
+        let (db, reports) = fan_out.process(db);
+        assert_eq!(db.dbsize(), 0);
+        assert_eq!(reports[0].copied, 1);
+        assert_eq!(reports[1].copied, 2);
+        assert!(!Path::new(&format!("{}/b/tests/file2.txt", folder)).exists());
+    }
+}
@@ -2,15 +2,72 @@
 ///
 /// # file Model
 ///
+use crate::attributes::FileAttributes;
 use anyhow::{anyhow, Result};
 use chrono::naive::NaiveDateTime;
+use chrono::{DateTime, Utc};
 use domain_keys::keys::RouteKey;
 use hashbrown::HashSet;
 use log::error;
 use openssl::sha;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// the read buffer size used when streaming a file through the hasher
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// a time-stamped copy of a journaled file written to a target
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FileVersion {
+    pub path: String,
+    pub saved: NaiveDateTime,
+    pub len: u64,
+    pub hash: String,
+}
+
+impl FileVersion {
+    /// return the version's path for the target path and time, e.g. file.txt@2026-10-18T10:00:00Z
+    pub fn version_path(target_path: &Path, saved: DateTime<Utc>) -> PathBuf {
+        let stamp = saved.format("%Y-%m-%dT%H:%M:%SZ");
+        PathBuf::from(format!("{}@{}", target_path.display(), stamp))
+    }
+
+    /// return the time stamp portion of the version's path, without any compressed or encrypted extension
+    pub fn stamp(&self) -> &str {
+        match self.path.rsplit_once('@') {
+            Some((_, stamp)) => stamp.split('.').next().unwrap_or(""),
+            None => "",
+        }
+    }
+}
+
+/// the source's size, modified time and hash when a copy was written; compressed, encrypted and remote copies are
+/// compared against the record of their own copy, as each target may hold an older save
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CopyRecord {
+    pub len: u64,
+    pub modified: u64,
+    pub hash: String,
+    /// when the copy was written
+    pub saved: NaiveDateTime,
+}
+
+/// return the extensions added to the original's file name by the copy, e.g. .gz.enc; a version's time stamp is
+/// skipped.  returns None if the copy's name does not start with the original's name.
+pub fn copy_suffix<'a>(original: &Path, copy: &'a Path) -> Option<&'a str> {
+    let name = original.file_name()?.to_str()?;
+    let copy_name = copy.file_name()?.to_str()?;
+    let suffix = copy_name.strip_prefix(name)?;
+
+    match suffix.strip_prefix('@') {
+        Some(stamped) => Some(stamped.find('.').map_or("", |idx| &stamped[idx..])),
+        None => Some(suffix),
+    }
+}
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct FileModel {
@@ -21,6 +78,21 @@ pub struct FileModel {
     pub modified: u64,
     pub last_saved: Option<NaiveDateTime>,
     pub written_to: HashSet<String>,
+    #[serde(default)]
+    pub versions: Vec<FileVersion>,
+    #[serde(default)]
+    pub compressed: bool,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// the source's modified time, mode and optionally owner and extended attributes when last saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<FileAttributes>,
+    /// the path a symlink points to, when the link itself is backed up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<PathBuf>,
+    /// the source as it was when each path in written_to was saved
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub copies: BTreeMap<String, CopyRecord>,
 }
 
 impl FileModel {
@@ -33,6 +105,12 @@ impl FileModel {
             modified: 0,
             last_saved: None,
             written_to: HashSet::new(),
+            versions: vec![],
+            compressed: false,
+            encrypted: false,
+            attributes: None,
+            link_target: None,
+            copies: BTreeMap::new(),
         }
     }
 
@@ -45,6 +123,12 @@ impl FileModel {
             modified,
             last_saved: None,
             written_to: HashSet::new(),
+            versions: vec![],
+            compressed: false,
+            encrypted: false,
+            attributes: None,
+            link_target: None,
+            copies: BTreeMap::new(),
         }
     }
 
@@ -58,6 +142,12 @@ impl FileModel {
             modified: model.modified,
             last_saved: model.last_saved,
             written_to: model.written_to,
+            versions: model.versions,
+            compressed: model.compressed,
+            encrypted: model.encrypted,
+            attributes: model.attributes,
+            link_target: model.link_target,
+            copies: model.copies,
         }
     }
 
@@ -83,6 +173,20 @@ impl FileModel {
         Ok(model)
     }
 
+    /// create the model for the symlink itself, recording the path it points to; len and modified are the link's
+    pub fn from_link(path: &Path) -> Result<FileModel> {
+        let meta = path.symlink_metadata()?;
+        let modified = meta
+            .modified()?
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)?;
+
+        let mut model =
+            FileModel::from(path.to_path_buf(), meta.len(), modified.as_micros() as u64);
+        model.link_target = Some(std::fs::read_link(path)?);
+
+        Ok(model)
+    }
+
     /// calc the file's hash in hex format
     pub fn calc_hash(&self, content: &[u8]) -> String {
         let mut hasher = sha::Sha256::new();
@@ -92,6 +196,41 @@ impl FileModel {
         hex::encode(hash)
     }
 
+    /// stream the file's content through sha-256 and return the hash in hex format
+    pub fn hash_file(&self) -> Result<String> {
+        let mut file = File::open(&self.path)?;
+        let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+        let mut hasher = sha::Sha256::new();
+
+        loop {
+            let count = file.read(&mut buf)?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&buf[..count]);
+        }
+
+        Ok(hex::encode(hasher.finish()))
+    }
+
+    /// return the record of the source when the copy at the path was written; None if there is no record, so the
+    /// copy is written again
+    pub fn copy_record(&self, path: &str) -> Option<CopyRecord> {
+        if !self.written_to.contains(path) {
+            return None;
+        }
+
+        self.copies.get(path).cloned()
+    }
+
+    /// return the most recent version written under the target prefix
+    pub fn latest_version(&self, prefix: &str) -> Option<&FileVersion> {
+        self.versions
+            .iter()
+            .filter(|version| version.path.starts_with(prefix))
+            .max_by_key(|version| version.saved)
+    }
+
     /// strip off the home parts to return the relative path
     pub fn relative_path(&self) -> String {
         let mut home = env::var("HOME").expect("The user should have a home folder.");
@@ -118,4 +257,93 @@ mod tests {
             "e23cd91ac0d728eec44d3c20b87accdb75ec7b9e67d35bad7fb8b672e0348d95"
         );
     }
+
+    #[test]
+    fn from_link() {
+        let model = FileModel::from_link(Path::new("./tests/.config/file3.txt")).unwrap();
+        assert_eq!(model.link_target, Some(PathBuf::from("../file3.txt")));
+        assert_eq!(model.len, 12);
+
+        assert!(FileModel::from_link(Path::new("./tests/file3.txt")).is_err());
+    }
+
+    #[test]
+    fn versions() {
+        use chrono::TimeZone;
+
+        let saved = Utc.with_ymd_and_hms(2026, 10, 18, 10, 0, 0).unwrap();
+        let path = FileVersion::version_path(Path::new("tback/file.txt"), saved);
+        assert_eq!(path, PathBuf::from("tback/file.txt@2026-10-18T10:00:00Z"));
+
+        let mut model = FileModel::new("file.txt");
+        assert!(model.latest_version("tback/").is_none());
+
+        for (hour, target) in [(10, "tback/"), (12, "tback/"), (14, "qback/")] {
+            let saved = Utc.with_ymd_and_hms(2026, 10, 18, hour, 0, 0).unwrap();
+            let path = FileVersion::version_path(Path::new(&format!("{}file.txt", target)), saved);
+            model.versions.push(FileVersion {
+                path: path.to_str().unwrap().to_string(),
+                saved: saved.naive_utc(),
+                ..FileVersion::default()
+            });
+        }
+
+        let latest = model.latest_version("tback/").unwrap();
+        assert_eq!(latest.stamp(), "2026-10-18T12:00:00Z");
+
+        let mut latest = latest.clone();
+        latest.path = format!("{}.gz.enc", latest.path);
+        assert_eq!(latest.stamp(), "2026-10-18T12:00:00Z");
+    }
+
+    #[test]
+    fn copy_suffix() {
+        let original = Path::new("./tests/file1.txt");
+        let suffix = |copy: &'static str| super::copy_suffix(original, Path::new(copy));
+
+        assert_eq!(suffix("tback/tests/file1.txt"), Some(""));
+        assert_eq!(suffix("tback/tests/file1.txt.gz.enc"), Some(".gz.enc"));
+        assert_eq!(
+            suffix("tback/tests/file1.txt@2026-10-18T10:00:00Z"),
+            Some("")
+        );
+        assert_eq!(
+            suffix("tback/tests/file1.txt@2026-10-18T10:00:00Z.gz"),
+            Some(".gz")
+        );
+        assert_eq!(suffix("tback/tests/file2.txt"), None);
+    }
+
+    #[test]
+    fn copy_record() {
+        let mut model = FileModel::new("./tests/file1.txt");
+        let copy = "tback/tests/file1.txt.gz";
+        assert!(model.copy_record(copy).is_none());
+
+        // a copy without a record is written again rather than taken as current
+        model.written_to.insert(copy.to_string());
+        assert!(model.copy_record(copy).is_none());
+
+        let record = CopyRecord {
+            len: 186,
+            saved: Utc::now().naive_utc(),
+            ..CopyRecord::default()
+        };
+        model.copies.insert(copy.to_string(), record.clone());
+        assert_eq!(model.copy_record(copy), Some(record));
+    }
+
+    #[test]
+    fn hash_file() {
+        let model = FileModel::new("tests/big-file.pdf");
+        let hash = model.hash_file().unwrap();
+
+        assert_eq!(
+            hash,
+            "e23cd91ac0d728eec44d3c20b87accdb75ec7b9e67d35bad7fb8b672e0348d95"
+        );
+
+        let model = FileModel::new("tests/no-such-file.txt");
+        assert!(model.hash_file().is_err());
+    }
 }
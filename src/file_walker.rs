@@ -1,43 +1,107 @@
-use crate::config::Config;
+use crate::config::{Config, SourceConfig, SymlinkMode};
 use crate::file_model::FileModel;
-use anyhow::Result;
-use log::{debug, error, info};
+use crate::path_filter::PathFilter;
+use log::{debug, error, info, warn};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
+/// a source folder with its rules and the filter built from the global and source excludes
+struct Source {
+    rules: SourceConfig,
+    folder: PathBuf,
+    filter: PathFilter,
+    symlinks: SymlinkMode,
+}
+
+impl Source {
+    /// return true if the path matches the exclude patterns or an ignore file between the source folder and the path
+    fn exclude(&self, path: &Path) -> bool {
+        if self.filter.is_excluded(path, Some(&self.folder)) {
+            debug!("exclude: {}", path.display());
+            return true;
+        }
+
+        false
+    }
+
+    /// return true if the path is inside the folder and no deeper than the max depth
+    fn contains(&self, path: &Path) -> bool {
+        match path.strip_prefix(&self.folder) {
+            Ok(rest) => self
+                .rules
+                .max_depth
+                .map_or(true, |depth| rest.components().count() <= depth),
+            Err(_) => false,
+        }
+    }
+}
+
+/// the files found by a walk and why each path that could not be read was left out
+#[derive(Debug, Default)]
+pub struct Walk {
+    pub files: Vec<FileModel>,
+    pub errors: Vec<String>,
+}
+
+impl Walk {
+    /// log and record a path that could not be read
+    fn failed(&mut self, path: &Path, error: impl std::fmt::Display) {
+        let msg = format!("could not read {}: {}", path.display(), error);
+        error!("{}", msg);
+        self.errors.push(msg);
+    }
+}
+
 pub struct FileWalker {
     config: Config,
     home: String,
+    sources: Vec<Source>,
 }
 
 impl FileWalker {
     /// create a new FileWalker
     pub fn new(config: Config) -> FileWalker {
         let home = config.clone().home;
+        let sources = config
+            .source_list()
+            .into_iter()
+            .map(|rules| {
+                let folder = [&home, &rules.path].iter().collect();
+                let excludes = [config.excludes.clone(), rules.excludes.clone()].concat();
+                let filter = PathFilter::new(&excludes, &config.includes);
+                let symlinks = rules.symlink_mode(config.symlinks);
+
+                Source {
+                    rules,
+                    folder,
+                    filter,
+                    symlinks,
+                }
+            })
+            .collect();
 
-        FileWalker { config, home }
+        FileWalker {
+            config,
+            home,
+            sources,
+        }
     }
 
     /// walk the files and folders
-    pub fn walk_files_and_folders(&self) -> Result<Vec<FileModel>> {
-        let mut files: Vec<FileModel> = Vec::new();
+    pub fn walk_files_and_folders(&self) -> Walk {
+        let mut walk = self.walk_files();
+        let mut folders = self.walk_folders();
+        walk.files.append(&mut folders.files);
+        walk.errors.append(&mut folders.errors);
 
-        match self.walk_files() {
-            Ok(mut file_list) => files.append(&mut file_list),
-            Err(e) => error!("error walking files: {}", e),
-        }
-        match self.walk_folders() {
-            Ok(mut file_list) => files.append(&mut file_list),
-            Err(e) => error!("error walking files: {}", e),
-        }
-
-        Ok(files)
+        walk
     }
 
     /// walk all the folders and files specified in config source folders and files
-    pub fn walk_files(&self) -> Result<Vec<FileModel>> {
+    pub fn walk_files(&self) -> Walk {
         info!("walk the folders and files");
-        let mut files: Vec<FileModel> = Vec::new();
+        let mut walk = Walk::default();
 
         for file in self.config.files.iter() {
             let pbuf: PathBuf = [&self.home, file].iter().collect();
@@ -47,65 +111,161 @@ impl FileWalker {
                 if path.is_file() && path.exists() {
                     debug!("{}", &pbuf.display());
                     let model = FileModel::new(pbuf.to_str().unwrap());
-                    let model = model.read_metadata()?;
-
-                    files.push(model);
+                    match model.read_metadata() {
+                        Ok(model) => walk.files.push(model),
+                        Err(e) => walk.failed(path, e),
+                    }
                 }
             } else {
                 error!("{} not found", pbuf.display());
             }
         }
 
-        Ok(files)
+        walk
     }
 
-    /// walk all the source folders and gather the files
-    pub fn walk_folders(&self) -> Result<Vec<FileModel>> {
-        let mut files: Vec<FileModel> = Vec::new();
+    /// walk all the source folders and gather the files; a path that can't be read is recorded and the walk goes on
+    pub fn walk_folders(&self) -> Walk {
+        let mut walk = Walk::default();
+
+        for source in self.sources.iter() {
+            if !source.folder.exists() {
+                error!("{} not found", source.folder.display());
+                continue;
+            }
 
-        for folder in self.config.source_folders.iter() {
-            let fname: PathBuf = [&self.home, folder].iter().collect();
+            let follow = source.symlinks == SymlinkMode::Follow;
+            let mut walker = WalkDir::new(&source.folder).follow_links(follow);
+            if let Some(depth) = source.rules.max_depth {
+                walker = walker.max_depth(depth);
+            }
 
-            for entry in WalkDir::new(fname).into_iter().filter_map(|e| e.ok()) {
-                if self.exclude(entry.path()) || entry.file_name() == ".DS_Store" {
+            // skip excluded folders entirely rather than walking and dropping their contents
+            let entries = walker
+                .into_iter()
+                .filter_entry(|e| e.depth() == 0 || !source.exclude(e.path()));
+
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) if e.loop_ancestor().is_some() => {
+                        warn!("skip symlink loop: {}", e);
+                        continue;
+                    }
+                    // removed since its folder was read
+                    Err(e) if e.io_error().map(|e| e.kind()) == Some(ErrorKind::NotFound) => {
+                        debug!("skip: {}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        let path = e.path().unwrap_or(&source.folder).to_path_buf();
+                        walk.failed(&path, e);
+                        continue;
+                    }
+                };
+
+                if entry.file_name() == ".DS_Store"
+                    || entry.file_name() == crate::path_filter::IGNORE_FILE
+                {
                     continue;
                 }
 
-                let meta = entry.metadata()?;
+                if entry.path_is_symlink() {
+                    match source.symlinks {
+                        SymlinkMode::Skip => {
+                            debug!("symlink: {}", entry.path().display());
+                            continue;
+                        }
+                        SymlinkMode::Store => {
+                            if source.filter.is_included(entry.path()) {
+                                match FileModel::from_link(entry.path()) {
+                                    Ok(model) => walk.files.push(model),
+                                    Err(e) => walk.failed(entry.path(), e),
+                                }
+                            }
+                            continue;
+                        }
+                        SymlinkMode::Follow => (),
+                    }
+                }
+
+                let meta = match entry.metadata() {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        walk.failed(entry.path(), e);
+                        continue;
+                    }
+                };
                 let pbuf = entry.into_path();
                 let path = pbuf.as_path();
 
-                if path.is_symlink() {
-                    debug!("symlink: {}", path.display());
-                    continue;
-                }
-
-                if path.is_file() {
-                    let modified = meta.modified()?;
-                    let modified = modified.duration_since(std::time::SystemTime::UNIX_EPOCH)?;
-                    // debug!("{} {} {}", &pbuf.display(), meta.len(), modified.as_micros());
-                    let model = FileModel::from(pbuf, meta.len(), modified.as_micros() as u64);
-                    files.push(model);
+                if path.is_file() && source.filter.is_included(path) {
+                    match meta.modified() {
+                        Ok(modified) => {
+                            // a time before the epoch is taken as the epoch
+                            let modified = modified
+                                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                                .unwrap_or_default();
+                            // debug!("{} {} {}", &pbuf.display(), meta.len(), modified.as_micros());
+                            let model =
+                                FileModel::from(pbuf, meta.len(), modified.as_micros() as u64);
+                            walk.files.push(model);
+                        }
+                        Err(e) => walk.failed(path, e),
+                    }
                 }
             }
         }
 
-        Ok(files)
+        walk
     }
 
-    /// if the file path contains an exclude phrase return true, else false
-    fn exclude(&self, path: &Path) -> bool {
-        let excludes = &self.config.excludes;
-        let name = path.to_str().unwrap().to_string();
+    /// return the absolute or home relative paths of the configured source folders
+    pub fn source_folders(&self) -> Vec<PathBuf> {
+        self.sources
+            .iter()
+            .map(|source| source.folder.clone())
+            .collect()
+    }
 
-        for ex in excludes.iter() {
-            if name.contains(ex.as_str()) {
-                debug!("exclude: {} {}", name, ex);
-                return true;
+    /// return the absolute or home relative paths of the configured files
+    pub fn source_files(&self) -> Vec<PathBuf> {
+        self.config
+            .files
+            .iter()
+            .map(|file| [&self.home, file].iter().collect())
+            .collect()
+    }
+
+    /// return the model for a single path if it is a configured file, or a regular file or symlink, per the symlink
+    /// mode, inside a source folder that is not excluded; used to check paths reported by the file watcher
+    pub fn model_from_path(&self, path: &Path) -> Option<FileModel> {
+        let name = path.file_name()?;
+        let source = self.sources.iter().find(|source| {
+            source.contains(path)
+                && !source.exclude(path)
+                && source.filter.is_included(path)
+                && name != ".DS_Store"
+                && name != crate::path_filter::IGNORE_FILE
+        });
+        let tracked = self.source_files().iter().any(|file| file == path) || source.is_some();
+        if !tracked {
+            return None;
+        }
+
+        if path.is_symlink() {
+            match source.map_or(SymlinkMode::Skip, |source| source.symlinks) {
+                SymlinkMode::Skip => return None,
+                SymlinkMode::Store => return FileModel::from_link(path).ok(),
+                SymlinkMode::Follow => (),
             }
         }
 
-        false
+        if !path.is_file() {
+            return None;
+        }
+
+        FileModel::new(path.to_str()?).read_metadata().ok()
     }
 }
 
@@ -120,11 +280,197 @@ mod tests {
         let config = Config::read_config(".test-replica/config/walk-config.toml").unwrap();
         let walker = FileWalker::new(config.clone());
 
+        let source = &walker.sources[0];
         let path = Path::new("/home/dpw/.config/chromium/thing");
-        assert!(walker.exclude(path));
+        assert!(source.exclude(path));
 
         let path = Path::new(".config/configstore/");
-        assert!(walker.exclude(path));
+        assert!(source.exclude(path));
+
+        let path = Path::new("/home/dpw/.config/chromium-notes.md");
+        assert!(!source.exclude(path));
+    }
+
+    #[test]
+    fn includes_and_ignore_files() {
+        let folder = "tests/tback-tmp/walk-ignore";
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(format!("{}/cache", folder)).unwrap();
+        std::fs::create_dir_all(format!("{}/notes", folder)).unwrap();
+        for name in [
+            "cache/page.md",
+            "notes/my-cache-notes.md",
+            "notes/todo.md",
+            "notes/scratch.tmp",
+            "notes/draft.md",
+            "notes/important.tmp",
+        ] {
+            std::fs::write(format!("{}/{}", folder, name), name).unwrap();
+        }
+        std::fs::write(format!("{}/notes/.replicaignore", folder), "draft.md\n").unwrap();
+
+        let mut config = Config::read_config(".test-replica/config/walk-config.toml").unwrap();
+        config.files = vec![];
+        config.source_folders = vec![folder.to_string()];
+        config.excludes = vec![
+            "cache".to_string(),
+            "**/*.tmp".to_string(),
+            "!important.tmp".to_string(),
+        ];
+        let walker = FileWalker::new(config.clone());
+
+        let mut names: Vec<String> = walker
+            .walk_folders()
+            .files
+            .iter()
+            .map(|model| {
+                model
+                    .path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, ["important.tmp", "my-cache-notes.md", "todo.md"]);
+
+        let draft = format!("./{}/notes/draft.md", folder);
+        assert!(walker.model_from_path(Path::new(&draft)).is_none());
+
+        config.includes = vec!["*.tmp".to_string()];
+        let walker = FileWalker::new(config);
+        let files = walker.walk_folders().files;
+        assert_eq!(files.len(), 1);
+        assert!(files[0].path.ends_with("important.tmp"));
+    }
+
+    #[test]
+    fn symlink_modes() {
+        let folder = "tests/tback-tmp/walk-links";
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(format!("{}/sub", folder)).unwrap();
+        std::fs::write(format!("{}/real.txt", folder), "real").unwrap();
+        std::os::unix::fs::symlink("real.txt", format!("{}/link.txt", folder)).unwrap();
+        std::os::unix::fs::symlink("..", format!("{}/sub/loop", folder)).unwrap();
+
+        let mut config = Config::read_config(".test-replica/config/walk-config.toml").unwrap();
+        config.files = vec![];
+        config.source_folders = vec![folder.to_string()];
+
+        let walk = |config: &Config| {
+            let mut files = FileWalker::new(config.clone()).walk_folders().files;
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            files
+        };
+
+        let files = walk(&config);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].link_target.is_none());
+
+        config.symlinks = SymlinkMode::Store;
+        let files = walk(&config);
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].link_target, Some(PathBuf::from("real.txt")));
+        assert_eq!(files[2].link_target, Some(PathBuf::from("..")));
+        let walker = FileWalker::new(config.clone());
+        let link = format!("./{}/link.txt", folder);
+        let model = walker.model_from_path(Path::new(&link)).unwrap();
+        assert_eq!(model.link_target, Some(PathBuf::from("real.txt")));
+
+        // the loop back to the parent folder is skipped
+        config.symlinks = SymlinkMode::Follow;
+        let files = walk(&config);
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|model| model.link_target.is_none()));
+        assert_eq!(files[0].len, 4);
+    }
+
+    #[test]
+    fn sources() {
+        let folder = "tests/tback-tmp/walk-sources";
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(format!("{}/a/b", folder)).unwrap();
+        for name in ["top.txt", "skip.tmp", "a/one.txt", "a/b/two.txt"] {
+            std::fs::write(format!("{}/{}", folder, name), name).unwrap();
+        }
+        std::os::unix::fs::symlink("../../file1.txt", format!("{}/linked.txt", folder)).unwrap();
+
+        let mut config = Config::read_config(".test-replica/config/walk-config.toml").unwrap();
+        config.files = vec![];
+        config.source_folders = vec![];
+        config.sources = vec![SourceConfig {
+            excludes: vec!["*.tmp".to_string()],
+            max_depth: Some(2),
+            ..SourceConfig::new(folder)
+        }];
+        let walker = FileWalker::new(config.clone());
+
+        let mut names: Vec<String> = walker
+            .walk_folders()
+            .files
+            .iter()
+            .map(|model| {
+                model
+                    .path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, ["one.txt", "top.txt"]);
+
+        let deep = format!("./{}/a/b/two.txt", folder);
+        assert!(walker.model_from_path(Path::new(&deep)).is_none());
+        let linked = format!("./{}/linked.txt", folder);
+        assert!(walker.model_from_path(Path::new(&linked)).is_none());
+
+        config.sources[0].follow_symlinks = true;
+        let walker = FileWalker::new(config);
+        let files = walker.walk_folders().files;
+        assert_eq!(files.len(), 3);
+        let model = walker.model_from_path(Path::new(&linked)).unwrap();
+        assert_eq!(model.len, 186);
+    }
+
+    #[test]
+    fn unreadable_folder() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let folder = "tests/tback-tmp/walk-unreadable";
+        let locked = format!("{}/locked", folder);
+        let unlock = || {
+            let _ = std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755));
+        };
+        unlock();
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(&locked).unwrap();
+        std::fs::write(format!("{}/open.txt", folder), "open").unwrap();
+        std::fs::write(format!("{}/secret.txt", locked), "secret").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let mut config = Config::read_config(".test-replica/config/walk-config.toml").unwrap();
+        config.files = vec![];
+        config.source_folders = vec![folder.to_string()];
+        let walk = FileWalker::new(config).walk_folders();
+        let readable = std::fs::read_dir(&locked).is_ok();
+        unlock();
+
+        // root reads the folder anyway
+        if readable {
+            assert!(walk.errors.is_empty());
+            return;
+        }
+
+        // the rest of the folder is still walked
+        assert_eq!(walk.files.len(), 1);
+        assert!(walk.files[0].path.ends_with("open.txt"));
+        assert_eq!(walk.errors.len(), 1);
+        assert!(walk.errors[0].contains("locked"));
     }
 
     #[test]
@@ -135,9 +481,10 @@ mod tests {
         config.source_folders = vec![String::from("bad.txt"), String::from("even-worse.txt")];
         let walker = FileWalker::new(config.clone());
 
-        let files = walker.walk_files_and_folders().unwrap();
-        println!("{:?}", files);
-        assert_eq!(files.len(), 0);
+        let walk = walker.walk_files_and_folders();
+        println!("{:?}", walk);
+        assert_eq!(walk.files.len(), 0);
+        assert!(walk.errors.is_empty());
     }
 
     #[test]
@@ -146,9 +493,38 @@ mod tests {
         let config = Config::read_config(".test-replica/config/walk-config.toml").unwrap();
         let walker = FileWalker::new(config.clone());
 
-        let files = walker.walk_files_and_folders().unwrap();
-        println!("{:?}", files);
-        assert_eq!(files.len(), 5);
+        let walk = walker.walk_files_and_folders();
+        println!("{:?}", walk);
+        assert_eq!(walk.files.len(), 5);
+        assert!(walk.errors.is_empty());
+    }
+
+    #[test]
+    fn model_from_path() {
+        let config = Config::read_config(".test-replica/config/walk-config.toml").unwrap();
+        let walker = FileWalker::new(config.clone());
+
+        let model = walker
+            .model_from_path(Path::new("./tests/file1.txt"))
+            .unwrap();
+        assert_eq!(model.len, 186);
+        assert!(walker
+            .model_from_path(Path::new("./tests/.config/file2.txt"))
+            .is_some());
+
+        // not configured, a symlink, excluded, missing
+        assert!(walker
+            .model_from_path(Path::new("./tests/file3.txt"))
+            .is_none());
+        assert!(walker
+            .model_from_path(Path::new("./tests/.config/file3.txt"))
+            .is_none());
+        assert!(walker
+            .model_from_path(Path::new("./tests/.config/chromium/x"))
+            .is_none());
+        assert!(walker
+            .model_from_path(Path::new("./tests/.config/nofile.txt"))
+            .is_none());
     }
 
     #[test]
@@ -161,7 +537,7 @@ mod tests {
         let file_count = walker.config.files.len();
         println!("{:?} count: {}", walker.config.files, file_count);
 
-        let list = walker.walk_files().expect("should return ok");
+        let list = walker.walk_files().files;
 
         println!("{:?}", list);
         assert_eq!(list.len(), file_count);
@@ -0,0 +1,163 @@
+/// File Watcher - collect the files changed since the last pass from file system events
+///
+/// # File Watcher
+///
+/// watches the source folders recursively and the folders holding the configured files; the changed paths are
+/// checked with the file walker's rules so the queue holds the same models a full walk would produce
+///
+use crate::config::Config;
+use crate::file_model::FileModel;
+use crate::file_walker::FileWalker;
+use anyhow::Result;
+use hashbrown::HashSet;
+use log::{debug, info, warn};
+use notify::event::EventKind;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+
+pub struct FileWatcher {
+    walker: FileWalker,
+    // kept alive to keep receiving events
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    // the watched path as configured and its canonical form, to map reported paths back to the configured form
+    roots: Vec<(PathBuf, PathBuf)>,
+}
+
+impl FileWatcher {
+    /// start watching the configured source folders and files
+    pub fn new(config: Config) -> Result<FileWatcher> {
+        let walker = FileWalker::new(config);
+        let (tx, events) = channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        let mut roots: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+        let mut watched: Vec<(PathBuf, RecursiveMode)> = walker
+            .source_folders()
+            .into_iter()
+            .map(|folder| (folder, RecursiveMode::Recursive))
+            .collect();
+
+        // watch a file's folder rather than the file so editors that replace the file are still seen
+        for file in walker.source_files() {
+            if let Some(parent) = file.parent() {
+                let parent = parent.to_path_buf();
+                if !watched.iter().any(|(path, _)| path == &parent) {
+                    watched.push((parent, RecursiveMode::NonRecursive));
+                }
+            }
+        }
+
+        for (path, mode) in watched {
+            match watcher.watch(path.as_path(), mode) {
+                Ok(_) => {
+                    info!("watch {} {:?}", path.display(), mode);
+                    let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+                    roots.push((path, canonical));
+                }
+                Err(e) => warn!("could not watch {}: {}", path.display(), e),
+            }
+        }
+
+        Ok(FileWatcher {
+            walker,
+            _watcher: watcher,
+            events,
+            roots,
+        })
+    }
+
+    /// return the models of the tracked files that were created or modified since the last call
+    pub fn changed_files(&self) -> Vec<FileModel> {
+        let mut paths: HashSet<PathBuf> = HashSet::new();
+
+        for event in self.events.try_iter() {
+            match event {
+                Ok(event) => match event.kind {
+                    EventKind::Create(_) | EventKind::Modify(_) => {
+                        paths.extend(event.paths.iter().map(|path| self.configured_path(path)))
+                    }
+                    _ => (),
+                },
+                Err(e) => warn!("watch error: {}", e),
+            }
+        }
+
+        let mut files: Vec<FileModel> = paths
+            .iter()
+            .filter_map(|path| self.walker.model_from_path(path))
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        debug!("changed files: {}", files.len());
+
+        files
+    }
+
+    /// discard any queued events, e.g. after a full walk
+    pub fn clear(&self) {
+        let count = self.events.try_iter().count();
+        debug!("clear {} events", count);
+    }
+
+    /// map a reported path to the form of the configured path that was watched
+    fn configured_path(&self, path: &Path) -> PathBuf {
+        for (configured, canonical) in self.roots.iter() {
+            if let Ok(rest) = path.strip_prefix(canonical) {
+                return configured.join(rest);
+            }
+        }
+
+        path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, thread, time::Duration};
+
+    fn wait_for_changes(watcher: &FileWatcher) -> Vec<FileModel> {
+        for _ in 0..40 {
+            thread::sleep(Duration::from_millis(50));
+            let files = watcher.changed_files();
+            if !files.is_empty() {
+                return files;
+            }
+        }
+
+        vec![]
+    }
+
+    #[test]
+    fn changed_files() {
+        let folder = "tests/tback-tmp/watch";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(format!("{}/sub", folder)).unwrap();
+
+        let mut config = Config::read_config(".test-replica/config/walk-config.toml").unwrap();
+        config.source_folders = vec![folder.to_string()];
+        config.files = vec![];
+        config.excludes = vec!["skip-me.*".to_string()];
+
+        let watcher = FileWatcher::new(config).unwrap();
+        assert!(watcher.changed_files().is_empty());
+
+        fs::write(format!("{}/sub/changed.txt", folder), "changed").unwrap();
+        fs::write(format!("{}/skip-me.txt", folder), "excluded").unwrap();
+
+        let files = wait_for_changes(&watcher);
+        assert_eq!(files.len(), 1);
+        assert_eq!(
+            files[0].path,
+            PathBuf::from("./tests/tback-tmp/watch/sub/changed.txt")
+        );
+        assert_eq!(files[0].len, 7);
+
+        fs::write(format!("{}/sub/cleared.txt", folder), "cleared").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        watcher.clear();
+        assert!(watcher.changed_files().is_empty());
+    }
+}
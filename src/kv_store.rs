@@ -1,45 +1,73 @@
 use crate::file_model::FileModel;
+use crate::target;
 /// Key/Value Store - database operations
 use anyhow::{anyhow, Result};
 use hashbrown::HashMap;
 use log::{error, info, warn};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufReader, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 // use serde::{Deserialize, Serialize};
 
+/// the number of snapshots of the previous database kept by default
+pub const DEFAULT_SNAPSHOTS: usize = 5;
+
 #[derive(Debug, Default, Clone)]
 pub struct KeyValueStore {
     dbpath: PathBuf,
     db: HashMap<String, FileModel>,
     index: HashMap<String, String>,
     dirty_flag: bool,
+    /// the number of time-stamped snapshots of the previous dbfile kept on save; 0 to keep none
+    pub snapshots: usize,
+    /// the previous dbfile was kept by a save in this pass, or was not valid and must not be kept
+    snapshot_taken: bool,
 }
 
 impl KeyValueStore {
-    /// initializes the database ; reads the dbfile, stores in k/v and creates index.
+    /// initializes the database ; reads the dbfile, stores in k/v and creates index.  if the dbfile can't be
+    /// parsed the newest snapshot that can is used instead.
     pub fn init(dbpath: PathBuf) -> Result<KeyValueStore> {
         let mut client = KeyValueStore {
             dbpath,
             db: HashMap::new(),
             index: HashMap::new(),
             dirty_flag: false,
+            snapshots: DEFAULT_SNAPSHOTS,
+            snapshot_taken: false,
         };
 
-        match client.read_dbfile() {
-            Ok(_) => Ok(client),
-            Err(e) => {
-                let msg = format!("error initializing database: {}", e);
-                error!("{}", msg);
-                Err(anyhow!("{}", msg))
+        let dbpath = client.dbpath.clone();
+        let err = match client.read_dbfile(&dbpath) {
+            Ok(_) => return Ok(client),
+            Err(e) => e,
+        };
+
+        for snapshot in Self::snapshot_files(&dbpath).iter().rev() {
+            client.db.clear();
+            client.index.clear();
+            if client.read_dbfile(snapshot).is_ok() {
+                warn!(
+                    "database {} is not valid ({}), restored from snapshot {}",
+                    dbpath.display(),
+                    err,
+                    snapshot.display()
+                );
+                client.dirty_flag = true;
+                client.snapshot_taken = true;
+                return Ok(client);
             }
         }
+
+        let msg = format!("error initializing database: {}", err);
+        error!("{}", msg);
+        Err(anyhow!("{}", msg))
     }
 
-    fn read_dbfile(&mut self) -> Result<()> {
-        info!("read database file from {}", self.dbpath.display());
+    fn read_dbfile(&mut self, dbpath: &Path) -> Result<()> {
+        info!("read database file from {}", dbpath.display());
 
-        let file = match File::open(self.dbpath.as_os_str()) {
+        let file = match File::open(dbpath) {
             Ok(file) => file,
             Err(e) => {
                 warn!("New empty list: {}", e);
@@ -55,30 +83,111 @@ impl KeyValueStore {
         let list: Vec<FileModel> = serde_json::from_str(&text)?;
 
         for model in list {
-            let key = model.key.to_string();
-            let mpath = model.path.to_str().unwrap();
-
-            self.db.insert(key.clone(), model.clone());
-            self.index.insert(mpath.to_string(), key);
+            let mpath = model.path.to_str().unwrap().to_string();
+
+            // older runs created a new record per file per run; collapse them to one
+            let model = match self.index.get(&mpath) {
+                Some(existing_key) => {
+                    let existing = self.db.remove(existing_key).unwrap();
+                    warn!("merge duplicate record for {}: {}", mpath, existing.key);
+                    self.dirty_flag = true;
+                    Self::merge(existing, model)
+                }
+                None => model,
+            };
+
+            self.index.insert(mpath, model.key.clone());
+            self.db.insert(model.key.clone(), model);
         }
 
         Ok(())
     }
 
+    /// merge two records for the same path; the most recently saved wins and the targets are combined
+    fn merge(a: FileModel, b: FileModel) -> FileModel {
+        let (mut newer, older) = if b.last_saved >= a.last_saved {
+            (b, a)
+        } else {
+            (a, b)
+        };
+
+        newer.written_to.extend(older.written_to);
+        for (path, record) in older.copies {
+            newer.copies.entry(path).or_insert(record);
+        }
+        for version in older.versions {
+            if !newer.versions.contains(&version) {
+                newer.versions.push(version);
+            }
+        }
+        newer.versions.sort_by_key(|version| version.saved);
+        newer
+    }
+
     /// get the file model or return None if it doesn't exist
     pub fn get(&self, key: &str) -> Option<&FileModel> {
         self.db.get(key)
     }
 
-    /// insert the model into k/v store's db
+    /// insert the model into k/v store's db and keep the path index current; a different
+    /// record previously indexed under the same path is replaced
     pub fn set(&mut self, model: FileModel) -> Result<()> {
         self.dirty_flag = true;
         let key = model.key.to_string();
+        let mpath = model.path.to_str().unwrap().to_string();
+
+        if let Some(old) = self.db.get(&key) {
+            let old_path = old.path.to_str().unwrap();
+            if old_path != mpath && self.index.get(old_path) == Some(&key) {
+                self.index.remove(old_path);
+            }
+        }
+
+        if let Some(old_key) = self.index.insert(mpath, key.clone()) {
+            if old_key != key {
+                warn!("replace record {} with {}", old_key, key);
+                self.db.remove(&old_key);
+            }
+        }
+
         let _ = self.db.insert(key, model);
 
         Ok(())
     }
 
+    /// insert the model, merging the targets and versions of an existing record with the same key for the path;
+    /// used where several targets save the same file from one read of the database
+    pub fn update(&mut self, model: FileModel) -> Result<()> {
+        let model = match self.find(model.path.to_str().unwrap()) {
+            Some(existing) if existing.key == model.key => Self::merge(existing.clone(), model),
+            _ => model,
+        };
+
+        self.set(model)
+    }
+
+    /// return the model with the key and save history of the existing record for the same path,
+    /// or the model unchanged if the path is not yet tracked
+    pub fn identify(&self, model: FileModel) -> FileModel {
+        match self.find(model.path.to_str().unwrap()) {
+            Some(existing) => FileModel {
+                key: existing.key.clone(),
+                last_saved: existing.last_saved,
+                written_to: existing.written_to.clone(),
+                versions: existing.versions.clone(),
+                attributes: existing.attributes.clone(),
+                copies: existing.copies.clone(),
+                ..model
+            },
+            None => model,
+        }
+    }
+
+    /// iterate over all the models in the database
+    pub fn models(&self) -> impl Iterator<Item = &FileModel> {
+        self.db.values()
+    }
+
     /// return the size of this database
     pub fn dbsize(&self) -> usize {
         self.db.len()
@@ -97,21 +206,36 @@ impl KeyValueStore {
         self.db.get(key.unwrap())
     }
 
-    /// save the kv to file
+    /// start a new pass, so the next save keeps the previous dbfile as a snapshot again
+    pub fn start_pass(&mut self) {
+        self.snapshot_taken = false;
+    }
+
+    /// save the kv to file; the json is written to a temp file, synced and renamed over the dbfile so a failed write
+    /// leaves the previous dbfile in place.  the first save of a pass first keeps the previous dbfile as a
+    /// time-stamped snapshot, unless it could not be read.
     pub fn savedb(&mut self, filename: &str) -> Result<()> {
         info!("save the k/v models as a list to file: {}", filename);
-        // ok, first copy the current to a time-stamped replica.
+        let path = Path::new(filename);
 
         let list: Vec<FileModel> = self.db.clone().into_values().collect();
         let json = serde_json::to_string_pretty(&list).unwrap();
 
-        match File::create(filename) {
-            Ok(mut buf) => buf.write_all(json.as_bytes())?,
-            Err(e) => {
-                let msg = format!("dbfile write error: {}, {}", filename, e);
-                error!("{}", msg);
-                return Err(anyhow!("{}", msg));
+        if !self.snapshot_taken {
+            if let Err(e) = self.snapshot(path) {
+                warn!("dbfile snapshot error: {}, {}", filename, e);
             }
+            self.snapshot_taken = true;
+        }
+
+        if let Err(e) = target::write_atomic(path, |temp| {
+            let mut file = File::create(temp)?;
+            file.write_all(json.as_bytes())?;
+            Ok(file.sync_all()?)
+        }) {
+            let msg = format!("dbfile write error: {}, {}", filename, e);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
         }
 
         info!("reset the dirty flag to false");
@@ -119,11 +243,62 @@ impl KeyValueStore {
 
         Ok(())
     }
+
+    /// copy the current dbfile to a time-stamped snapshot and remove the oldest beyond the retention count
+    fn snapshot(&self, path: &Path) -> Result<()> {
+        if self.snapshots == 0 || !path.is_file() {
+            return Ok(());
+        }
+
+        let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.6fZ");
+        let snapshot = PathBuf::from(format!("{}.{}", path.display(), stamp));
+        fs::copy(path, &snapshot)?;
+        info!("snapshot database to {}", snapshot.display());
+
+        let snapshots = Self::snapshot_files(path);
+        let count = snapshots.len().saturating_sub(self.snapshots);
+        for old in snapshots.iter().take(count) {
+            info!("remove snapshot {}", old.display());
+            fs::remove_file(old)?;
+        }
+
+        Ok(())
+    }
+
+    /// return the snapshots of the dbfile, oldest first
+    pub fn snapshot_files(path: &Path) -> Vec<PathBuf> {
+        let folder = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            Some(folder) => folder,
+            None => Path::new("."),
+        };
+        let prefix = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => format!("{}.", name),
+            None => return vec![],
+        };
+
+        let mut snapshots: Vec<PathBuf> = match fs::read_dir(folder) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    let name = entry.file_name();
+                    let name = name.to_str().unwrap_or("");
+                    name.strip_prefix(&prefix)
+                        .is_some_and(|stamp| stamp.starts_with(|c: char| c.is_ascii_digit()))
+                })
+                .map(|entry| path.with_file_name(entry.file_name()))
+                .collect(),
+            Err(_) => vec![],
+        };
+        snapshots.sort();
+
+        snapshots
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hashbrown::HashSet;
 
     #[test]
     fn savedb_bad() {
@@ -145,6 +320,72 @@ mod tests {
         assert!(result.is_ok())
     }
 
+    #[test]
+    fn savedb_snapshots() {
+        let folder = "tests/tback-tmp/kv-snapshots";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+        let filename = format!("{}/files.json", folder);
+
+        let mut client = KeyValueStore::init(PathBuf::from("tests/data/files.json")).unwrap();
+        client.snapshots = 2;
+
+        // the first save has no previous dbfile to keep
+        client.savedb(&filename).unwrap();
+        assert!(KeyValueStore::snapshot_files(Path::new(&filename)).is_empty());
+
+        // later saves in the same pass keep nothing more
+        client.savedb(&filename).unwrap();
+        assert!(KeyValueStore::snapshot_files(Path::new(&filename)).is_empty());
+
+        for _ in 0..3 {
+            client.start_pass();
+            client.savedb(&filename).unwrap();
+        }
+        let snapshots = KeyValueStore::snapshot_files(Path::new(&filename));
+        assert_eq!(snapshots.len(), 2);
+        assert!(!Path::new(&format!(
+            "{}{}",
+            filename,
+            crate::backup_process::TEMP_EXTENSION
+        ))
+        .exists());
+
+        let saved = KeyValueStore::init(PathBuf::from(&filename)).unwrap();
+        assert_eq!(saved.dbsize(), client.dbsize());
+        assert!(!saved.is_dirty());
+    }
+
+    #[test]
+    fn init_from_snapshot() {
+        let folder = "tests/tback-tmp/kv-fallback";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+        let filename = format!("{}/files.json", folder);
+
+        let mut client = KeyValueStore::init(PathBuf::from("tests/data/files.json")).unwrap();
+        client.savedb(&filename).unwrap();
+        client.start_pass();
+        client.savedb(&filename).unwrap();
+
+        // a truncated dbfile and a newer, also broken, snapshot fall back to the valid snapshot
+        fs::write(&filename, "[ { \"key\": ").unwrap();
+        fs::write(format!("{}.99991231T000000.000000Z", filename), "{").unwrap();
+
+        let mut restored = KeyValueStore::init(PathBuf::from(&filename)).unwrap();
+        assert_eq!(restored.dbsize(), client.dbsize());
+        assert!(restored.is_dirty());
+        assert!(restored.find("./tests/big-file.pdf").is_some());
+
+        // the broken dbfile is not kept as a snapshot
+        let count = KeyValueStore::snapshot_files(Path::new(&filename)).len();
+        restored.savedb(&filename).unwrap();
+        assert_eq!(
+            KeyValueStore::snapshot_files(Path::new(&filename)).len(),
+            count
+        );
+    }
+
     #[test]
     fn find() {
         let filename = "tests/data/files.json";
@@ -192,6 +433,85 @@ mod tests {
         assert_eq!(client.dbsize(), count);
     }
 
+    #[test]
+    fn set_updates_index() {
+        let filename = "tests/data/files.json";
+        let mut client = KeyValueStore::init(PathBuf::from(filename)).unwrap();
+        let count = client.dbsize();
+
+        // a new record for a tracked path replaces the old one
+        let model = FileModel::new("./tests/big-file.pdf");
+        client.set(model.clone()).unwrap();
+        assert_eq!(client.dbsize(), count);
+        assert_eq!(client.find("./tests/big-file.pdf").unwrap().key, model.key);
+        assert!(client.get("Npeu7mr2B2ua25Sn").is_none());
+
+        // a new path is indexed
+        let model = FileModel::new("./tests/file3.txt");
+        client.set(model.clone()).unwrap();
+        assert_eq!(client.dbsize(), count + 1);
+        assert_eq!(client.find("./tests/file3.txt").unwrap().key, model.key);
+
+        // moving a record drops the old path from the index
+        let mut moved = model.clone();
+        moved.path = PathBuf::from("./tests/file4.txt");
+        client.set(moved).unwrap();
+        assert_eq!(client.dbsize(), count + 1);
+        assert!(client.find("./tests/file3.txt").is_none());
+        assert_eq!(client.find("./tests/file4.txt").unwrap().key, model.key);
+    }
+
+    #[test]
+    fn update() {
+        let filename = "tests/data/saved.json";
+        let mut client = KeyValueStore::init(PathBuf::from(filename)).unwrap();
+        let existing = client.find("./tests/file1.txt").unwrap().clone();
+
+        let mut model = client.identify(FileModel::new("./tests/file1.txt"));
+        model.written_to = HashSet::from(["tests/other/file1.txt".to_string()]);
+        model.last_saved = Some(chrono::Utc::now().naive_utc());
+        client.update(model.clone()).unwrap();
+
+        let updated = client.find("./tests/file1.txt").unwrap();
+        assert_eq!(updated.last_saved, model.last_saved);
+        assert_eq!(updated.written_to.len(), existing.written_to.len() + 1);
+
+        // a record with another key replaces the existing one
+        let model = FileModel::new("./tests/file1.txt");
+        client.update(model.clone()).unwrap();
+        assert_eq!(client.find("./tests/file1.txt").unwrap(), &model);
+    }
+
+    #[test]
+    fn identify() {
+        let filename = "tests/data/saved.json";
+        let client = KeyValueStore::init(PathBuf::from(filename)).unwrap();
+
+        let model = FileModel::new("./tests/file1.txt");
+        let model = client.identify(model);
+        assert_eq!(model.key, "4LWn7mr28UxySwNG");
+        assert!(model.last_saved.is_some());
+
+        let model = FileModel::new("./tests/file3.txt");
+        let key = model.key.clone();
+        let model = client.identify(model);
+        assert_eq!(model.key, key);
+        assert!(model.last_saved.is_none());
+    }
+
+    #[test]
+    fn init_merges_duplicates() {
+        let filename = "tests/data/duplicates.json";
+        let client = KeyValueStore::init(PathBuf::from(filename)).unwrap();
+
+        assert_eq!(client.dbsize(), 2);
+        assert!(client.is_dirty());
+
+        let model = client.find("./tests/file1.txt").unwrap();
+        assert_eq!(model.key, "Norm7msl0dQLwUQi");
+        assert_eq!(model.written_to.len(), 2);
+    }
+
     #[test]
     fn init() {
         let filename = "tests/data/files.json";
@@ -1,10 +1,25 @@
 #![doc = include_str!("../README.md")]
 
+pub mod attributes;
 pub mod backup_process;
+pub mod compression;
 pub mod config;
+pub mod encryption;
+pub mod fan_out;
 pub mod file_model;
 pub mod file_walker;
+pub mod file_watcher;
 pub mod kv_store;
+pub mod path_filter;
+pub mod repository;
+pub mod restore_process;
+pub mod run_report;
+pub mod s3;
+pub mod sftp;
+#[cfg(test)]
+mod stand_in;
+pub mod target;
+pub mod webdav;
 
 /// The current version as read from the cargo toml file
 ///
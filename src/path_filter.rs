@@ -0,0 +1,281 @@
+/// Path Filter - gitignore style exclude and include patterns
+///
+/// # Path Filter
+///
+/// each pattern is a glob matched against whole path components, so `cache` matches a `cache` folder or file
+/// anywhere but not `my-cache-notes.md`.  a pattern matches at any depth unless it starts with `/`, which anchors it
+/// to the folder the pattern belongs to.  a match on a folder applies to everything inside it.  patterns prefixed
+/// with `!` re-include what an earlier pattern excluded and the last matching pattern wins.  entries prefixed with
+/// `re:` are regular expressions matched against the full path.
+///
+/// `.replicaignore` files found in walked folders add patterns, one per line with `#` comments, for the paths
+/// below them.
+///
+use anyhow::{anyhow, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use hashbrown::HashMap;
+use log::{debug, warn};
+use regex::Regex;
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// the name of the per folder ignore file
+pub const IGNORE_FILE: &str = ".replicaignore";
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Glob(GlobMatcher, GlobMatcher),
+    Regex(Regex),
+}
+
+/// a single exclude or include pattern
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: String,
+    matcher: Matcher,
+    negate: bool,
+}
+
+impl Rule {
+    /// parse the pattern; base anchors patterns that start with / and limits where an ignore file's patterns apply
+    pub fn parse(pattern: &str, base: Option<&Path>) -> Result<Rule> {
+        let (negate, text) = match pattern.strip_prefix('!') {
+            Some(text) => (true, text),
+            None => (false, pattern),
+        };
+
+        let matcher = if let Some(re) = text.strip_prefix("re:") {
+            Matcher::Regex(Regex::new(re)?)
+        } else {
+            let text = text.trim_end_matches('/');
+            if text.is_empty() {
+                return Err(anyhow!("empty pattern: {}", pattern));
+            }
+
+            // the folder's name is literal, e.g. photos[2024] is not a character class
+            let base = base.map(|base| globset::escape(&base.to_string_lossy()));
+            let glob = match (text.strip_prefix('/'), base) {
+                (Some(anchored), Some(base)) => format!("{}/{}", base, anchored),
+                (Some(anchored), None) => format!("/{}", anchored),
+                (None, Some(base)) => format!("{}/**/{}", base, text),
+                (None, None) if text.starts_with("**/") => text.to_string(),
+                (None, None) => format!("**/{}", text),
+            };
+
+            let build = |glob: &str| -> Result<GlobMatcher> {
+                Ok(GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()?
+                    .compile_matcher())
+            };
+
+            Matcher::Glob(build(&glob)?, build(&format!("{}/**", glob))?)
+        };
+
+        Ok(Rule {
+            pattern: pattern.to_string(),
+            matcher,
+            negate,
+        })
+    }
+
+    /// return true if the path, or one of its parent folders, matches
+    pub fn is_match(&self, path: &Path) -> bool {
+        match &self.matcher {
+            Matcher::Glob(glob, inside) => glob.is_match(path) || inside.is_match(path),
+            Matcher::Regex(re) => re.is_match(path.to_str().unwrap_or("")),
+        }
+    }
+}
+
+/// parse the list of patterns, logging and skipping any that are invalid
+fn parse_rules<S: AsRef<str>>(patterns: &[S], base: Option<&Path>) -> Vec<Rule> {
+    patterns
+        .iter()
+        .map(|pattern| pattern.as_ref().trim())
+        .filter(|pattern| !pattern.is_empty() && !pattern.starts_with('#'))
+        .filter_map(|pattern| match Rule::parse(pattern, base) {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                warn!("skip bad pattern {}: {}", pattern, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// return the verdict of the last matching rule: Some(true) for excluded, Some(false) for re-included
+fn last_match(rules: &[Rule], path: &Path) -> Option<bool> {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.is_match(path))
+        .map(|rule| {
+            debug!("{} matches {}", rule.pattern, path.display());
+            !rule.negate
+        })
+}
+
+#[derive(Debug, Default)]
+pub struct PathFilter {
+    excludes: Vec<Rule>,
+    includes: Vec<Rule>,
+    ignore_files: RefCell<HashMap<PathBuf, Vec<Rule>>>,
+}
+
+impl PathFilter {
+    /// create the filter from the config's exclude and include patterns
+    pub fn new<S: AsRef<str>>(excludes: &[S], includes: &[S]) -> PathFilter {
+        PathFilter {
+            excludes: parse_rules(excludes, None),
+            includes: parse_rules(includes, None),
+            ignore_files: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// return true if the path is excluded by the config patterns or an ignore file in the folders from root down
+    pub fn is_excluded(&self, path: &Path, root: Option<&Path>) -> bool {
+        let path = normalize(path);
+        let mut excluded = last_match(&self.excludes, &path).unwrap_or(false);
+
+        if let Some(root) = root {
+            let root = normalize(root);
+            // deeper ignore files take precedence, so apply them from the root down
+            let mut folders: Vec<&Path> = path
+                .ancestors()
+                .skip(1)
+                .take_while(|folder| folder.starts_with(&root))
+                .collect();
+            folders.reverse();
+
+            for folder in folders {
+                if let Some(verdict) = self.ignore_file_match(folder, &path) {
+                    excluded = verdict;
+                }
+            }
+        }
+
+        excluded
+    }
+
+    /// return true if there are no include patterns or the file matches one of them
+    pub fn is_included(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        self.includes.is_empty() || self.includes.iter().any(|rule| rule.is_match(&path))
+    }
+
+    /// apply the folder's ignore file, read once and cached
+    fn ignore_file_match(&self, folder: &Path, path: &Path) -> Option<bool> {
+        let mut cache = self.ignore_files.borrow_mut();
+        let rules = cache.entry(folder.to_path_buf()).or_insert_with(|| {
+            match fs::read_to_string(folder.join(IGNORE_FILE)) {
+                Ok(text) => {
+                    let lines: Vec<&str> = text.lines().collect();
+                    parse_rules(&lines, Some(folder))
+                }
+                Err(_) => vec![],
+            }
+        });
+
+        last_match(rules, path)
+    }
+}
+
+/// remove any trailing slash and . components so paths and patterns line up
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, std::path::Component::CurDir))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components() {
+        let filter = PathFilter::new(&["cache"], &[]);
+
+        assert!(filter.is_excluded(Path::new("/home/dpw/.cache/x/cache"), None));
+        assert!(filter.is_excluded(Path::new("/home/dpw/cache/thing.txt"), None));
+        assert!(!filter.is_excluded(Path::new("/home/dpw/notes/my-cache-notes.md"), None));
+    }
+
+    #[test]
+    fn globs_and_negation() {
+        let filter = PathFilter::new(&["**/*.tmp", "!important.tmp", ".config/chromium/"], &[]);
+
+        assert!(filter.is_excluded(Path::new("./a/b/scratch.tmp"), None));
+        assert!(!filter.is_excluded(Path::new("./a/b/important.tmp"), None));
+        assert!(!filter.is_excluded(Path::new("./a/b/scratch.tmp.txt"), None));
+        assert!(filter.is_excluded(Path::new("/home/dpw/.config/chromium/Default/x"), None));
+        assert!(!filter.is_excluded(Path::new("/home/dpw/.config/chromium-notes"), None));
+    }
+
+    #[test]
+    fn anchored_and_regex() {
+        let filter = PathFilter::new(&["/tmp/cache", r"re:\.bak$", "[bad"], &[]);
+
+        assert!(filter.is_excluded(Path::new("/tmp/cache/x"), None));
+        assert!(!filter.is_excluded(Path::new("/home/tmp/cache/x"), None));
+        assert!(filter.is_excluded(Path::new("./notes/todo.md.bak"), None));
+        assert!(!filter.is_excluded(Path::new("./notes/todo.bak.md"), None));
+    }
+
+    #[test]
+    fn includes() {
+        let filter = PathFilter::new(&["drafts"], &["*.md", "*.toml"]);
+
+        assert!(filter.is_included(Path::new("./notes/todo.md")));
+        assert!(!filter.is_included(Path::new("./notes/todo.txt")));
+
+        let filter = PathFilter::new::<&str>(&[], &[]);
+        assert!(filter.is_included(Path::new("./notes/todo.txt")));
+    }
+
+    #[test]
+    fn ignore_files() {
+        let root = Path::new("tests/tback-tmp/ignore");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "# top level\n*.log\n/build\n").unwrap();
+        fs::write(root.join("sub").join(IGNORE_FILE), "!keep.log\n").unwrap();
+
+        let filter = PathFilter::new::<&str>(&[], &[]);
+        let root = Some(root);
+        let excluded = |path: &str| filter.is_excluded(Path::new(path), root);
+
+        assert!(excluded("tests/tback-tmp/ignore/app.log"));
+        assert!(excluded("tests/tback-tmp/ignore/sub/deeper/app.log"));
+        assert!(!excluded("tests/tback-tmp/ignore/sub/keep.log"));
+        assert!(excluded("tests/tback-tmp/ignore/build/out.txt"));
+        assert!(!excluded("tests/tback-tmp/ignore/sub/build/out.txt"));
+        assert!(!excluded("tests/tback-tmp/ignore/app.txt"));
+
+        // ignore files only apply below their folder
+        assert!(!filter.is_excluded(Path::new("tests/other/app.log"), None));
+    }
+
+    #[test]
+    fn ignore_file_glob_folder() {
+        let root = Path::new("tests/tback-tmp/ignore-glob");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("photos[2024]")).unwrap();
+        fs::write(
+            root.join("photos[2024]").join(IGNORE_FILE),
+            "*.log\n/build\n",
+        )
+        .unwrap();
+
+        let filter = PathFilter::new::<&str>(&[], &[]);
+        let excluded = |path: &str| filter.is_excluded(Path::new(path), Some(root));
+
+        // the folder's name is matched literally, not as a character class
+        assert!(excluded("tests/tback-tmp/ignore-glob/photos[2024]/a.log"));
+        assert!(excluded(
+            "tests/tback-tmp/ignore-glob/photos[2024]/build/x.jpg"
+        ));
+        assert!(!excluded("tests/tback-tmp/ignore-glob/photos[2024]/a.jpg"));
+    }
+}
@@ -0,0 +1,966 @@
+/// Repository - a deduplicating store of content defined chunks and per-run snapshots
+///
+/// # Repository
+///
+/// with `format = "repository"` a target holds chunks rather than copies.  each file is split where a rolling gear
+/// hash of its content matches a mask, so an edit only changes the chunks around it, and each chunk is stored once as
+/// `chunks/ab/<sha-256>`, gzipped and/or encrypted as configured; encrypted chunks are named by a hash keyed with the
+/// encryption key, so their names don't show what they hold.  a chunk's first byte records whether it is gzipped, so
+/// the name only depends on the content.  every run that changes the backup writes a snapshot,
+/// `snapshots/<stamp>.json`, listing the chunks of each file in the run; a file that is unchanged since the previous
+/// snapshot is carried over without being read.  the same content in two files, or a renamed file, costs no more
+/// than its entry in the snapshot.  the oldest snapshots beyond the number to keep are removed with the chunks only
+/// they used.  `repository.json` marks the target as a repository and records the chunk sizes.
+///
+use crate::attributes::FileAttributes;
+use crate::compression;
+use crate::encryption::{self, DecryptReader, EncryptWriter, Key, SALT_LEN};
+use crate::file_model::FileModel;
+use crate::target::{self, write_atomic, SourceMeta, Target};
+use anyhow::{anyhow, Result};
+use chrono::naive::NaiveDateTime;
+use chrono::Utc;
+use log::{error, info, warn};
+use openssl::sha;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// the folder the chunks are stored in, below a folder named for the first two characters of their hash
+pub const CHUNKS: &str = "chunks";
+
+/// the folder of the snapshots written by each run
+pub const SNAPSHOTS: &str = "snapshots";
+
+/// the file that marks a target as a repository
+pub const MARKER: &str = "repository.json";
+
+/// the version of the repository layout written to the marker
+const FORMAT_VERSION: u32 = 1;
+
+/// the first byte of a chunk, before it is encrypted: the rest is the content as is, or gzipped
+const CHUNK_PLAIN: u8 = 0;
+const CHUNK_GZIP: u8 = 1;
+
+/// the random values mixed into the rolling hash for each byte
+const GEAR: [u64; 256] = gear_table();
+
+/// fill the gear table from a splitmix64 sequence so every repository cuts the same content at the same places
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut idx = 0;
+    while idx < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[idx] = z ^ (z >> 31);
+        idx += 1;
+    }
+
+    table
+}
+
+/// splits content into chunks of min to max bytes, cut where the content matches so the cuts move with an insert
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Chunker {
+    pub min: usize,
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl Default for Chunker {
+    fn default() -> Chunker {
+        Chunker::new(256 * 1024, 1024 * 1024, 4 * 1024 * 1024)
+    }
+}
+
+impl Chunker {
+    pub fn new(min: usize, avg: usize, max: usize) -> Chunker {
+        Chunker { min, avg, max }
+    }
+
+    /// the high bits of the hash that must be zero for a cut; the mean distance past min is about avg - min
+    fn mask(&self) -> u64 {
+        let bits = self.avg.saturating_sub(self.min).max(2).ilog2();
+        !0u64 << (64 - bits)
+    }
+
+    /// return the length of the first chunk of the data; all of it if no cut is found before max
+    pub fn cut(&self, data: &[u8]) -> usize {
+        let end = data.len().min(self.max);
+        if end <= self.min {
+            return end;
+        }
+
+        let mask = self.mask();
+        let mut hash = 0u64;
+        for (idx, byte) in data.iter().enumerate().take(end).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & mask == 0 {
+                return idx + 1;
+            }
+        }
+
+        end
+    }
+
+    /// read the reader to the end, passing each chunk to emit; an empty reader has no chunks
+    pub fn split<R: Read, F: FnMut(&[u8]) -> Result<()>>(
+        &self,
+        reader: &mut R,
+        mut emit: F,
+    ) -> Result<()> {
+        let mut buf: Vec<u8> = Vec::with_capacity(self.max);
+        let mut eof = false;
+
+        loop {
+            while !eof && buf.len() < self.max {
+                let start = buf.len();
+                buf.resize(self.max, 0);
+                let count = match reader.read(&mut buf[start..]) {
+                    Ok(count) => count,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                    Err(e) => return Err(e.into()),
+                };
+                buf.truncate(start + count);
+                eof = count == 0;
+            }
+
+            if buf.is_empty() {
+                return Ok(());
+            }
+
+            let cut = self.cut(&buf);
+            emit(&buf[..cut])?;
+            buf.drain(..cut);
+        }
+    }
+}
+
+/// a file in a snapshot: the source's size, modified time and hash, and the names of its chunks in order
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SnapshotFile {
+    pub len: u64,
+    pub modified: u64,
+    pub hash: String,
+    pub chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<FileAttributes>,
+}
+
+/// the files in the repository after a run, by their path relative to home
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Snapshot {
+    pub created: Option<NaiveDateTime>,
+    pub files: BTreeMap<String, SnapshotFile>,
+}
+
+/// the contents of the marker file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Info {
+    version: u32,
+    chunker: Chunker,
+    /// the salt of the key that names and encrypts the chunks, in hex
+    salt: String,
+}
+
+/// the chunks and snapshots on a target; a backup run adds its files with write_file then saves its snapshot
+pub struct Repository {
+    pub backend: Arc<dyn Target>,
+    pub chunker: Chunker,
+    pub compress: bool,
+    pub key: Option<Key>,
+    /// the name of the snapshot this run writes, e.g. 20261018100000123456.json
+    pub name: String,
+    /// the latest snapshot when the repository was opened
+    pub previous: Snapshot,
+    /// the salt a passphrase key is derived with, so every run names the same content the same
+    salt: [u8; SALT_LEN],
+    known: Mutex<HashSet<String>>,
+    /// the chunks a worker is storing; another worker with the same chunk waits for it rather than writing it too
+    storing: Mutex<HashSet<String>>,
+    stored: Condvar,
+    files: Mutex<BTreeMap<String, SnapshotFile>>,
+    snapshots: Mutex<HashMap<String, Arc<Snapshot>>>,
+}
+
+/// return true if the target is marked as a repository
+pub fn is_repository(backend: &dyn Target) -> bool {
+    matches!(backend.stat(MARKER), Ok(Some(_)))
+}
+
+/// return the path of the chunk on the target
+pub fn chunk_path(name: &str) -> String {
+    let prefix = name.get(..2).unwrap_or(name);
+    format!("{}/{}/{}", CHUNKS, prefix, name)
+}
+
+impl Repository {
+    /// open the repository on the target, reading the chunk sizes and latest snapshot; a target without the marker
+    /// is a new repository, written when the first snapshot is saved
+    pub fn open(backend: Arc<dyn Target>) -> Result<Repository> {
+        let mut repository = Repository {
+            backend,
+            chunker: Chunker::default(),
+            compress: false,
+            key: None,
+            name: format!("{}.json", Utc::now().format("%Y%m%d%H%M%S%6f")),
+            previous: Snapshot::default(),
+            salt: encryption::new_salt()?,
+            known: Mutex::new(HashSet::new()),
+            storing: Mutex::new(HashSet::new()),
+            stored: Condvar::new(),
+            files: Mutex::new(BTreeMap::new()),
+            snapshots: Mutex::new(HashMap::new()),
+        };
+
+        let Some(info) = repository.read_json::<Info>(MARKER)? else {
+            info!("new repository on {:?}", repository.backend);
+            return Ok(repository);
+        };
+        if info.version != FORMAT_VERSION {
+            let msg = format!("unknown repository format version: {}", info.version);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+        repository.chunker = info.chunker;
+        let bytes = hex::decode(&info.salt)
+            .ok()
+            .filter(|bytes| bytes.len() == SALT_LEN);
+        let Some(bytes) = bytes else {
+            let msg = format!("bad repository salt: {}", info.salt);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        };
+        repository.salt.copy_from_slice(&bytes);
+
+        if let Some(latest) = repository.snapshots()?.pop() {
+            let previous = repository.snapshot(&latest)?;
+            let known: HashSet<String> = previous
+                .files
+                .values()
+                .flat_map(|file| file.chunks.iter().cloned())
+                .collect();
+            info!(
+                "repository snapshot {}: {} files, {} chunks",
+                latest,
+                previous.files.len(),
+                known.len()
+            );
+
+            repository.known = Mutex::new(known);
+            repository.previous = previous.as_ref().clone();
+        }
+
+        Ok(repository)
+    }
+
+    /// return the names of the snapshots, oldest first
+    pub fn snapshots(&self) -> Result<Vec<String>> {
+        let folder = format!("{}/", SNAPSHOTS);
+        let mut names: Vec<String> = self
+            .backend
+            .list(&folder)?
+            .iter()
+            .filter_map(|path| path.strip_prefix(folder.as_str()))
+            .filter(|name| name.ends_with(".json"))
+            .map(|name| name.to_string())
+            .collect();
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// read the snapshot with the name
+    pub fn snapshot(&self, name: &str) -> Result<Arc<Snapshot>> {
+        if let Some(snapshot) = self.snapshots.lock().unwrap().get(name) {
+            return Ok(Arc::clone(snapshot));
+        }
+
+        let path = target::join(SNAPSHOTS, name);
+        let Some(snapshot) = self.read_json::<Snapshot>(&path)? else {
+            let msg = format!("snapshot {} is missing", name);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        };
+
+        let snapshot = Arc::new(snapshot);
+        self.snapshots
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), Arc::clone(&snapshot));
+
+        Ok(snapshot)
+    }
+
+    /// split the source into chunks, store the ones the repository doesn't have and add the file to this run's
+    /// snapshot; return the snapshot's entry
+    pub fn write_file(
+        &self,
+        model: &FileModel,
+        attributes: Option<FileAttributes>,
+    ) -> Result<SnapshotFile> {
+        let mut reader = BufReader::new(File::open(&model.path)?);
+        let mut hasher = sha::Sha256::new();
+        let mut file = SnapshotFile {
+            modified: model.modified,
+            attributes,
+            ..SnapshotFile::default()
+        };
+
+        self.chunker.split(&mut reader, |data| {
+            hasher.update(data);
+            file.len += data.len() as u64;
+            file.chunks.push(self.put_chunk(model, data)?);
+            Ok(())
+        })?;
+        file.hash = hex::encode(hasher.finish());
+
+        self.files
+            .lock()
+            .unwrap()
+            .insert(model.relative_path(), file.clone());
+
+        Ok(file)
+    }
+
+    /// store the chunk unless it is known or already on the target; return its name
+    fn put_chunk(&self, model: &FileModel, data: &[u8]) -> Result<String> {
+        let key = self.salted(self.key.as_ref())?;
+        let hash = match &key {
+            Some(key) => key.keyed_hash(data)?,
+            None => model.calc_hash(data),
+        };
+        let compressed = self.compress && !compression::is_compressed_format(&model.path);
+        let name = hash.clone();
+
+        if self.known.lock().unwrap().contains(&name) {
+            return Ok(name);
+        }
+
+        // reserve the name so two workers with the same new chunk don't write it at once
+        let mut storing = self.storing.lock().unwrap();
+        while storing.contains(&name) {
+            storing = self.stored.wait(storing).unwrap();
+        }
+        if self.known.lock().unwrap().contains(&name) {
+            return Ok(name);
+        }
+        storing.insert(name.clone());
+        drop(storing);
+
+        let resp = self.store_chunk(&name, hash, data, compressed, key.as_ref());
+        if resp.is_ok() {
+            self.known.lock().unwrap().insert(name.clone());
+        }
+        self.storing.lock().unwrap().remove(&name);
+        self.stored.notify_all();
+        resp?;
+
+        Ok(name)
+    }
+
+    /// write the chunk to the target unless it is already there, e.g. written by a run that failed before its
+    /// snapshot
+    fn store_chunk(
+        &self,
+        name: &str,
+        hash: String,
+        data: &[u8],
+        compressed: bool,
+        key: Option<&Key>,
+    ) -> Result<()> {
+        let path = chunk_path(name);
+        if self.backend.stat(&path)?.is_some() {
+            return Ok(());
+        }
+
+        let source = SourceMeta {
+            len: data.len() as u64,
+            modified: 0,
+            hash,
+        };
+        let stage = target::stage_path();
+        let resp = write_chunk(&stage, data, compressed, key)
+            .and_then(|_| self.backend.put(&stage, &path, &source));
+        let _ = fs::remove_file(&stage);
+
+        resp
+    }
+
+    /// add the file's entry from the previous snapshot to this run's, as it is unchanged or could not be read; return
+    /// false if the previous snapshot doesn't have the file
+    pub fn carry(&self, path: &str) -> bool {
+        match self.previous.files.get(path) {
+            Some(file) => {
+                self.files
+                    .lock()
+                    .unwrap()
+                    .insert(path.to_string(), file.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// write this run's snapshot and the marker; a complete run's snapshot has only the files written or carried by
+    /// the run, so files no longer in the source are dropped, else the previous snapshot's files are kept too.  return
+    /// the snapshot's name, or None if it would be the same as the previous snapshot
+    pub fn save(&self, complete: bool) -> Result<Option<String>> {
+        let files = self.files.lock().unwrap();
+
+        let mut snapshot = if complete {
+            Snapshot::default()
+        } else {
+            self.previous.clone()
+        };
+        for (path, file) in files.iter() {
+            snapshot.files.insert(path.clone(), file.clone());
+        }
+        if snapshot.files == self.previous.files {
+            return Ok(None);
+        }
+        snapshot.created = Some(Utc::now().naive_utc());
+
+        let info = Info {
+            version: FORMAT_VERSION,
+            chunker: self.chunker,
+            salt: hex::encode(self.salt),
+        };
+
+        // the marker is written last so a repository with a marker always has a snapshot
+        let resp = self
+            .write_json(&target::join(SNAPSHOTS, &self.name), &snapshot)
+            .and_then(|_| self.write_json(MARKER, &info));
+        if let Err(e) = resp {
+            let msg = format!("error saving snapshot {}: {:#}", self.name, e);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+
+        info!(
+            "saved snapshot {}: {} files",
+            self.name,
+            snapshot.files.len()
+        );
+
+        Ok(Some(self.name.clone()))
+    }
+
+    /// restore the file at the path relative to home from the snapshot to dest, checking each chunk and the whole
+    /// file against their hashes; the key decrypts encrypted chunks
+    pub fn restore_file(
+        &self,
+        name: &str,
+        path: &str,
+        dest: &Path,
+        key: Option<&Key>,
+    ) -> Result<()> {
+        let snapshot = self.snapshot(name)?;
+        let Some(file) = snapshot.files.get(path) else {
+            let msg = format!("{} is not in snapshot {}", path, name);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        };
+
+        write_atomic(dest, |temp| {
+            let mut writer = BufWriter::new(File::create(temp)?);
+            let mut hasher = sha::Sha256::new();
+            for name in file.chunks.iter() {
+                let data = self.read_chunk(name, key)?;
+                hasher.update(&data);
+                writer.write_all(&data)?;
+            }
+
+            let hash = hex::encode(hasher.finish());
+            if hash != file.hash {
+                return Err(anyhow!("content hash {} should be {}", hash, file.hash));
+            }
+
+            writer.into_inner()?.sync_all()?;
+            Ok(())
+        })
+    }
+
+    /// download the chunk, decrypt and decompress it and check it against its name
+    fn read_chunk(&self, name: &str, key: Option<&Key>) -> Result<Vec<u8>> {
+        let stage = target::stage_path();
+        let resp = self
+            .backend
+            .get(&chunk_path(name), &stage)
+            .and_then(|_| read_chunk_file(&stage, key));
+        let _ = fs::remove_file(&stage);
+
+        // without a key, read_chunk_file has already failed an encrypted chunk
+        let (data, encrypted) = resp?;
+        let hash = match self.salted(key.filter(|_| encrypted))? {
+            Some(key) => key.keyed_hash(&data)?,
+            None => FileModel::default().calc_hash(&data),
+        };
+        if name != hash {
+            let msg = format!("chunk {} does not match its hash", name);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+
+        Ok(data)
+    }
+
+    /// return the key derived with the repository's salt
+    fn salted(&self, key: Option<&Key>) -> Result<Option<Key>> {
+        key.map(|key| key.with_salt(&self.salt)).transpose()
+    }
+
+    /// remove the oldest snapshots beyond the number to keep, then the chunks that no remaining snapshot uses; return
+    /// the number of snapshots and chunks removed
+    pub fn prune(&self, keep: usize) -> Result<(usize, usize)> {
+        let names = self.snapshots()?;
+        if keep == 0 || names.len() <= keep {
+            return Ok((0, 0));
+        }
+
+        // read every kept snapshot before anything is removed
+        let (old, kept) = names.split_at(names.len() - keep);
+        let mut used: HashSet<String> = HashSet::new();
+        for name in kept {
+            let snapshot = self.snapshot(name)?;
+            used.extend(
+                snapshot
+                    .files
+                    .values()
+                    .flat_map(|file| file.chunks.iter().cloned()),
+            );
+        }
+
+        for name in old {
+            self.backend.delete(&target::join(SNAPSHOTS, name))?;
+            info!("removed snapshot {}", name);
+        }
+
+        let mut removed = 0;
+        for folder in self.backend.list(&format!("{}/", CHUNKS))? {
+            if !folder.ends_with('/') {
+                continue;
+            }
+
+            for path in self.backend.list(&folder)? {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                if !path.ends_with('/') && !used.contains(name) {
+                    self.backend.delete(&path)?;
+                    removed += 1;
+                }
+            }
+        }
+        info!("removed {} unused chunks", removed);
+
+        Ok((old.len(), removed))
+    }
+
+    /// read the json file on the target, or None if there is no file
+    fn read_json<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>> {
+        if self.backend.stat(path)?.is_none() {
+            return Ok(None);
+        }
+
+        let stage = target::stage_path();
+        let resp = self
+            .backend
+            .get(path, &stage)
+            .and_then(|_| Ok(fs::read(&stage)?));
+        let _ = fs::remove_file(&stage);
+
+        match serde_json::from_slice(&resp?) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                let msg = format!("could not parse {}: {}", path, e);
+                warn!("{}", msg);
+                Err(anyhow!("{}", msg))
+            }
+        }
+    }
+
+    /// write the value as json to the path on the target
+    fn write_json<T: Serialize>(&self, path: &str, value: &T) -> Result<()> {
+        let stage = target::stage_path();
+        let resp = fs::write(&stage, serde_json::to_vec(value)?)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| self.backend.put(&stage, path, &SourceMeta::default()));
+        let _ = fs::remove_file(&stage);
+
+        resp
+    }
+}
+
+/// write the chunk to the local file, gzipped and/or encrypted
+fn write_chunk(dest: &Path, data: &[u8], compressed: bool, key: Option<&Key>) -> Result<()> {
+    let writer = BufWriter::new(File::create(dest)?);
+
+    let writer = match key {
+        Some(key) => write_data(data, EncryptWriter::new(writer, key)?, compressed)?.finish()?,
+        None => write_data(data, writer, compressed)?,
+    };
+    writer.into_inner()?.sync_all()?;
+
+    Ok(())
+}
+
+/// write the chunk's first byte and the data, compressing if requested; return the writer
+fn write_data<W: Write>(data: &[u8], mut writer: W, compressed: bool) -> Result<W> {
+    if compressed {
+        writer.write_all(&[CHUNK_GZIP])?;
+        compression::compress(&mut &data[..], writer)
+    } else {
+        writer.write_all(&[CHUNK_PLAIN])?;
+        writer.write_all(data)?;
+        Ok(writer)
+    }
+}
+
+/// read the chunk's local file, decrypting it if it has an encryption header and decompressing as its first byte
+/// shows; return the data and true if the chunk was encrypted
+fn read_chunk_file(src: &Path, key: Option<&Key>) -> Result<(Vec<u8>, bool)> {
+    let content = fs::read(src)?;
+    let encrypted = encryption::is_encrypted(&content);
+    let mut reader: Box<dyn Read + '_> = Box::new(&content[..]);
+
+    if encrypted {
+        let Some(key) = key else {
+            return Err(anyhow!("the chunk is encrypted but no key is configured"));
+        };
+        reader = Box::new(DecryptReader::new(reader, content.len() as u64, key)?);
+    }
+
+    let mut first = [0u8; 1];
+    reader.read_exact(&mut first)?;
+    match first[0] {
+        CHUNK_PLAIN => (),
+        CHUNK_GZIP => reader = Box::new(compression::decompressor(reader)),
+        other => return Err(anyhow!("unknown chunk format: {}", other)),
+    }
+
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+
+    Ok((data, encrypted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::LocalTarget;
+
+    /// return len bytes of repeatable noise
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn chunks(chunker: &Chunker, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = vec![];
+        chunker
+            .split(&mut &data[..], |chunk| {
+                chunks.push(chunk.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        chunks
+    }
+
+    fn open(folder: &str) -> Repository {
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+        Repository::open(Arc::new(LocalTarget::new(folder))).unwrap()
+    }
+
+    fn chunk_count(folder: &str) -> usize {
+        fs::read_dir(format!("{}/{}", folder, CHUNKS))
+            .map(|dirs| {
+                dirs.map(|dir| fs::read_dir(dir.unwrap().path()).unwrap().count())
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn split() {
+        let chunker = Chunker::new(2 * 1024, 8 * 1024, 32 * 1024);
+        let data = noise(500 * 1024, 1);
+
+        let split = chunks(&chunker, &data);
+        assert!(split.len() > 10);
+        assert_eq!(split.concat(), data);
+        for chunk in split[..split.len() - 1].iter() {
+            assert!(chunk.len() >= chunker.min && chunk.len() <= chunker.max);
+        }
+
+        // an insert near the start only changes the chunks around it
+        let mut edited = data.clone();
+        edited.splice(1000..1000, noise(100, 2));
+        let edited = chunks(&chunker, &edited);
+        let same = edited.iter().filter(|chunk| split.contains(chunk)).count();
+        assert!(same >= split.len() - 2);
+
+        assert!(chunks(&chunker, &[]).is_empty());
+        assert_eq!(chunks(&chunker, &[7u8; 100]), vec![vec![7u8; 100]]);
+        assert_eq!(chunks(&chunker, &[0u8; 70 * 1024]).len(), 3);
+    }
+
+    #[test]
+    fn write_and_restore() {
+        let folder = "tests/tback-tmp/repository";
+        let mut repository = open(folder);
+        repository.chunker = Chunker::new(16 * 1024, 64 * 1024, 256 * 1024);
+        repository.compress = true;
+        repository.key = Some(Key::from_bytes([9u8; 32]));
+        assert!(!is_repository(repository.backend.as_ref()));
+
+        let model = FileModel::new("./tests/big-file.pdf")
+            .read_metadata()
+            .unwrap();
+        let file = repository.write_file(&model, None).unwrap();
+        assert_eq!(file.len, model.len);
+        assert_eq!(file.hash, model.hash_file().unwrap());
+        assert!(file.chunks.len() > 1);
+        assert!(file.chunks.iter().all(|name| !name.contains('.')));
+        let name = repository.save(true).unwrap().unwrap();
+
+        // reopened, the snapshot and chunk sizes are read back
+        let repository = Repository::open(Arc::clone(&repository.backend)).unwrap();
+        assert!(is_repository(repository.backend.as_ref()));
+        assert_eq!(repository.snapshots().unwrap(), vec![name.clone()]);
+        assert_eq!(repository.chunker.avg, 64 * 1024);
+        assert_eq!(repository.previous.files[&model.relative_path()], file);
+        assert!(repository.save(false).unwrap().is_none());
+
+        let dest = Path::new("tests/tback-tmp/repository-big-file.pdf");
+        let path = model.relative_path();
+        assert!(repository.restore_file(&name, &path, dest, None).is_err());
+        let key = Key::from_bytes([9u8; 32]);
+        repository
+            .restore_file(&name, &path, dest, Some(&key))
+            .unwrap();
+        assert_eq!(
+            fs::read(dest).unwrap(),
+            fs::read("tests/big-file.pdf").unwrap()
+        );
+        assert!(repository
+            .restore_file(&name, "tests/no-such-file.txt", dest, Some(&key))
+            .is_err());
+    }
+
+    #[test]
+    fn dedup() {
+        let folder = "tests/tback-tmp/repository-dedup";
+        let source = "tests/tback-tmp/repository-dedup-src";
+        let _ = fs::remove_dir_all(source);
+        fs::create_dir_all(source).unwrap();
+
+        let mut repository = open(folder);
+        repository.chunker = Chunker::new(2 * 1024, 8 * 1024, 32 * 1024);
+
+        let data = noise(200 * 1024, 3);
+        let mut edited = data.clone();
+        edited.splice(50_000..50_000, b"an edit".to_vec());
+
+        let write = |name: &str, content: &[u8]| {
+            let path = format!("./{}/{}", source, name);
+            fs::write(&path, content).unwrap();
+            let model = FileModel::new(&path).read_metadata().unwrap();
+            repository.write_file(&model, None).unwrap();
+            chunk_count(folder)
+        };
+
+        let count = write("a.bin", &data);
+        assert!(count > 5);
+        assert_eq!(write("b.bin", &data), count);
+        assert!(write("c.bin", &edited) <= count + 3);
+
+        // the same content is one chunk whether or not its file type is compressed
+        let mut repository = open(folder);
+        repository.compress = true;
+        for name in ["d.txt", "d.pdf"] {
+            let path = format!("./{}/{}", source, name);
+            fs::write(&path, b"the same content").unwrap();
+            let model = FileModel::new(&path).read_metadata().unwrap();
+            repository.write_file(&model, None).unwrap();
+        }
+        assert_eq!(chunk_count(folder), 1);
+    }
+
+    #[test]
+    fn concurrent_duplicates() {
+        let source = "tests/tback-tmp/repository-concurrent-src";
+        let _ = fs::remove_dir_all(source);
+        fs::create_dir_all(source).unwrap();
+        let data = noise(300 * 1024, 4);
+        let models: Vec<FileModel> = (0..16)
+            .map(|idx| {
+                let path = format!("./{}/copy-{}.bin", source, idx);
+                fs::write(&path, &data).unwrap();
+                FileModel::new(&path).read_metadata().unwrap()
+            })
+            .collect();
+
+        // workers writing the same new chunks at once each store them once, intact
+        for round in 0..5 {
+            let folder = format!("tests/tback-tmp/repository-concurrent-{}", round);
+            let mut repository = open(&folder);
+            repository.chunker = Chunker::new(16 * 1024, 64 * 1024, 256 * 1024);
+            repository.compress = true;
+            repository.key = Some(Key::from_bytes([7u8; 32]));
+
+            let files: Vec<SnapshotFile> = std::thread::scope(|scope| {
+                let workers: Vec<_> = models
+                    .iter()
+                    .map(|model| scope.spawn(|| repository.write_file(model, None)))
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().unwrap().unwrap())
+                    .collect()
+            });
+            assert!(files.iter().all(|file| file.chunks == files[0].chunks));
+            assert_eq!(chunk_count(&folder), files[0].chunks.len());
+
+            let name = repository.save(true).unwrap().unwrap();
+            let dest = Path::new("tests/tback-tmp/repository-concurrent.bin");
+            let key = Key::from_bytes([7u8; 32]);
+            repository
+                .restore_file(&name, &models[15].relative_path(), dest, Some(&key))
+                .unwrap();
+            assert_eq!(fs::read(dest).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn keyed_chunk_names() {
+        let model = FileModel::new("./tests/file1.txt").read_metadata().unwrap();
+        let data = fs::read(&model.path).unwrap();
+        let plain = model.calc_hash(&data);
+
+        let repository = open("tests/tback-tmp/repository-plain-names");
+        let file = repository.write_file(&model, None).unwrap();
+        assert_eq!(file.chunks, vec![plain.clone()]);
+
+        // an encrypted chunk's name doesn't give away its content's hash
+        let key = Key::from_bytes([5u8; 32]);
+        let mut repository = open("tests/tback-tmp/repository-keyed-names");
+        repository.key = Some(key.clone());
+        let file = repository.write_file(&model, None).unwrap();
+        assert_eq!(file.chunks, vec![key.keyed_hash(&data).unwrap()]);
+        assert_ne!(file.chunks[0], plain);
+        assert_ne!(
+            key.keyed_hash(&data).unwrap(),
+            Key::from_bytes([6u8; 32]).keyed_hash(&data).unwrap()
+        );
+
+        // a passphrase gets a new salt on each load; the repository's salt keeps the names the same across runs
+        let folder = "tests/tback-tmp/repository-passphrase";
+        let mut repository = open(folder);
+        repository.key = Some(Key::from_passphrase("my passphrase").unwrap());
+        let first = repository.write_file(&model, None).unwrap();
+        let name = repository.save(true).unwrap().unwrap();
+
+        let mut repository = Repository::open(Arc::clone(&repository.backend)).unwrap();
+        repository.key = Some(Key::from_passphrase("my passphrase").unwrap());
+        assert_eq!(repository.write_file(&model, None).unwrap(), first);
+        assert_eq!(chunk_count(folder), 1);
+
+        let dest = Path::new("tests/tback-tmp/repository-passphrase.txt");
+        let key = Key::from_passphrase("my passphrase").unwrap();
+        repository
+            .restore_file(&name, &model.relative_path(), dest, Some(&key))
+            .unwrap();
+        assert_eq!(fs::read(dest).unwrap(), data);
+    }
+
+    #[test]
+    fn complete_and_partial_runs() {
+        let folder = "tests/tback-tmp/repository-runs";
+        let source = "tests/tback-tmp/repository-runs-src";
+        let _ = fs::remove_dir_all(source);
+        fs::create_dir_all(source).unwrap();
+        let model = |name: &str, content: &[u8]| {
+            let path = format!("./{}/{}", source, name);
+            fs::write(&path, content).unwrap();
+            FileModel::new(&path).read_metadata().unwrap()
+        };
+        let (a, b) = (model("a.txt", b"first"), model("b.txt", b"second"));
+
+        let repository = open(folder);
+        repository.write_file(&a, None).unwrap();
+        repository.write_file(&b, None).unwrap();
+        repository.save(true).unwrap().unwrap();
+
+        // a partial run keeps the files it didn't see
+        let repository = Repository::open(Arc::clone(&repository.backend)).unwrap();
+        let c = model("c.txt", b"third");
+        repository.write_file(&c, None).unwrap();
+        let name = repository.save(false).unwrap().unwrap();
+        assert_eq!(repository.snapshot(&name).unwrap().files.len(), 3);
+
+        // a complete run drops the files that are no longer in the source
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let repository = Repository::open(Arc::clone(&repository.backend)).unwrap();
+        assert!(repository.carry(&a.relative_path()));
+        assert!(!repository.carry("no-such-file.txt"));
+        repository.write_file(&c, None).unwrap();
+        let name = repository.save(true).unwrap().unwrap();
+        let files = &repository.snapshot(&name).unwrap().files;
+        assert_eq!(
+            files.keys().cloned().collect::<Vec<String>>(),
+            vec![a.relative_path(), c.relative_path()]
+        );
+    }
+
+    #[test]
+    fn prune() {
+        let folder = "tests/tback-tmp/repository-prune";
+        let source = "tests/tback-tmp/repository-prune-src";
+        let _ = fs::remove_dir_all(source);
+        fs::create_dir_all(source).unwrap();
+        let path = format!("./{}/a.txt", source);
+
+        let mut backend: Arc<dyn Target> = Arc::new(LocalTarget::new(folder));
+        let _ = fs::remove_dir_all(folder);
+        for content in ["one", "two", "three"] {
+            fs::write(&path, content).unwrap();
+            let model = FileModel::new(&path).read_metadata().unwrap();
+            let repository = Repository::open(backend).unwrap();
+            repository.write_file(&model, None).unwrap();
+            repository.save(true).unwrap().unwrap();
+            backend = Arc::clone(&repository.backend);
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+
+        let repository = Repository::open(backend).unwrap();
+        assert_eq!(repository.prune(0).unwrap(), (0, 0));
+        assert_eq!(repository.prune(3).unwrap(), (0, 0));
+        assert_eq!(chunk_count(folder), 3);
+
+        // only the newest snapshot's chunk is left
+        assert_eq!(repository.prune(1).unwrap(), (2, 2));
+        assert_eq!(repository.snapshots().unwrap().len(), 1);
+        assert_eq!(chunk_count(folder), 1);
+        let name = repository.snapshots().unwrap().pop().unwrap();
+        let dest = Path::new("tests/tback-tmp/repository-prune-a.txt");
+        repository
+            .restore_file(&name, &FileModel::new(&path).relative_path(), dest, None)
+            .unwrap();
+        assert_eq!(fs::read_to_string(dest).unwrap(), "three");
+    }
+}
@@ -0,0 +1,776 @@
+/// Restore Process to recover backed up files from a target
+///
+/// # Restore Process
+///
+/// create with the target folder; select the files from the database that were written to the target,
+/// optionally filtered by glob patterns, and copy them back to their original path or an alternate root.
+/// journaled files restore their latest version unless a specific version stamp is requested.
+/// compressed copies are decompressed and encrypted copies decrypted, and verified, on the way back.  copies on a
+/// remote target are downloaded to a staging file first.  a repository target restores each file from the snapshot
+/// recorded for it.
+///
+use crate::compression;
+use crate::encryption::{self, DecryptReader, Key};
+use crate::file_model::{FileModel, FileVersion};
+use crate::kv_store::KeyValueStore;
+use crate::repository::{self, Repository};
+use crate::run_report::Failure;
+use crate::target::{self, write_atomic, LocalTarget, Target};
+use anyhow::{anyhow, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{error, info, warn};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub struct RestoreProcess {
+    pub target: PathBuf,
+    pub dest_root: Option<PathBuf>,
+    pub patterns: Vec<String>,
+    pub overwrite: bool,
+    pub version: Option<String>,
+    pub key: Option<Key>,
+    pub dryrun: bool,
+    /// the store the copies are read from: the target folder, or a target given as a url
+    pub backend: Arc<dyn Target>,
+    /// the target's chunks and snapshots, if it is a repository
+    pub repository: Option<Repository>,
+}
+
+impl RestoreProcess {
+    pub fn new(path: &str, dryrun: bool) -> RestoreProcess {
+        let mut tp = path.to_string();
+        if !tp.ends_with('/') {
+            tp.push('/');
+        }
+
+        info!("restore from: {}, dryrun = {}", tp, dryrun);
+
+        // a target that can't be opened is treated as a missing folder, so target_exists reports it
+        let backend = target::open(path).unwrap_or_else(|e| {
+            warn!("could not open target {}: {:#}", path, e);
+            Arc::new(LocalTarget::new(path))
+        });
+
+        let repository = if repository::is_repository(backend.as_ref()) {
+            match Repository::open(Arc::clone(&backend)) {
+                Ok(repository) => Some(repository),
+                Err(e) => {
+                    warn!("could not open the repository {}: {:#}", tp, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        RestoreProcess {
+            target: PathBuf::from(tp),
+            dest_root: None,
+            patterns: vec![],
+            overwrite: false,
+            version: None,
+            key: None,
+            dryrun,
+            backend,
+            repository,
+        }
+    }
+
+    /// return true if the target folder exists, or a remote target can be reached, else false
+    pub fn target_exists(&self) -> bool {
+        if self.backend.exists() {
+            true
+        } else {
+            warn!("Target {:?} does not exist.", self.target);
+            false
+        }
+    }
+
+    /// select the database models that match the patterns (all if no patterns) and were written to this target
+    pub fn select(&self, db: &KeyValueStore) -> Result<Vec<FileModel>> {
+        let globs = self.build_globs()?;
+
+        let mut files: Vec<FileModel> = db
+            .models()
+            .filter(|model| self.target_copy(model).is_some())
+            .filter(|model| globs.is_empty() || Self::matches(&globs, model))
+            .cloned()
+            .collect();
+
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(files)
+    }
+
+    /// return the versions of the selected journaled files written to this target, oldest first
+    pub fn list_versions(&self, db: &KeyValueStore) -> Result<Vec<(FileModel, FileVersion)>> {
+        let prefix = self.target.to_str().unwrap();
+        let mut versions: Vec<(FileModel, FileVersion)> = Vec::new();
+
+        for model in self.select(db)? {
+            for version in model.versions.iter() {
+                if version.path.starts_with(prefix) {
+                    versions.push((model.clone(), version.clone()));
+                }
+            }
+        }
+
+        versions.sort_by(|a, b| a.0.path.cmp(&b.0.path).then(a.1.saved.cmp(&b.1.saved)));
+
+        Ok(versions)
+    }
+
+    /// restore the selected files; return the list of models that were restored, or an error if any file failed
+    pub fn process(&self, db: &KeyValueStore) -> Result<Vec<FileModel>> {
+        info!("process the restore queue");
+
+        let mut restored: Vec<FileModel> = Vec::new();
+        let mut failures: Vec<Failure> = Vec::new();
+        for model in self.select(db)? {
+            match self.restore_file(&model) {
+                Ok(Some(dest)) => {
+                    info!(
+                        "file restore: {} -> {}",
+                        model.path.display(),
+                        dest.display()
+                    );
+                    restored.push(model);
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    error!("restore failed: {}", e);
+                    failures.push(Failure {
+                        path: model.path.display().to_string(),
+                        error: format!("{:#}", e),
+                    });
+                }
+            }
+        }
+
+        // the files that could be restored are kept
+        if let Some(first) = failures.first() {
+            let msg = format!(
+                "{} files could not be restored and {} were, the first failure: {}: {}",
+                failures.len(),
+                restored.len(),
+                first.path,
+                first.error
+            );
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+
+        Ok(restored)
+    }
+
+    /// copy the target's copy of the model back; return the destination path or None if skipped
+    pub fn restore_file(&self, model: &FileModel) -> Result<Option<PathBuf>> {
+        if let Some(link) = &model.link_target {
+            return self.restore_link(model, link);
+        }
+
+        let src = match self.target_copy(model) {
+            Some(src) => src,
+            None => {
+                let msg = format!(
+                    "{} was not written to {:?}",
+                    model.path.display(),
+                    self.target
+                );
+                return Err(anyhow!("{}", msg));
+            }
+        };
+
+        let relative = target::relative_to(self.target.to_str().unwrap(), src.to_str().unwrap());
+        match self.backend.stat(&relative) {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                let msg = format!("target copy is missing: {}", src.display());
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+            Err(e) => {
+                let msg = format!("could not check {}: {:#}", src.display(), e);
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+        }
+
+        let dest = self.dest_path(model);
+        if dest.exists() && !self.overwrite {
+            warn!("skip existing file: {} (use overwrite)", dest.display());
+            return Ok(None);
+        }
+
+        if self.dryrun {
+            info!(
+                "dryrun, would restore: {} -> {}",
+                src.display(),
+                dest.display()
+            );
+            return Ok(Some(dest));
+        }
+
+        if let (Some(repository), Some(name)) = (&self.repository, self.snapshot_name(&src)) {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            repository.restore_file(&name, &model.relative_path(), &dest, self.key.as_ref())?;
+        } else {
+            let compressed = compression::is_compressed_copy(model.path.as_path(), src.as_path());
+            let encrypted = encryption::is_encrypted_copy(model.path.as_path(), src.as_path());
+            if self.backend.is_local() {
+                self.copy(src.as_path(), dest.as_path(), compressed, encrypted)?;
+            } else {
+                self.download(&relative, dest.as_path(), compressed, encrypted)?;
+            }
+        }
+
+        // the recorded attributes describe the latest save, not an earlier version
+        if self.version.is_none() {
+            if let Some(attributes) = &model.attributes {
+                attributes.apply(dest.as_path(), true);
+            }
+        }
+
+        Ok(Some(dest))
+    }
+
+    /// recreate a stored symlink; return the destination path or None if skipped
+    fn restore_link(&self, model: &FileModel, link: &Path) -> Result<Option<PathBuf>> {
+        let dest = self.dest_path(model);
+        if dest.symlink_metadata().is_ok() && !self.overwrite {
+            warn!("skip existing file: {} (use overwrite)", dest.display());
+            return Ok(None);
+        }
+
+        if self.dryrun {
+            info!(
+                "dryrun, would link: {} -> {}",
+                dest.display(),
+                link.display()
+            );
+            return Ok(Some(dest));
+        }
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(&dest)?;
+        }
+        std::os::unix::fs::symlink(link, &dest)?;
+
+        Ok(Some(dest))
+    }
+
+    /// return the path of the model's copy on this target, if the model was written here; for
+    /// journaled files this is the requested or latest version
+    pub fn target_copy(&self, model: &FileModel) -> Option<PathBuf> {
+        let prefix = self.target.to_str().unwrap();
+
+        if let Some(stamp) = &self.version {
+            return model
+                .versions
+                .iter()
+                .find(|version| version.path.starts_with(prefix) && version.stamp() == stamp)
+                .map(|version| PathBuf::from(&version.path));
+        }
+
+        // prefer the copy written last to this target
+        let written: Vec<&String> = model
+            .written_to
+            .iter()
+            .filter(|written| written.starts_with(prefix))
+            .collect();
+        let latest = written
+            .iter()
+            .filter_map(|written| {
+                let saved = model.copies.get(written.as_str())?.saved;
+                Some((saved, written))
+            })
+            .max()
+            .map(|(_, written)| written);
+
+        latest
+            .or(written.first())
+            .map(|written| PathBuf::from(written.as_str()))
+            .or_else(|| {
+                model
+                    .latest_version(prefix)
+                    .map(|version| PathBuf::from(&version.path))
+            })
+    }
+
+    /// return the name of the snapshot if the copy is a repository snapshot
+    fn snapshot_name(&self, src: &Path) -> Option<String> {
+        let name = src
+            .strip_prefix(self.target.join(repository::SNAPSHOTS))
+            .ok()?;
+
+        Some(name.to_str()?.to_string())
+    }
+
+    /// return the original path, or the path relative to the alternate root
+    pub fn dest_path(&self, model: &FileModel) -> PathBuf {
+        match &self.dest_root {
+            Some(root) => Path::join(root, PathBuf::from(model.relative_path())),
+            None => model.path.clone(),
+        }
+    }
+
+    /// download the remote copy to a staging file and restore it from there; the staging file is removed
+    fn download(&self, path: &str, dest: &Path, compressed: bool, encrypted: bool) -> Result<()> {
+        let stage = target::stage_path();
+        let resp = match self.backend.get(path, &stage) {
+            Ok(_) => self.copy(&stage, dest, compressed, encrypted),
+            Err(e) => {
+                let msg = format!("error downloading {}: {:#}", path, e);
+                error!("{}", msg);
+                Err(anyhow!("{}", msg))
+            }
+        };
+        let _ = fs::remove_file(&stage);
+
+        resp
+    }
+
+    /// copy from src to dest, decrypting and decompressing as needed; creates the parent folder if necessary.  the copy
+    /// is written to a temp file and only renamed over dest once it is complete and verified
+    fn copy(&self, src: &Path, dest: &Path, compressed: bool, encrypted: bool) -> Result<()> {
+        if let Some(parent) = dest.parent() {
+            if !parent.exists() && fs::create_dir_all(parent).is_err() {
+                let msg = format!("error creating parent folder: {}", parent.display());
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+        }
+
+        if !compressed && !encrypted {
+            let resp = write_atomic(dest, |temp| {
+                fs::copy(src, temp)?;
+                Ok(())
+            });
+            if resp.is_err() {
+                let msg = format!("error copying {} to {}", src.display(), dest.display());
+                error!("{}", msg);
+                return Err(anyhow!("{}", msg));
+            }
+
+            return Ok(());
+        }
+
+        // the output is not trusted unless the whole file decrypted and verified, so an existing file is kept
+        let resp = write_atomic(dest, |temp| {
+            self.read_copy(src, temp, compressed, encrypted)
+        });
+        if let Err(e) = resp {
+            let msg = format!(
+                "error restoring {} to {}: {}",
+                src.display(),
+                dest.display(),
+                e
+            );
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+
+        Ok(())
+    }
+
+    fn read_copy(&self, src: &Path, dest: &Path, compressed: bool, encrypted: bool) -> Result<()> {
+        let file = File::open(src)?;
+        let len = file.metadata()?.len();
+        let mut reader: Box<dyn Read> = Box::new(BufReader::new(file));
+
+        if encrypted {
+            let key = match &self.key {
+                Some(key) => key,
+                None => return Err(anyhow!("the copy is encrypted but no key is configured")),
+            };
+            reader = Box::new(DecryptReader::new(reader, len, key)?);
+        }
+
+        if compressed {
+            reader = Box::new(compression::decompressor(reader));
+        }
+
+        let mut writer = BufWriter::new(File::create(dest)?);
+        io::copy(&mut reader, &mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn build_globs(&self) -> Result<GlobSet> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in self.patterns.iter() {
+            builder.add(Glob::new(pattern)?);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// match either the stored path or the path without the leading ./
+    fn matches(globs: &GlobSet, model: &FileModel) -> bool {
+        let path = model.path.to_str().unwrap();
+        globs.is_match(path) || globs.is_match(path.trim_start_matches("./"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_db() -> KeyValueStore {
+        let mut db = KeyValueStore::init(PathBuf::from("tests/data/saved.json")).unwrap();
+        for name in ["file2.txt", "file3.txt"] {
+            let path = format!("./tests/{}", name);
+            let mut model = db.identify(FileModel::new(path.as_str()));
+            model.written_to.insert(format!("tests/tback/{}", name));
+            db.set(model).unwrap();
+        }
+
+        db
+    }
+
+    #[test]
+    fn select() {
+        let db = create_db();
+        let mut restore = RestoreProcess::new("tests/tback", true);
+        assert!(restore.target_exists());
+
+        let files = restore.select(&db).unwrap();
+        assert_eq!(files.len(), 2);
+
+        restore.patterns = vec!["tests/*3.txt".to_string()];
+        let files = restore.select(&db).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("./tests/file3.txt"));
+
+        let restore = RestoreProcess::new("tests/qback", true);
+        assert!(!restore.target_exists());
+        assert!(restore.select(&db).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_pattern() {
+        let db = create_db();
+        let mut restore = RestoreProcess::new("tests/tback", true);
+        restore.patterns = vec!["tests/[".to_string()];
+        assert!(restore.select(&db).is_err());
+    }
+
+    #[test]
+    fn restore_overwrite() {
+        let db = create_db();
+        let mut restore = RestoreProcess::new("tests/tback", false);
+
+        // the originals exist so nothing is restored
+        let restored = restore.process(&db).unwrap();
+        assert!(restored.is_empty());
+
+        restore.dryrun = true;
+        restore.overwrite = true;
+        let restored = restore.process(&db).unwrap();
+        assert_eq!(restored.len(), 2);
+    }
+
+    #[test]
+    fn restore_attributes() {
+        use crate::attributes::FileAttributes;
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = "tests/tback-tmp/restore-attributes";
+        let _ = fs::remove_dir_all(root);
+
+        let mut db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let mut model = FileModel::new("./tests/file3.txt");
+        model.written_to.insert("tests/tback/file3.txt".to_string());
+        model.attributes = Some(FileAttributes {
+            mtime: 1_600_000_000,
+            mode: 0o700,
+            ..FileAttributes::default()
+        });
+        db.set(model).unwrap();
+
+        let mut restore = RestoreProcess::new("tests/tback", false);
+        restore.dest_root = Some(PathBuf::from(root));
+        assert_eq!(restore.process(&db).unwrap().len(), 1);
+
+        let meta = fs::metadata(format!("{}/tests/file3.txt", root)).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o700);
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta).unix_seconds(),
+            1_600_000_000
+        );
+    }
+
+    #[test]
+    fn restore_link() {
+        let root = "tests/tback-tmp/restore-link";
+        let _ = fs::remove_dir_all(root);
+
+        let mut db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let mut model = FileModel::new("./tests/.config/file3.txt");
+        model.link_target = Some(PathBuf::from("../file3.txt"));
+        model
+            .written_to
+            .insert("tests/tback/.config/file3.txt".to_string());
+        db.set(model).unwrap();
+
+        let mut restore = RestoreProcess::new("tests/tback", false);
+        restore.dest_root = Some(PathBuf::from(root));
+        assert_eq!(restore.process(&db).unwrap().len(), 1);
+
+        let dest = format!("{}/tests/.config/file3.txt", root);
+        assert_eq!(fs::read_link(&dest).unwrap(), PathBuf::from("../file3.txt"));
+
+        // an existing link is kept unless overwrite is set
+        assert!(restore.process(&db).unwrap().is_empty());
+        restore.overwrite = true;
+        assert_eq!(restore.process(&db).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn restore_alternate_root() {
+        let root = "tests/tback-tmp/restore";
+        let _ = fs::remove_dir_all(root);
+
+        let db = create_db();
+        let mut restore = RestoreProcess::new("tests/tback", false);
+        restore.dest_root = Some(PathBuf::from(root));
+
+        let restored = restore.process(&db).unwrap();
+        assert_eq!(restored.len(), 2);
+
+        let text = fs::read_to_string("tests/tback-tmp/restore/tests/file3.txt").unwrap();
+        assert_eq!(text, fs::read_to_string("tests/tback/file3.txt").unwrap());
+    }
+
+    #[test]
+    fn restore_version() {
+        let target = "tests/tback-tmp/versions";
+        let root = "tests/tback-tmp/restore-version";
+        let _ = fs::remove_dir_all(target);
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(format!("{}/tests", target)).unwrap();
+
+        let mut db = create_db();
+        let mut model = db.identify(FileModel::new("./tests/file1.txt"));
+        for (stamp, text) in [("2026-10-18T10:00:00", "v1"), ("2026-10-18T12:00:00", "v2")] {
+            let path = format!("{}/tests/file1.txt@{}Z", target, stamp);
+            fs::write(&path, text).unwrap();
+            model.versions.push(FileVersion {
+                path,
+                saved: stamp.parse().unwrap(),
+                ..FileVersion::default()
+            });
+        }
+        db.set(model).unwrap();
+
+        let mut restore = RestoreProcess::new(target, false);
+        restore.patterns = vec!["tests/file1.txt".to_string()];
+        restore.dest_root = Some(PathBuf::from(root));
+
+        let versions = restore.list_versions(&db).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].1.stamp(), "2026-10-18T10:00:00Z");
+
+        // the latest version by default
+        let restored = restore.process(&db).unwrap();
+        assert_eq!(restored.len(), 1);
+        let dest = format!("{}/tests/file1.txt", root);
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "v2");
+
+        restore.version = Some("2026-10-18T10:00:00Z".to_string());
+        restore.overwrite = true;
+        let restored = restore.process(&db).unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "v1");
+
+        restore.version = Some("2020-01-01T00:00:00Z".to_string());
+        assert!(restore.select(&db).unwrap().is_empty());
+    }
+
+    #[test]
+    fn target_copy_latest() {
+        use crate::file_model::CopyRecord;
+        use chrono::NaiveDate;
+
+        // the usb target holds an old compressed copy and a newer plain one; the model's flags, from the nas target,
+        // don't pick the copy
+        let mut model = FileModel::new("./tests/file1.txt");
+        model.compressed = true;
+        model.encrypted = true;
+        let old = "tests/tback-tmp/usb/tests/file1.txt.gz".to_string();
+        let new = "tests/tback-tmp/usb/tests/file1.txt".to_string();
+        let nas = "tests/tback-tmp/nas/tests/file1.txt.gz.enc".to_string();
+        let day = |d: u32| {
+            NaiveDate::from_ymd_opt(2026, 10, d)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        for (path, saved) in [(&old, day(1)), (&new, day(2)), (&nas, day(3))] {
+            model.written_to.insert(path.clone());
+            let record = CopyRecord {
+                saved,
+                ..CopyRecord::default()
+            };
+            model.copies.insert(path.clone(), record);
+        }
+
+        let restore = RestoreProcess::new("tests/tback-tmp/usb", false);
+        assert_eq!(restore.target_copy(&model), Some(PathBuf::from(&new)));
+        let restore = RestoreProcess::new("tests/tback-tmp/nas", false);
+        assert_eq!(restore.target_copy(&model), Some(PathBuf::from(&nas)));
+    }
+
+    #[test]
+    fn restore_compressed() {
+        use crate::backup_process::BackupProcess;
+
+        let target = "tests/tback-tmp/restore-compressed";
+        let root = "tests/tback-tmp/restore-decompressed";
+        let _ = fs::remove_dir_all(target);
+        let _ = fs::remove_dir_all(root);
+
+        let model = FileModel::new("./tests/file1.txt").read_metadata().unwrap();
+        let mut backup = BackupProcess::new(target, vec![model], false);
+        backup.compress = true;
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let (db, _) = backup.process(db).unwrap();
+
+        let mut restore = RestoreProcess::new(target, false);
+        restore.dest_root = Some(PathBuf::from(root));
+        let restored = restore.process(&db).unwrap();
+        assert_eq!(restored.len(), 1);
+
+        let text = fs::read_to_string(format!("{}/tests/file1.txt", root)).unwrap();
+        assert_eq!(text, fs::read_to_string("tests/file1.txt").unwrap());
+    }
+
+    #[test]
+    fn restore_encrypted() {
+        use crate::backup_process::BackupProcess;
+
+        let target = "tests/tback-tmp/restore-encrypted";
+        let root = "tests/tback-tmp/restore-decrypted";
+        let _ = fs::remove_dir_all(target);
+        let _ = fs::remove_dir_all(root);
+
+        let files: Vec<FileModel> = ["./tests/file1.txt", "./tests/big-file.pdf"]
+            .iter()
+            .map(|path| FileModel::new(path).read_metadata().unwrap())
+            .collect();
+        let mut backup = BackupProcess::new(target, files, false);
+        backup.compress = true;
+        backup.key = Some(Key::from_bytes([5u8; 32]));
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let (db, _) = backup.process(db).unwrap();
+
+        // no key, then the wrong key
+        let mut restore = RestoreProcess::new(target, false);
+        restore.dest_root = Some(PathBuf::from(root));
+        assert!(restore.process(&db).is_err());
+
+        restore.key = Some(Key::from_bytes([6u8; 32]));
+        assert!(restore.process(&db).is_err());
+        assert!(!Path::new(&format!("{}/tests/file1.txt", root)).exists());
+
+        restore.key = Some(Key::from_bytes([5u8; 32]));
+        let restored = restore.process(&db).unwrap();
+        assert_eq!(restored.len(), 2);
+
+        for name in ["file1.txt", "big-file.pdf"] {
+            let restored = fs::read(format!("{}/tests/{}", root, name)).unwrap();
+            assert_eq!(restored, fs::read(format!("tests/{}", name)).unwrap());
+        }
+    }
+
+    #[test]
+    fn restore_repository() {
+        use crate::backup_process::BackupProcess;
+        use crate::config::TargetFormat;
+
+        let target = "tests/tback-tmp/restore-repository";
+        let root = "tests/tback-tmp/restore-from-repository";
+        let _ = fs::remove_dir_all(target);
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(target).unwrap();
+
+        let files: Vec<FileModel> = ["./tests/file1.txt", "./tests/big-file.pdf"]
+            .iter()
+            .map(|path| FileModel::new(path).read_metadata().unwrap())
+            .collect();
+        let mut backup = BackupProcess::new(target, files, false);
+        backup.format = TargetFormat::Repository;
+        backup.key = Some(Key::from_bytes([5u8; 32]));
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let (db, _) = backup.process(db).unwrap();
+
+        let mut restore = RestoreProcess::new(target, false);
+        assert!(restore.repository.is_some());
+        restore.dest_root = Some(PathBuf::from(root));
+        assert!(restore.process(&db).is_err());
+
+        restore.key = Some(Key::from_bytes([5u8; 32]));
+        assert_eq!(restore.process(&db).unwrap().len(), 2);
+        for name in ["file1.txt", "big-file.pdf"] {
+            let restored = fs::read(format!("{}/tests/{}", root, name)).unwrap();
+            assert_eq!(restored, fs::read(format!("tests/{}", name)).unwrap());
+        }
+
+        assert!(RestoreProcess::new("tests/tback", false)
+            .repository
+            .is_none());
+    }
+
+    #[test]
+    fn restore_corrupt_keeps_original() {
+        use crate::backup_process::BackupProcess;
+
+        let target = "tests/tback-tmp/restore-corrupt";
+        let root = "tests/tback-tmp/restore-corrupt-dest";
+        let _ = fs::remove_dir_all(target);
+        let _ = fs::remove_dir_all(root);
+
+        let model = FileModel::new("./tests/file1.txt").read_metadata().unwrap();
+        let mut backup = BackupProcess::new(target, vec![model], false);
+        backup.key = Some(Key::from_bytes([5u8; 32]));
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let (db, _) = backup.process(db).unwrap();
+
+        // truncate the copy so the tag can't be verified
+        let copy = format!("{}/tests/file1.txt.enc", target);
+        let bytes = fs::read(&copy).unwrap();
+        fs::write(&copy, &bytes[..bytes.len() - 4]).unwrap();
+
+        let dest = format!("{}/tests/file1.txt", root);
+        fs::create_dir_all(format!("{}/tests", root)).unwrap();
+        fs::write(&dest, "the original").unwrap();
+
+        let mut restore = RestoreProcess::new(target, false);
+        restore.dest_root = Some(PathBuf::from(root));
+        restore.overwrite = true;
+        restore.key = Some(Key::from_bytes([5u8; 32]));
+        assert!(restore.process(&db).is_err());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "the original");
+        assert!(!Path::new(&format!("{}.replica-tmp", dest)).exists());
+    }
+
+    #[test]
+    fn restore_missing() {
+        let mut model = FileModel::new("./tests/file-nofile.txt");
+        let restore = RestoreProcess::new("tests/tback", false);
+        assert!(restore.restore_file(&model).is_err());
+
+        model
+            .written_to
+            .insert("tests/tback/./tests/file-nofile.txt".to_string());
+        assert!(restore.restore_file(&model).is_err());
+    }
+}
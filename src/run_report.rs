@@ -0,0 +1,321 @@
+/// Run Report - the counts, bytes, durations and failures of a backup run
+///
+/// # Run Report
+///
+/// each target's backup returns a target report; the binary gathers them into a run report that is logged as a
+/// summary and optionally written as json for monitoring.  the report's status, with the configured minimum of
+/// targets that must succeed, sets the exit code so cron and systemd see failed runs.
+///
+use anyhow::Result;
+use chrono::naive::NaiveDateTime;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::process::ExitCode;
+use std::time::Instant;
+
+/// the outcome of a run, in order of precedence; the value is the process exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunStatus {
+    Ok = 0,
+    /// an error outside the backup itself, e.g. a restore that failed or the daemon is already running
+    Error = 1,
+    /// fewer targets than the configured minimum were backed up without failures
+    PartialFailure = 2,
+    /// none of the configured targets could be reached
+    NoTarget = 3,
+    /// the config, or the encryption key it points to, could not be read
+    ConfigError = 4,
+    /// the database could not be read or saved
+    DbError = 5,
+}
+
+impl RunStatus {
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl From<RunStatus> for ExitCode {
+    fn from(status: RunStatus) -> ExitCode {
+        ExitCode::from(status.code())
+    }
+}
+
+/// a file that could not be backed up and why
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Failure {
+    pub path: String,
+    pub error: String,
+}
+
+/// the outcome of backing up the files to one target
+#[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
+pub struct TargetReport {
+    pub target: String,
+    /// the number of files queued for this target
+    pub files: usize,
+    /// the number of files written, or that would be written in a dry run
+    pub copied: usize,
+    /// the number of files whose copy was current
+    pub skipped: usize,
+    pub failed: usize,
+    /// the source bytes of the files written
+    pub bytes: u64,
+    pub seconds: f64,
+    pub failures: Vec<Failure>,
+    /// why the target could not be backed up at all, e.g. it could not be reached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl TargetReport {
+    pub fn new(target: &str, files: usize) -> TargetReport {
+        TargetReport {
+            target: target.to_string(),
+            files,
+            ..TargetReport::default()
+        }
+    }
+
+    /// a target that could not be backed up at all, with the error chain that stopped it
+    pub fn unavailable(target: &str, files: usize, error: &anyhow::Error) -> TargetReport {
+        TargetReport {
+            target: target.to_string(),
+            files,
+            error: Some(format!("{:#}", error)),
+            ..TargetReport::default()
+        }
+    }
+
+    /// count a written file
+    pub fn copied(&mut self, bytes: u64) {
+        self.copied += 1;
+        self.bytes += bytes;
+    }
+
+    /// count a file whose copy was current
+    pub fn skipped(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// count a failed file with the error chain that caused it
+    pub fn failed(&mut self, path: &str, error: &anyhow::Error) {
+        self.failed += 1;
+        self.failures.push(Failure {
+            path: path.to_string(),
+            error: format!("{:#}", error),
+        });
+    }
+
+    /// return the throughput in MB/s
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / 1_000_000.0 / self.seconds.max(0.001)
+    }
+}
+
+/// the outcome of a backup pass over all the targets
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct RunReport {
+    pub started: NaiveDateTime,
+    pub seconds: f64,
+    pub dryrun: bool,
+    /// the number of files found by the walk, or reported by the watcher
+    pub files: usize,
+    pub targets: Vec<TargetReport>,
+    /// why the pass could not run, e.g. the walk failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// why the database could not be saved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub db_error: Option<String>,
+    #[serde(skip)]
+    start_time: Option<Instant>,
+}
+
+impl RunReport {
+    /// start the report's clock
+    pub fn new(files: usize, dryrun: bool) -> RunReport {
+        RunReport {
+            started: Utc::now().naive_utc(),
+            seconds: 0.0,
+            dryrun,
+            files,
+            targets: vec![],
+            error: None,
+            db_error: None,
+            start_time: Some(Instant::now()),
+        }
+    }
+
+    /// stop the clock; return the report
+    pub fn finish(mut self) -> RunReport {
+        if let Some(start_time) = self.start_time {
+            self.seconds = start_time.elapsed().as_secs_f64();
+        }
+
+        self
+    }
+
+    /// the total of files written to all targets
+    pub fn copied(&self) -> usize {
+        self.targets.iter().map(|target| target.copied).sum()
+    }
+
+    /// the total of files that failed on all targets
+    pub fn failed(&self) -> usize {
+        self.targets.iter().map(|target| target.failed).sum()
+    }
+
+    /// the total of bytes written to all targets
+    pub fn bytes(&self) -> u64 {
+        self.targets.iter().map(|target| target.bytes).sum()
+    }
+
+    /// return the run's status: ok if at least min targets were backed up without a failed file
+    pub fn status(&self, min_targets: usize) -> RunStatus {
+        let reached = self
+            .targets
+            .iter()
+            .filter(|target| target.error.is_none())
+            .count();
+        let succeeded = self
+            .targets
+            .iter()
+            .filter(|target| target.error.is_none() && target.failed == 0)
+            .count();
+
+        if self.error.is_some() {
+            RunStatus::Error
+        } else if self.db_error.is_some() {
+            RunStatus::DbError
+        } else if reached == 0 {
+            RunStatus::NoTarget
+        } else if succeeded < min_targets.max(1) {
+            RunStatus::PartialFailure
+        } else {
+            RunStatus::Ok
+        }
+    }
+
+    /// return a one line summary for the log
+    pub fn summary(&self) -> String {
+        format!(
+            "files: {}, targets: {}, copied: {}, failed: {}, bytes: {}, seconds: {:.3}",
+            self.files,
+            self.targets.len(),
+            self.copied(),
+            self.failed(),
+            self.bytes(),
+            self.seconds
+        )
+    }
+
+    /// return the report as pretty json
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// write the report as json to the file
+    pub fn write(&self, filename: &str) -> Result<()> {
+        fs::write(filename, self.to_json()?)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn target_report() {
+        let mut report = TargetReport::new("tback", 4);
+        report.copied(100);
+        report.copied(50);
+        report.skipped();
+        let error = anyhow!("permission denied").context("error saving to: tback/file1.txt");
+        report.failed("./tests/file1.txt", &error);
+
+        assert_eq!(report.copied, 2);
+        assert_eq!(report.bytes, 150);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.failed, 1);
+        assert_eq!(
+            report.failures[0].error,
+            "error saving to: tback/file1.txt: permission denied"
+        );
+    }
+
+    #[test]
+    fn run_report() {
+        let mut report = RunReport::new(4, false);
+        let mut target = TargetReport::new("tback", 4);
+        target.copied(100);
+        report.targets.push(target.clone());
+        target.failed("./tests/file2.txt", &anyhow!("disk full"));
+        report.targets.push(target);
+
+        let report = report.finish();
+        assert!(report.seconds > 0.0);
+        assert_eq!(report.copied(), 2);
+        assert_eq!(report.failed(), 1);
+        assert_eq!(report.bytes(), 200);
+        assert!(report.summary().contains("copied: 2, failed: 1"));
+
+        let filename = "tests/tback-tmp/run-report.json";
+        let _ = fs::create_dir_all("tests/tback-tmp");
+        report.write(filename).unwrap();
+        let text = fs::read_to_string(filename).unwrap();
+        let read: RunReport = serde_json::from_str(&text).unwrap();
+        assert_eq!(read.targets, report.targets);
+        assert_eq!(read.started, report.started);
+    }
+
+    #[test]
+    fn status() {
+        let mut report = RunReport::new(4, false);
+        assert_eq!(report.status(1), RunStatus::NoTarget);
+
+        let mut failed = TargetReport::new("usb", 4);
+        failed.failed("./tests/file1.txt", &anyhow!("disk full"));
+        report.targets.push(failed);
+        assert_eq!(report.status(1), RunStatus::PartialFailure);
+
+        report.targets.push(TargetReport::new("nas", 4));
+        assert_eq!(report.status(1), RunStatus::Ok);
+        assert_eq!(report.status(2), RunStatus::PartialFailure);
+        // a minimum of 0 still needs one good target
+        report.targets.remove(1);
+        assert_eq!(report.status(0), RunStatus::PartialFailure);
+
+        report.db_error = Some("disk full".to_string());
+        assert_eq!(report.status(1), RunStatus::DbError);
+        assert_eq!(RunStatus::DbError.code(), 5);
+
+        report.error = Some("walk failed".to_string());
+        assert_eq!(report.status(1), RunStatus::Error);
+    }
+
+    #[test]
+    fn unavailable_targets() {
+        let mut report = RunReport::new(4, false);
+        let error = anyhow!("no route to host").context("could not reach nas");
+        report
+            .targets
+            .push(TargetReport::unavailable("nas", 4, &error));
+        assert_eq!(report.status(1), RunStatus::NoTarget);
+        assert_eq!(
+            report.targets[0].error.as_deref(),
+            Some("could not reach nas: no route to host")
+        );
+
+        // a reachable target is ok, but not enough for a minimum of two
+        report.targets.push(TargetReport::new("usb", 4));
+        assert_eq!(report.status(1), RunStatus::Ok);
+        assert_eq!(report.status(2), RunStatus::PartialFailure);
+        assert!(report.to_json().unwrap().contains("no route to host"));
+    }
+}
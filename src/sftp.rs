@@ -0,0 +1,486 @@
+/// SFTP Target - copies written to a remote machine over SSH
+///
+/// # SFTP Target
+///
+/// a target like `sftp://user@host:port/path` is written with the OpenSSH sftp client in batch mode, so the keys,
+/// identity files and known hosts of the user's ssh config apply.  authentication must be key based as there is no
+/// password prompt, and the host must already be in known_hosts.  a path that starts with `/~/` is relative to the
+/// remote user's home folder.  the client's ssh connections share one master connection, kept open for a minute
+/// after the last use, so each command doesn't pay for a new handshake.
+///
+use crate::backup_process::TEMP_EXTENSION;
+use crate::target::{self, SourceMeta, Target, TargetStat};
+use anyhow::{anyhow, Result};
+use log::{debug, error};
+use std::path::Path;
+use subprocess::{Exec, Redirection};
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub struct SftpTarget {
+    /// the user@host to connect to
+    pub destination: String,
+    pub port: Option<u16>,
+    /// the folder on the remote machine the copies are written below
+    pub root: String,
+    /// the sftp client to run, e.g. a stand-in for tests
+    pub command: String,
+    /// the options passed to the client before the destination
+    pub options: Vec<String>,
+}
+
+impl SftpTarget {
+    pub fn new(destination: &str, root: &str) -> SftpTarget {
+        SftpTarget {
+            destination: destination.to_string(),
+            port: None,
+            root: root.trim_end_matches('/').to_string(),
+            command: "sftp".to_string(),
+            options: [
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "StrictHostKeyChecking=yes",
+                "-o",
+                "ControlMaster=auto",
+                "-o",
+                "ControlPath=~/.ssh/replica-%C",
+                "-o",
+                "ControlPersist=60",
+            ]
+            .iter()
+            .map(|opt| opt.to_string())
+            .collect(),
+        }
+    }
+
+    /// create from an `sftp://user@host:port/path` url
+    pub fn from_url(url: &Url) -> Result<SftpTarget> {
+        let Some(host) = url.host_str() else {
+            let msg = format!("no host in target url: {}", url);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        };
+
+        let destination = if url.username().is_empty() {
+            host.to_string()
+        } else {
+            format!("{}@{}", url.username(), host)
+        };
+
+        let path = url.path();
+        let root = path.strip_prefix("/~/").unwrap_or(path);
+
+        let mut target = SftpTarget::new(&destination, root);
+        target.port = url.port();
+
+        Ok(target)
+    }
+
+    /// return the remote path of a path relative to the root
+    fn remote_path(&self, path: &str) -> String {
+        if self.root.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.root, path)
+        }
+    }
+
+    /// run the batch of sftp commands; a command prefixed with `-` may fail without failing the batch.  return the
+    /// client's output
+    fn run(&self, commands: &[String]) -> Result<String> {
+        let mut args = self.options.clone();
+        if let Some(port) = self.port {
+            args.push("-P".to_string());
+            args.push(port.to_string());
+        }
+        args.extend(["-b", "-", self.destination.as_str()].map(String::from));
+
+        let batch = commands.join("\n") + "\n";
+        debug!("{} {:?}: {}", self.command, args, batch);
+
+        let capture = Exec::cmd(&self.command)
+            .args(&args)
+            .stdin(batch.as_str())
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Pipe)
+            .capture()?;
+
+        if capture.success() {
+            Ok(capture.stdout_str())
+        } else {
+            let msg = format!(
+                "sftp to {} failed: {}",
+                self.destination,
+                capture.stderr_str().trim()
+            );
+            error!("{}", msg);
+            Err(anyhow!("{}", msg))
+        }
+    }
+}
+
+impl Target for SftpTarget {
+    fn exists(&self) -> bool {
+        let root = if self.root.is_empty() {
+            "."
+        } else {
+            &self.root
+        };
+        self.run(&[format!("cd {}", quote(root))]).is_ok()
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<TargetStat>> {
+        let remote = self.remote_path(path);
+        let output = self.run(&[format!("-ls -ln {}", quote(&remote))])?;
+
+        // the client echoes each command; the listing of a file is one line with its name.  a folder lists its
+        // entries instead, so only a line for the file itself counts
+        let name = remote.rsplit('/').next().unwrap_or_default();
+        let size = output
+            .lines()
+            .filter(|line| !line.starts_with("sftp>"))
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+            .filter(|fields| fields.len() >= 9 && !fields[0].starts_with('d'))
+            .filter(|fields| {
+                let listed = fields[8..].join(" ");
+                listed == remote || listed == name
+            })
+            .find_map(|fields| fields[4].parse::<u64>().ok());
+
+        Ok(size.map(|len| TargetStat {
+            len,
+            ..TargetStat::default()
+        }))
+    }
+
+    /// the source record is kept in the database only
+    fn put(&self, local: &Path, path: &str, _source: &SourceMeta) -> Result<()> {
+        let remote = self.remote_path(path);
+        let temp = format!("{}{}", remote, TEMP_EXTENSION);
+
+        // create each parent folder, top down; the ones that exist fail harmlessly
+        let folders: Vec<&Path> = Path::new(&remote)
+            .ancestors()
+            .skip(1)
+            .filter(|folder| !folder.as_os_str().is_empty() && *folder != Path::new("/"))
+            .collect();
+        let mut commands: Vec<String> = folders
+            .iter()
+            .rev()
+            .map(|folder| format!("-mkdir {}", quote(folder.to_str().unwrap())))
+            .collect();
+
+        // rename uses the posix-rename extension, so replaces the existing copy
+        commands.push(format!(
+            "put {} {}",
+            quote(local.to_str().unwrap()),
+            quote(&temp)
+        ));
+        commands.push(format!("rename {} {}", quote(&temp), quote(&remote)));
+
+        if let Err(e) = self.run(&commands) {
+            let _ = self.run(&[format!("-rm {}", quote(&temp))]);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, path: &str, local: &Path) -> Result<()> {
+        self.run(&[format!(
+            "get {} {}",
+            quote(&self.remote_path(path)),
+            quote(local.to_str().unwrap())
+        )])?;
+
+        Ok(())
+    }
+
+    fn list(&self, folder: &str) -> Result<Vec<String>> {
+        let remote = match self.remote_path(folder.trim_end_matches('/')) {
+            path if path.is_empty() => ".".to_string(),
+            path => path,
+        };
+        let output = self.run(&[format!("ls -ln {}", quote(&remote))])?;
+
+        // each listing line has eight fields before the name; the client may print the name with its folder
+        let mut paths = vec![];
+        for line in output.lines().filter(|line| !line.starts_with("sftp>")) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 9 {
+                continue;
+            }
+
+            let name = fields[8..].join(" ");
+            let name = name.rsplit('/').next().unwrap_or_default();
+            if name.is_empty() || name == "." || name == ".." {
+                continue;
+            }
+
+            let path = target::join(folder, name);
+            if fields[0].starts_with('d') {
+                paths.push(format!("{}/", path));
+            } else {
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        self.run(&[format!("rm {}", quote(&self.remote_path(path)))])?;
+
+        Ok(())
+    }
+}
+
+/// quote a path for an sftp batch command; the client expands globs in the paths of commands like ls, get and rm,
+/// so the glob characters are escaped too, and the client removes the escapes for commands that don't expand them
+fn quote(path: &str) -> String {
+    let mut quoted = String::with_capacity(path.len() + 2);
+    quoted.push('"');
+    for ch in path.chars() {
+        if matches!(ch, '\\' | '"' | '*' | '?' | '[' | ']') {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup_process::BackupProcess;
+    use crate::file_model::FileModel;
+    use crate::kv_store::KeyValueStore;
+    use crate::restore_process::RestoreProcess;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// a target that runs the batch stand-in on a local folder
+    fn stand_in(folder: &str) -> SftpTarget {
+        let root = env::current_dir().unwrap().join(folder);
+        let mut target = SftpTarget::new("backup@stand-in", root.to_str().unwrap());
+        target.command = "tests/sftp-stand-in.sh".to_string();
+        target
+    }
+
+    #[test]
+    fn from_url() {
+        let url = Url::parse("sftp://backup@nas:2222/volume1/replica/").unwrap();
+        let target = SftpTarget::from_url(&url).unwrap();
+        assert_eq!(target.destination, "backup@nas");
+        assert_eq!(target.port, Some(2222));
+        assert_eq!(target.root, "/volume1/replica");
+        assert_eq!(target.remote_path("a/b.txt"), "/volume1/replica/a/b.txt");
+
+        let url = Url::parse("sftp://nas/~/replica").unwrap();
+        let target = SftpTarget::from_url(&url).unwrap();
+        assert_eq!(target.destination, "nas");
+        assert_eq!(target.root, "replica");
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(quote("my \"docs\"/a b"), "\"my \\\"docs\\\"/a b\"");
+        assert_eq!(quote("x[1]*?.jpg"), r#""x\[1\]\*\?.jpg""#);
+    }
+
+    #[test]
+    fn stand_in_put_stat() {
+        let folder = "tests/tback-tmp/sftp";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+
+        let target = stand_in(folder);
+        assert!(target.exists());
+        assert!(!stand_in("tests/tback-tmp/no-such-sftp").exists());
+
+        assert_eq!(target.stat("tests/file1.txt").unwrap(), None);
+        target
+            .put(
+                Path::new("tests/file1.txt"),
+                "tests/a dir/file1.txt",
+                &SourceMeta::default(),
+            )
+            .unwrap();
+        let len = fs::metadata("tests/file1.txt").unwrap().len();
+        assert_eq!(
+            target.stat("tests/a dir/file1.txt").unwrap().unwrap().len,
+            len
+        );
+        assert!(!Path::new(&format!(
+            "{}/tests/a dir/file1.txt{}",
+            folder, TEMP_EXTENSION
+        ))
+        .exists());
+
+        // replaces the existing copy
+        target
+            .put(
+                Path::new("tests/file2.txt"),
+                "tests/a dir/file1.txt",
+                &SourceMeta::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            fs::read(format!("{}/tests/a dir/file1.txt", folder)).unwrap(),
+            fs::read("tests/file2.txt").unwrap()
+        );
+
+        assert!(target
+            .put(
+                Path::new("tests/no-such-file.txt"),
+                "tests/none.txt",
+                &SourceMeta::default()
+            )
+            .is_err());
+
+        assert_eq!(target.list("").unwrap(), vec!["tests/"]);
+        assert_eq!(target.list("tests").unwrap(), vec!["tests/a dir/"]);
+        assert_eq!(
+            target.list("tests/a dir/").unwrap(),
+            vec!["tests/a dir/file1.txt"]
+        );
+
+        let local = format!("{}/got.txt", folder);
+        target
+            .get("tests/a dir/file1.txt", Path::new(&local))
+            .unwrap();
+        assert_eq!(
+            fs::read(&local).unwrap(),
+            fs::read("tests/file2.txt").unwrap()
+        );
+
+        target.delete("tests/a dir/file1.txt").unwrap();
+        assert_eq!(target.stat("tests/a dir/file1.txt").unwrap(), None);
+        assert!(target.delete("tests/a dir/file1.txt").is_err());
+    }
+
+    #[test]
+    fn stand_in_glob_names() {
+        let folder = "tests/tback-tmp/sftp-globs";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+
+        let target = stand_in(folder);
+        let source = SourceMeta::default();
+        target
+            .put(Path::new("tests/file1.txt"), "a*.txt", &source)
+            .unwrap();
+        target
+            .put(Path::new("tests/big-file.pdf"), "ab.txt", &source)
+            .unwrap();
+        target
+            .put(Path::new("tests/file2.txt"), "x[1]/b?.txt", &source)
+            .unwrap();
+
+        let len = fs::metadata("tests/file1.txt").unwrap().len();
+        assert_eq!(target.stat("a*.txt").unwrap().unwrap().len, len);
+        let len = fs::metadata("tests/file2.txt").unwrap().len();
+        assert_eq!(target.stat("x[1]/b?.txt").unwrap().unwrap().len, len);
+
+        // a folder is not a file, whatever its first entry
+        assert_eq!(target.stat("x[1]").unwrap(), None);
+
+        target.delete("a*.txt").unwrap();
+        assert_eq!(target.stat("a*.txt").unwrap(), None);
+        assert!(target.stat("ab.txt").unwrap().is_some());
+    }
+
+    #[test]
+    fn backup_process() {
+        let folder = "tests/tback-tmp/sftp-backup";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+
+        let files: Vec<FileModel> = ["./tests/file1.txt", "./tests/big-file.pdf"]
+            .iter()
+            .map(|path| FileModel::new(path).read_metadata().unwrap())
+            .collect();
+        let mut backup = BackupProcess::new("sftp://backup@stand-in/replica", files, false);
+        backup.backend = Arc::new(stand_in(folder));
+        backup.compress = true;
+        assert!(backup.target_exists());
+
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let (db, report) = backup.process(db).unwrap();
+        assert_eq!(report.copied, 2);
+        assert_eq!(report.failed, 0);
+
+        let saved = db.find("./tests/file1.txt").unwrap();
+        assert!(saved
+            .written_to
+            .contains("sftp://backup@stand-in/replica/./tests/file1.txt.gz"));
+        assert!(Path::new(&format!("{}/tests/file1.txt.gz", folder)).exists());
+        assert_eq!(
+            fs::read(format!("{}/tests/big-file.pdf", folder)).unwrap(),
+            fs::read("tests/big-file.pdf").unwrap()
+        );
+
+        // the copies match their stored records
+        let (_, report) = backup.process(db).unwrap();
+        assert_eq!(report.skipped, 2);
+    }
+
+    #[test]
+    fn restore_process() {
+        let folder = "tests/tback-tmp/sftp-restore";
+        let root = "tests/tback-tmp/sftp-restored";
+        let _ = fs::remove_dir_all(folder);
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(folder).unwrap();
+
+        let files: Vec<FileModel> = ["./tests/file1.txt", "./tests/big-file.pdf"]
+            .iter()
+            .map(|path| FileModel::new(path).read_metadata().unwrap())
+            .collect();
+        let mut backup = BackupProcess::new("sftp://backup@stand-in/replica", files, false);
+        backup.backend = Arc::new(stand_in(folder));
+        backup.compress = true;
+        let db = KeyValueStore::init(PathBuf::from("tests/no-such-db.json")).unwrap();
+        let (db, _) = backup.process(db).unwrap();
+
+        let mut restore = RestoreProcess::new("sftp://backup@stand-in/replica", false);
+        restore.backend = Arc::new(stand_in(folder));
+        restore.dest_root = Some(PathBuf::from(root));
+        assert!(restore.target_exists());
+        assert_eq!(restore.process(&db).unwrap().len(), 2);
+        for name in ["file1.txt", "big-file.pdf"] {
+            let restored = fs::read(format!("{}/tests/{}", root, name)).unwrap();
+            assert_eq!(restored, fs::read(format!("tests/{}", name)).unwrap());
+        }
+
+        // a copy missing from the target fails that file only
+        fs::remove_file(format!("{}/tests/file1.txt.gz", folder)).unwrap();
+        restore.overwrite = true;
+        let error = restore.process(&db).unwrap_err().to_string();
+        assert!(error.starts_with("1 files could not be restored and 1 were"));
+    }
+
+    /// set REPLICA_TEST_SFTP to a url like sftp://me@localhost/tmp/replica-sftp to run against a real sshd
+    #[test]
+    #[ignore]
+    fn sshd_put_stat() {
+        let url = env::var("REPLICA_TEST_SFTP").expect("REPLICA_TEST_SFTP should be set");
+        let target = SftpTarget::from_url(&Url::parse(&url).unwrap()).unwrap();
+        assert!(target.exists());
+
+        target
+            .put(
+                Path::new("tests/file1.txt"),
+                "tests/file1.txt",
+                &SourceMeta::default(),
+            )
+            .unwrap();
+        let len = fs::metadata("tests/file1.txt").unwrap().len();
+        assert_eq!(target.stat("tests/file1.txt").unwrap().unwrap().len, len);
+    }
+}
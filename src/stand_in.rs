@@ -0,0 +1,108 @@
+/// Stand In - an in-process HTTP server the remote targets' tests run against
+///
+/// # Stand In
+///
+/// the server reads each request on its own thread, one connection at a time, and passes it to the test's handler,
+/// which keeps the service's state.  every response closes its connection.
+///
+use anyhow::Result;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+/// header names, lower case, and values
+pub type Headers = Vec<(String, String)>;
+
+/// a response's status, extra headers and body; a head response sends the body's length only
+pub type Response = (u16, Headers, Vec<u8>);
+
+/// a request as read from the connection
+pub struct Request {
+    pub method: String,
+    /// the path and query, as sent
+    pub target: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// return the value of the named header, given in lower case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// start the server with the handler; return its url
+pub fn start<F>(mut handle: F) -> String
+where
+    F: FnMut(Request) -> Result<Response> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = serve(stream, &mut handle);
+        }
+    });
+
+    url
+}
+
+fn serve<F>(mut stream: TcpStream, handle: &mut F) -> Result<()>
+where
+    F: FnMut(Request) -> Result<Response>,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default().to_string();
+
+    let mut headers: Headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => headers.push((name.to_lowercase(), value.trim().to_string())),
+            None => break,
+        }
+    }
+
+    let mut request = Request {
+        method,
+        target,
+        headers,
+        body: vec![],
+    };
+    let len: usize = request
+        .header("content-length")
+        .and_then(|len| len.parse().ok())
+        .unwrap_or_default();
+    request.body = vec![0u8; len];
+    reader.read_exact(&mut request.body)?;
+
+    let head = request.method == "HEAD";
+    let (status, extra, body) = handle(request)?;
+
+    let mut response = format!(
+        "HTTP/1.1 {} Stand-in\r\nconnection: close\r\ncontent-length: {}\r\n",
+        status,
+        body.len()
+    )
+    .into_bytes();
+    for (name, value) in extra {
+        response.extend(format!("{}: {}\r\n", name, value).into_bytes());
+    }
+    response.extend(b"\r\n");
+    if !head {
+        response.extend(body);
+    }
+    stream.write_all(&response)?;
+
+    Ok(())
+}
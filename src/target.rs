@@ -0,0 +1,675 @@
+/// Targets - the local folders and remote stores that copies are written to
+///
+/// # Targets
+///
+/// the backup process reads and writes its target through the `Target` trait.  a local folder is a `LocalTarget`; a
+/// target given as a url, like `sftp://user@host/backup` or `s3://bucket/prefix`, is opened by the factory registered
+/// for its scheme, and a library user can register factories for their own schemes.  a remote copy is checked
+/// against the stored record of the copy, as the remote file's content and times can't be compared cheaply, or
+/// against the source recorded with the copy where the target keeps one.  an upload never leaves a partial copy in
+/// place of a good one.
+///
+use crate::backup_process::TEMP_EXTENSION;
+use crate::config::Config;
+use crate::file_model::FileModel;
+use crate::s3::S3Target;
+use crate::sftp::SftpTarget;
+use crate::webdav::WebDavTarget;
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use reqwest::blocking::Client;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+/// the time allowed to connect to a remote target
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the count of local staging files written for uploads, to keep their names unique
+static STAGED: AtomicUsize = AtomicUsize::new(0);
+
+/// the source a copy was written from: its size, modified time and hash, if known
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SourceMeta {
+    pub len: u64,
+    pub modified: u64,
+    pub hash: String,
+}
+
+/// a copy's size and, where the target keeps them, its modified time in microseconds, etag and source record
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TargetStat {
+    pub len: u64,
+    pub modified: Option<u64>,
+    pub etag: Option<String>,
+    pub source: Option<SourceMeta>,
+}
+
+/// the store a backup process writes to; paths are relative to the target's root and use `/` separators
+pub trait Target: Debug + Send + Sync {
+    /// return true if the root can be reached
+    fn exists(&self) -> bool;
+
+    /// return true if the root is a local folder, so plain copies can be compared by their size and modified time
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    /// return the stat of the file at the path, or None if there is no file
+    fn stat(&self, path: &str) -> Result<Option<TargetStat>>;
+
+    /// upload the local file to the path, creating the parent folders; the target may keep the source record with
+    /// the copy
+    fn put(&self, local: &Path, path: &str, source: &SourceMeta) -> Result<()>;
+
+    /// download the file at the path to the local file
+    fn get(&self, path: &str, local: &Path) -> Result<()>;
+
+    /// return the paths of the files and folders in the folder, "" for the root; folder paths end with `/`
+    fn list(&self, folder: &str) -> Result<Vec<String>>;
+
+    /// remove the file at the path
+    fn delete(&self, path: &str) -> Result<()>;
+
+    /// write the file at the path with the write function, which returns the record of the source it wrote; the
+    /// content is written to a local staging file that is put, then removed
+    fn write(&self, path: &str, write: &mut WriteFn) -> Result<()> {
+        let stage = stage_path();
+        let resp = write_file(&stage, write).and_then(|source| self.put(&stage, path, &source));
+        let _ = fs::remove_file(&stage);
+
+        resp
+    }
+
+    /// create a symlink at the path to the link, replacing whatever is there
+    fn link(&self, path: &str, link: &Path) -> Result<()> {
+        let msg = format!(
+            "can't store the symlink {} to {} on {:?}",
+            path,
+            link.display(),
+            self
+        );
+        error!("{}", msg);
+        Err(anyhow!("{}", msg))
+    }
+
+    /// return the path the symlink at the path points to, or None if there is no symlink
+    fn read_link(&self, _path: &str) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+
+    /// return the hash of the file at the path, or None if there is no file; the file is downloaded to hash it
+    fn hash(&self, path: &str) -> Result<Option<String>> {
+        if self.stat(path)?.is_none() {
+            return Ok(None);
+        }
+
+        let stage = stage_path();
+        let resp = self
+            .get(path, &stage)
+            .and_then(|_| FileModel::new(stage.to_str().unwrap()).hash_file());
+        let _ = fs::remove_file(&stage);
+
+        Ok(Some(resp?))
+    }
+}
+
+/// the function that writes a file's content and returns the record of the source it wrote
+pub type WriteFn<'a> = dyn FnMut(&mut dyn Write) -> Result<SourceMeta> + 'a;
+
+/// a folder on a local or mounted file system
+#[derive(Debug, Clone)]
+pub struct LocalTarget {
+    pub root: PathBuf,
+}
+
+impl LocalTarget {
+    pub fn new(root: &str) -> LocalTarget {
+        LocalTarget {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn local_path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// create the parent folders of the path if they don't exist; return the local path
+    fn create_parent(&self, path: &str) -> Result<PathBuf> {
+        let dest = self.local_path(path);
+        if let Some(parent) = dest.parent() {
+            if !parent.exists() {
+                info!("create the parent folder: {:?}", &parent);
+                if let Err(e) = fs::create_dir_all(parent) {
+                    let msg = format!("error creating parent folder: {}: {}", parent.display(), e);
+                    error!("{}", msg);
+                    return Err(anyhow!("{}", msg));
+                }
+            }
+        }
+
+        Ok(dest)
+    }
+}
+
+impl Target for LocalTarget {
+    fn exists(&self) -> bool {
+        self.root.is_dir()
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<TargetStat>> {
+        let meta = match fs::metadata(self.local_path(path)) {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let modified = meta
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_micros() as u64;
+
+        Ok(Some(TargetStat {
+            len: meta.len(),
+            modified: Some(modified),
+            ..TargetStat::default()
+        }))
+    }
+
+    /// the source record is kept in the database only
+    fn put(&self, local: &Path, path: &str, _source: &SourceMeta) -> Result<()> {
+        let dest = self.create_parent(path)?;
+
+        write_atomic(&dest, |temp| {
+            fs::copy(local, temp)?;
+            File::open(temp)?.sync_all()?;
+            Ok(())
+        })
+    }
+
+    fn get(&self, path: &str, local: &Path) -> Result<()> {
+        fs::copy(self.local_path(path), local)?;
+
+        Ok(())
+    }
+
+    fn list(&self, folder: &str) -> Result<Vec<String>> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(self.local_path(folder))? {
+            let entry = entry?;
+            let path = join(folder, &entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                paths.push(format!("{}/", path));
+            } else {
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        fs::remove_file(self.local_path(path))?;
+
+        Ok(())
+    }
+
+    /// the file is written in place, through a temp sibling
+    fn write(&self, path: &str, write: &mut WriteFn) -> Result<()> {
+        let dest = self.create_parent(path)?;
+
+        write_atomic(&dest, |temp| write_file(temp, write).map(|_| ()))
+    }
+
+    fn link(&self, path: &str, link: &Path) -> Result<()> {
+        let dest = self.create_parent(path)?;
+
+        write_atomic(&dest, |temp| {
+            let _ = fs::remove_file(temp);
+            Ok(std::os::unix::fs::symlink(link, temp)?)
+        })
+    }
+
+    fn read_link(&self, path: &str) -> Result<Option<PathBuf>> {
+        match fs::read_link(self.local_path(path)) {
+            Ok(link) => Ok(Some(link)),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::InvalidInput
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// the file is hashed in place
+    fn hash(&self, path: &str) -> Result<Option<String>> {
+        if self.stat(path)?.is_none() {
+            return Ok(None);
+        }
+
+        let local = self.local_path(path);
+        Ok(Some(FileModel::new(local.to_str().unwrap()).hash_file()?))
+    }
+}
+
+/// write the local file with the write function and sync it; return the record of the source written
+fn write_file(path: &Path, write: &mut WriteFn) -> Result<SourceMeta> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let source = write(&mut writer)?;
+    writer.into_inner()?.sync_all()?;
+
+    Ok(source)
+}
+
+/// create the target for a url
+pub type TargetFactory = fn(&Url) -> Result<Arc<dyn Target>>;
+
+/// the factories for each url scheme, starting with the built in remote targets
+fn factories() -> &'static RwLock<HashMap<String, TargetFactory>> {
+    static FACTORIES: OnceLock<RwLock<HashMap<String, TargetFactory>>> = OnceLock::new();
+
+    FACTORIES.get_or_init(|| {
+        let sftp: TargetFactory = |url| Ok(Arc::new(SftpTarget::from_url(url)?));
+        let s3: TargetFactory = |url| Ok(Arc::new(S3Target::from_url(url)?));
+        let webdav: TargetFactory = |url| Ok(Arc::new(WebDavTarget::from_url(url)?));
+
+        let factories = [
+            ("sftp", sftp),
+            ("ssh", sftp),
+            ("s3", s3),
+            ("webdav", webdav),
+            ("davs", webdav),
+        ]
+        .into_iter()
+        .map(|(scheme, factory)| (scheme.to_string(), factory))
+        .collect();
+
+        RwLock::new(factories)
+    })
+}
+
+/// register the factory for targets with the url scheme, replacing the existing one
+pub fn register(scheme: &str, factory: TargetFactory) {
+    factories()
+        .write()
+        .unwrap()
+        .insert(scheme.to_string(), factory);
+}
+
+/// return true if the target is a URL rather than a local folder
+pub fn is_remote(target: &str) -> bool {
+    target.contains("://")
+}
+
+/// open the target for a local folder or URL
+pub fn open(target: &str) -> Result<Arc<dyn Target>> {
+    if !is_remote(target) {
+        return Ok(Arc::new(LocalTarget::new(target)));
+    }
+
+    let url = match Url::parse(target) {
+        Ok(url) => url,
+        Err(e) => {
+            let msg = format!("bad target url {}: {}", target, e);
+            error!("{}", msg);
+            return Err(anyhow!("{}", msg));
+        }
+    };
+
+    let factory = factories().read().unwrap().get(url.scheme()).copied();
+    match factory {
+        Some(factory) => factory(&url),
+        None => {
+            let msg = format!("unknown target scheme {}: {}", url.scheme(), target);
+            error!("{}", msg);
+            Err(anyhow!("{}", msg))
+        }
+    }
+}
+
+/// return the path below the root for a copy's path under the target, e.g. `./photos/cat.jpg` to `photos/cat.jpg`
+pub fn relative_to(target: &str, path: &str) -> String {
+    let target = target.trim_end_matches('/');
+    let path = match path.strip_prefix(target) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => path,
+    };
+
+    path.trim_start_matches('/')
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+/// return the path of the name in the folder, "" for the root
+pub fn join(folder: &str, name: &str) -> String {
+    let folder = folder.trim_end_matches('/');
+    if folder.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", folder, name)
+    }
+}
+
+/// percent encode all but the unreserved characters, and the slashes of a path if keep_slash
+pub fn percent_encode(text: &str, keep_slash: bool) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if keep_slash => "/".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// decode the percent encoded text; a `%` without two hex digits is kept as it is
+pub fn percent_decode(text: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = tail
+            .get(..2)
+            .filter(|_| byte == b'%')
+            .and_then(|digits| hex::decode(digits).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.extend(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// return the http client for remote targets; an upload may take as long as the link needs, so only connecting is
+/// timed and a dead connection is found by tcp keepalives
+pub fn http_client() -> Client {
+    Client::builder()
+        .timeout(None)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()
+        .expect("the http client should build")
+}
+
+/// return a unique path in the temp folder for a file staged before it is put on a target; the caller removes it
+pub fn stage_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "replica-{}-{}{}",
+        std::process::id(),
+        STAGED.fetch_add(1, Ordering::Relaxed),
+        TEMP_EXTENSION
+    ))
+}
+
+/// remove the staging files left in the temp folder by runs that have stopped; return the number removed
+pub fn remove_staged(dryrun: bool) -> usize {
+    let Ok(entries) = fs::read_dir(std::env::temp_dir()) else {
+        return 0;
+    };
+
+    let mut count = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(pid) = staged_pid(&name) else {
+            continue;
+        };
+        if pid == std::process::id() || Config::is_running(pid) {
+            continue;
+        }
+
+        let path = entry.path();
+        if dryrun {
+            info!("dryrun, would remove staged file: {}", path.display());
+            continue;
+        }
+
+        match fs::remove_file(&path) {
+            Ok(_) => {
+                info!("removed staged file: {}", path.display());
+                count += 1;
+            }
+            Err(e) => warn!("could not remove {}: {}", path.display(), e),
+        }
+    }
+
+    count
+}
+
+/// return the pid of the run that staged the file with the name, e.g. 123 for replica-123-4.replica-tmp
+fn staged_pid(name: &str) -> Option<u32> {
+    let stem = name
+        .strip_prefix("replica-")?
+        .strip_suffix(TEMP_EXTENSION)?;
+    let (pid, count) = stem.split_once('-')?;
+    count.parse::<usize>().ok()?;
+
+    pid.parse().ok()
+}
+
+/// write dest with the write function to a temp sibling, then rename it into place and sync the folder; the temp
+/// file is removed if the write fails
+pub fn write_atomic<F: FnOnce(&Path) -> Result<()>>(dest: &Path, write: F) -> Result<()> {
+    let temp = PathBuf::from(format!("{}{}", dest.display(), TEMP_EXTENSION));
+
+    let resp = write(&temp).and_then(|_| Ok(fs::rename(&temp, dest)?));
+    if resp.is_err() {
+        let _ = fs::remove_file(&temp);
+        return resp;
+    }
+
+    // sync the folder so the rename itself survives a crash
+    if let Some(parent) = dest.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if let Ok(folder) = File::open(parent) {
+            let _ = folder.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_urls() {
+        assert!(is_remote("sftp://backup@nas/volume1/replica"));
+        assert!(!is_remote("/Volumes/usb/replica"));
+
+        assert!(open("sftp://backup@nas/volume1/replica").is_ok());
+        assert!(open("s3://backups/replica").is_ok());
+        assert!(open("davs://me@cloud.example.com/remote.php/dav/files/me").is_ok());
+        assert!(open("ftp://nas/replica").is_err());
+        assert!(open("sftp://").is_err());
+        assert!(open("tests/tback").unwrap().is_local());
+    }
+
+    #[test]
+    fn register_scheme() {
+        assert!(open("test-mirror://nas/replica").is_err());
+
+        register("test-mirror", |url| {
+            Ok(Arc::new(LocalTarget::new(url.path())))
+        });
+        let target = open("test-mirror://nas/replica").unwrap();
+        assert!(format!("{:?}", target).contains("/replica"));
+    }
+
+    #[test]
+    fn relative() {
+        let target = "sftp://backup@nas/replica/";
+        assert_eq!(
+            relative_to(target, "sftp://backup@nas/replica/./tests/file1.txt"),
+            "tests/file1.txt"
+        );
+        assert_eq!(
+            relative_to(target, "sftp://backup@nas/replica/.config/a.toml.gz"),
+            ".config/a.toml.gz"
+        );
+        assert_eq!(
+            relative_to("tests/tback/", "tests/tback-tmp/file1.txt"),
+            "tests/tback-tmp/file1.txt"
+        );
+        assert_eq!(
+            percent_encode("photos/my cat.jpg", true),
+            "photos/my%20cat.jpg"
+        );
+        assert_eq!(percent_encode("a/b", false), "a%2Fb");
+        assert_eq!(percent_decode("photos/my%20cat%2Ejpg"), "photos/my cat.jpg");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(join("", "a.txt"), "a.txt");
+        assert_eq!(join("docs/", "a.txt"), "docs/a.txt");
+    }
+
+    #[test]
+    fn local_target() {
+        let folder = "tests/tback-tmp/local-target";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+
+        let target = LocalTarget::new(folder);
+        assert!(target.exists());
+        assert!(!LocalTarget::new("tests/tback-tmp/no-such-target").exists());
+
+        assert_eq!(target.stat("docs/file1.txt").unwrap(), None);
+        target
+            .put(
+                Path::new("tests/file1.txt"),
+                "docs/file1.txt",
+                &SourceMeta::default(),
+            )
+            .unwrap();
+        let stat = target.stat("docs/file1.txt").unwrap().unwrap();
+        assert_eq!(stat.len, fs::metadata("tests/file1.txt").unwrap().len());
+        assert!(stat.modified.is_some());
+        assert_eq!(target.stat("docs").unwrap(), None);
+
+        assert_eq!(target.list("").unwrap(), vec!["docs/"]);
+        assert_eq!(target.list("docs").unwrap(), vec!["docs/file1.txt"]);
+
+        let local = format!("{}/got.txt", folder);
+        target.get("docs/file1.txt", Path::new(&local)).unwrap();
+        assert_eq!(
+            fs::read(&local).unwrap(),
+            fs::read("tests/file1.txt").unwrap()
+        );
+
+        target.delete("docs/file1.txt").unwrap();
+        assert_eq!(target.stat("docs/file1.txt").unwrap(), None);
+        assert!(target.delete("docs/file1.txt").is_err());
+    }
+
+    /// a local folder seen through the trait's default write, link and hash, as a remote target would be
+    #[derive(Debug)]
+    struct Staged(LocalTarget);
+
+    impl Target for Staged {
+        fn exists(&self) -> bool {
+            self.0.exists()
+        }
+
+        fn stat(&self, path: &str) -> Result<Option<TargetStat>> {
+            self.0.stat(path)
+        }
+
+        fn put(&self, local: &Path, path: &str, source: &SourceMeta) -> Result<()> {
+            self.0.put(local, path, source)
+        }
+
+        fn get(&self, path: &str, local: &Path) -> Result<()> {
+            self.0.get(path, local)
+        }
+
+        fn list(&self, folder: &str) -> Result<Vec<String>> {
+            self.0.list(folder)
+        }
+
+        fn delete(&self, path: &str) -> Result<()> {
+            self.0.delete(path)
+        }
+    }
+
+    #[test]
+    fn write_link_and_hash() {
+        let folder = "tests/tback-tmp/write-target";
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).unwrap();
+
+        let expected = FileModel::new("tests/file1.txt").hash_file().unwrap();
+        let content = fs::read("tests/file1.txt").unwrap();
+        let local: Arc<dyn Target> = Arc::new(LocalTarget::new(folder));
+        let staged: Arc<dyn Target> = Arc::new(Staged(LocalTarget::new(folder)));
+
+        for (target, path) in [(&local, "local/file1.txt"), (&staged, "staged/file1.txt")] {
+            assert_eq!(target.hash(path).unwrap(), None);
+            target
+                .write(path, &mut |writer| {
+                    writer.write_all(&content)?;
+                    Ok(SourceMeta::default())
+                })
+                .unwrap();
+            assert_eq!(target.hash(path).unwrap(), Some(expected.clone()));
+
+            // a failed write leaves no file behind
+            let failed = target.write("failed/file1.txt", &mut |_| Err(anyhow!("failed")));
+            assert!(failed.is_err());
+            assert_eq!(target.stat("failed/file1.txt").unwrap(), None);
+        }
+        assert!(!Path::new(&format!("{}/local/file1.txt{}", folder, TEMP_EXTENSION)).exists());
+
+        let link = Path::new("../file1.txt");
+        assert_eq!(local.read_link("links/file1.txt").unwrap(), None);
+        local.link("links/file1.txt", link).unwrap();
+        local.link("links/file1.txt", link).unwrap();
+        assert_eq!(
+            local.read_link("links/file1.txt").unwrap().as_deref(),
+            Some(link)
+        );
+        assert_eq!(local.read_link("local/file1.txt").unwrap(), None);
+        assert!(staged.link("links/file2.txt", link).is_err());
+    }
+
+    #[test]
+    fn remove_staged() {
+        assert_eq!(staged_pid("replica-123-4.replica-tmp"), Some(123));
+        assert_eq!(staged_pid("replica-123.replica-tmp"), None);
+        assert_eq!(staged_pid("replica-123-4.txt"), None);
+
+        // a run that has stopped can't remove its own staging files
+        let stopped = std::env::temp_dir().join(format!("replica-999999999-0{}", TEMP_EXTENSION));
+        let own = stage_path();
+        fs::write(&stopped, "partial").unwrap();
+        fs::write(&own, "partial").unwrap();
+
+        assert_eq!(super::remove_staged(true), 0);
+        assert!(stopped.exists());
+        assert!(super::remove_staged(false) >= 1);
+        assert!(!stopped.exists());
+        assert!(own.exists());
+        let _ = fs::remove_file(&own);
+    }
+}
@@ -0,0 +1,43 @@
+#!/bin/sh
+#
+# a stand-in for the OpenSSH sftp client in batch mode (sftp -b - user@host) for tests; the remote paths are local
+# paths.  supports the commands the sftp target uses: cd, get, ls -ln, mkdir, put, rename and rm.  a command prefixed
+# with - may fail without failing the batch.  like sftp, the glob characters *?[] in a path must be escaped with \ to
+# be taken literally; an unescaped one fails the batch, as the real client would expand it to other files.
+#
+
+while IFS= read -r line; do
+    ignore=false
+    case "$line" in
+        -*) ignore=true; line="${line#-}" ;;
+    esac
+
+    eval "set -- $line"
+    cmd="$1"
+    shift
+
+    for arg do
+        shift
+        if printf '%s' "$arg" | sed 's/\\[][*?]//g' | grep -q '[][*?]'; then
+            echo "stand-in: unescaped glob in $arg" >&2
+            exit 1
+        fi
+        set -- "$@" "$(printf '%s' "$arg" | sed 's/\\\([][*?]\)/\1/g')"
+    done
+
+    case "$cmd" in
+        cd) test -d "$1" ;;
+        get) cp "$1" "$2" ;;
+        ls) shift; ls -ln "$1" ;;
+        mkdir) mkdir "$1" 2>/dev/null ;;
+        put) cp "$1" "$2" ;;
+        rename) mv -f "$1" "$2" ;;
+        rm) rm "$1" ;;
+        *) false ;;
+    esac
+
+    if [ $? -ne 0 ] && [ "$ignore" = false ]; then
+        echo "stand-in: $cmd failed" >&2
+        exit 1
+    fi
+done